@@ -3,6 +3,9 @@ import gdb
 import elftools
 from elftools.elf.elffile import ELFFile
 
+# Where the loader puts the kernel when built without ASLR
+KERNEL_BASE = 0x8000000000
+
 class ConnectCommand (gdb.Command):
     "Command for connecting to rusty-pintos."
 
@@ -64,8 +67,11 @@ class ConnectCommand (gdb.Command):
         gdb.Breakpoint('loader::run_kernel', internal=True, temporary=True)
         gdb.execute( 'c' )
 
-# jump to the kernel
-        gdb.execute( 'file target/debug/kernel.so' )
+# jump to the kernel. It is position independent, so this needs the
+# loader built with LOADER_FEATURES=no_aslr, or aslr = off in BOOT.CFG, to
+# run it at a known base
+        gdb.execute( 'file' )
+        gdb.execute( 'symbol-file -o 0x%x target/debug/kernel.so' % KERNEL_BASE )
         gdb.Breakpoint('kernel::kernel_entry', internal=True, temporary=True)
         gdb.execute( 'c' )
 
@@ -1,6 +1,4 @@
 
-use ::mem::c_void;
-
 pub enum TimerMode {
     OneShot,
     Periodic,
@@ -8,25 +6,52 @@ pub enum TimerMode {
 }
 
 pub struct LapicRegisters {
+    physical_address: ::mem::PhysicalAddress,
     ptr: *mut u32,
 }
 
 impl LapicRegisters {
-    pub fn new(ptr: *mut c_void) -> Self {
+    /// The registers are accessed at their direct map address, which
+    /// `page_in` has to map before use
+    pub fn new(physical_address: ::mem::PhysicalAddress) -> Self {
+        let mut virtual_address = physical_address.to_direct_map();
         LapicRegisters {
-            ptr: ptr as *mut u32,
+            physical_address: physical_address,
+            ptr: virtual_address.as_mut_ptr() as *mut u32,
         }
     }
 
-    pub fn page_in(&self, page_table: &mut ::page_table::PageTable) {
-        page_table.insert_page(::mem::PhysicalAddress::new(self.ptr as usize).into(), ::mem::VirtualAddress::new(self.ptr as usize).into(), ::page_table::PageSize::FourKb);
+    pub fn page_in(&self, page_table: &mut ::page_table::KernelPageTable) {
+        // Registers are memory mapped IO and must never be cached
+        let flags = ::page_table::MappingFlags {
+            write_through: true,
+            cache_disable: true,
+            no_execute: true,
+            ..Default::default()
+        };
+        ::tlb::insert_page(page_table, self.physical_address.into(), ::mem::VirtualAddress::new(self.ptr as usize).into(), ::page_table::PageSize::FourKb, flags);
+    }
+
+    /// Starts only the CPU whose LAPIC has `apic_id`
+    pub unsafe fn send_startup_ipi_to(&mut self, apic_id: u8) {
+        self.set_icr_destination(apic_id);
+        self.write_icr(0x2 /* page 0x2000 */, 0b110 /* startup */, 0b00 /* no shorthand */);
     }
 
-    pub unsafe fn send_startup_ipi(&mut self) {
-        let vector = 0x2u32; /* page 0x2000 */
-        let delivery_mode = 0b110u32;
+    /// Sends a fixed interrupt to the CPU whose LAPIC has `apic_id`
+    pub unsafe fn send_ipi_to(&mut self, apic_id: u8, vector: u8) {
+        self.set_icr_destination(apic_id);
+        self.write_icr(vector as u32, 0b000 /* fixed */, 0b00 /* no shorthand */);
+    }
+
+    unsafe fn set_icr_destination(&mut self, apic_id: u8) {
+        // The destination field of the ICR's high half
+        *self.ptr.offset(4 * 0x31) = (apic_id as u32) << 24;
+    }
+
+    /// Sends an interrupt and waits for the LAPIC to accept it
+    unsafe fn write_icr(&mut self, vector: u32, delivery_mode: u32, destination_shorthand: u32) {
         let level = 0b1u32;
-        let destination_shorthand = 0b11u32; /* all excluding self */
 
         let mut icr_low: u32 = 0;
 
@@ -36,6 +61,10 @@ impl LapicRegisters {
         icr_low |= destination_shorthand << 18;
 
         *self.ptr.offset(4 * 0x30) = icr_low;
+
+        // Delivery status is set until the interrupt has been sent
+        while (::core::ptr::read_volatile(self.ptr.offset(4 * 0x30)) >> 12) & 0x1 == 0x1 {
+        }
     }
 
     pub unsafe fn eoi(&mut self) {
@@ -100,6 +129,11 @@ impl LapicRegisters {
             *self.ptr.offset(4 * 0x2)
         }
     }
+
+    /// The ID of this CPU's LAPIC, as the MADT lists it
+    pub fn apic_id(&self) -> u8 {
+        (self.get_apic_id_register() >> 24) as u8
+    }
 }
 
 
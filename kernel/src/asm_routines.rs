@@ -46,6 +46,6 @@ pub fn cpuid_lapic_enabled() -> (apic::LapicRegisters, bool, bool, bool) {
         let bootstrap = (low >> 8) & 0x1 == 0x1;
         let enabled =   (low >> 11) & 0x1 == 0x1;
         let extended = (low >> 10) & 0x1 == 0x1;
-        (apic::LapicRegisters::new(address as *mut _), bootstrap, enabled, extended)
+        (apic::LapicRegisters::new(::mem::PhysicalAddress::new(address as usize)), bootstrap, enabled, extended)
     }
 }
@@ -0,0 +1,47 @@
+//! The command line the loader passed, for subsystems to read their
+//! `key=value` options from
+
+use core::str::FromStr;
+
+use options::CommandLine;
+
+static mut COMMAND_LINE: &'static str = "";
+
+/// Takes the command line from the loader's BootInfo. If the loader cut
+/// it off mid character, the partial character is dropped.
+pub unsafe fn init(boot_info: &'static ::boot_info::BootInfo) {
+    let address = ::mem::PhysicalAddress::new(boot_info.command_line.address as usize).to_direct_map();
+    let bytes: &'static [u8] = ::core::slice::from_raw_parts(
+        address.as_ptr() as *const u8,
+        boot_info.command_line.length as usize);
+    COMMAND_LINE = match ::core::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(error) => ::core::str::from_utf8_unchecked(&bytes[..error.valid_up_to()]),
+    };
+}
+
+pub fn command_line() -> CommandLine<'static> {
+    CommandLine::new(unsafe { COMMAND_LINE })
+}
+
+/// Value of `key`, see `CommandLine::get`
+pub fn get(key: &str) -> Option<&'static str> {
+    command_line().get(key)
+}
+
+/// Value of `key` parsed as a `T`. A value that doesn't parse is
+/// reported and ignored.
+pub fn parse<T: FromStr>(key: &str) -> Option<T> {
+    match command_line().parse(key) {
+        Some(Ok(value)) => Some(value),
+        Some(Err(_)) => {
+            println!("Ignoring bad command line value {}={}", key, get(key).unwrap_or(""));
+            None
+        },
+        None => None,
+    }
+}
+
+pub fn has_flag(flag: &str) -> bool {
+    command_line().has_flag(flag)
+}
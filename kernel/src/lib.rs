@@ -33,6 +33,12 @@ extern crate frame_allocator as falloc;
 
 extern crate page_table;
 
+extern crate boot_info;
+
+extern crate options;
+
+extern crate sha256;
+
 // bindings to cpuid
 mod asm_routines;
 
@@ -41,6 +47,15 @@ mod asm_routines;
 
 mod apic;
 
+// Invalidating TLBs on every CPU
+mod tlb;
+
+// key=value options from the loader
+mod command_line;
+
+// Files the loader read for us
+mod modules;
+
 lazy_static! {
     static ref IDT: x86_64::structures::idt::Idt = {
         let mut idt = x86_64::structures::idt::Idt::new();
@@ -63,6 +78,7 @@ lazy_static! {
         idt.simd_floating_point.set_handler_fn(simd_exception_handler);
         idt.virtualization.set_handler_fn(virtualization_exception_handler);
         idt.interrupts[0].set_handler_fn(timer_handler);
+        idt.interrupts[(tlb::SHOOTDOWN_VECTOR - 0x20) as usize].set_handler_fn(tlb::shootdown_handler);
         idt.interrupts[0xdf].set_handler_fn(spurious_interrupt_handler);
         idt
     };
@@ -70,21 +86,71 @@ lazy_static! {
 
 static mut LAPIC_REGISTERS: Option<apic::LapicRegisters> = None;
 
+/// How far the loader moved the kernel from its link address. Subtract
+/// it from a code address to look the address up in kernel.so.
+static mut KERNEL_SLIDE: usize = 0;
+
+/// The `BootInfo` version this kernel understands. The loader reads it
+/// out of kernel.so and refuses to boot us if it writes another one.
+#[no_mangle]
+pub static KERNEL_BOOT_INFO_VERSION: u32 = boot_info::VERSION;
+
 static mut testing: i64 = 32;
 
+/// The GDT every CPU loads, built by the bootstrap CPU
+static mut GDT: Option<x86::shared::dtables::DescriptorTablePointer<x86::shared::segmentation::SegmentDescriptor>> = None;
+
+/// Where the trampoline reads the kernel's PML4, the entry point and the
+/// stack of the CPU being started
+const TRAMPOLINE_PML4: usize = 0x3100;
+const TRAMPOLINE_ENTRY: usize = 0x3200;
+const TRAMPOLINE_STACK: usize = 0x3300;
+
+/// Pages of each AP's stack
+const AP_STACK_PAGES: usize = 4;
+
+/// Spins an AP gets to reach `ap_bootstrap` before it's given up on
+const AP_STARTUP_SPINS: usize = 100_000_000;
+
 /// This is the entry point for the rust language part of the
 /// OS. At this point all UEFI code can still be run, and
 /// we haven't yet exited boot services
 #[no_mangle]
-pub extern fn kernel_entry(system_table:&gnu_efi::api::SystemTable, mut frame_allocator: falloc::FrameAllocator, mut page_table: page_table::PageTable) -> ! {
+pub extern fn kernel_entry(boot_info: &'static boot_info::BootInfo) -> ! {
+    // Nothing else in the BootInfo can be trusted until this passes
+    if let Err(error) = boot_info.check() {
+        println!("Unusable BootInfo from the loader: {:?}", error);
+        loop {}
+    }
+    let layout = boot_info.kernel;
+
+    // Everything below reaches memory through the direct map, wherever
+    // the loader put it
+    unsafe {
+        ::mem::set_direct_map_offset(layout.direct_map_offset as usize);
+        KERNEL_SLIDE = layout.slide as usize;
+        command_line::init(boot_info);
+    }
+    configure_serial();
+
+    let system_table: &gnu_efi::api::SystemTable = unsafe {
+        &*(boot_info.system_table as *const gnu_efi::api::SystemTable)
+    };
+    // Take over the loader's allocator, which knows what it handed out
+    let mut frame_allocator: falloc::FrameAllocator = unsafe {
+        let address = ::mem::PhysicalAddress::new(boot_info.frame_allocator as usize).to_direct_map();
+        core::ptr::read(address.as_ptr() as *const falloc::FrameAllocator)
+    };
+    unsafe {
+        modules::init(boot_info, &mut frame_allocator);
+    }
+
     // Initialize the GDT
     unsafe {
         use x86::shared::segmentation::{SegmentDescriptor};
         use x86::shared::dtables::DescriptorTablePointer;
-        let gdt_frame = frame_allocator.get_frame();
-        let gdt_frame_num: usize = gdt_frame.into();
-        let gdt_page = ::mem::Page::new(gdt_frame_num);
-        let mut gdt_address: mem::VirtualAddress = gdt_page.into();
+        let gdt_frame = frame_allocator.get_frame(falloc::FrameUsage::Other).unwrap();
+        let mut gdt_address = ::mem::PhysicalAddress::from(gdt_frame).to_direct_map();
 
         let segment_descriptors: &mut [u64] =
             core::slice::from_raw_parts_mut(
@@ -96,31 +162,49 @@ pub extern fn kernel_entry(system_table:&gnu_efi::api::SystemTable, mut frame_al
         segment_descriptors[4] = 0x0020_fa00_0000_0000;
         segment_descriptors[5] = 0x0080_f200_0000_0000;
         let gdt: DescriptorTablePointer<SegmentDescriptor> = DescriptorTablePointer::new_gdtp(::core::mem::transmute(segment_descriptors));
-
-        x86::shared::dtables::lgdt(&gdt);
-
-        asm!("\
-                pushq $$0x10
-                lea 0x3(%rip), %rax
-                pushq %rax
-                lretq
-                1: nop
-                " : );
-
-        asm!("\
-                mov $$0x0, %eax
-                mov %ax, %ds
-                mov %ax, %es
-                mov %ax, %fs
-                mov %ax, %gs
-                mov %ax, %ss
-                " : );
-
+        GDT = Some(gdt);
+        load_gdt();
     }
+    // The loader's direct map is live, so page tables are reached
+    // through it from now on
+    let mut page_table: page_table::KernelPageTable = unsafe {
+        page_table::PageTable::from_frame(
+            ::mem::PhysicalAddress::new(boot_info.page_table as usize).into(),
+            page_table::GlobalFrameAllocator,
+            page_table::DirectMapped)
+    };
+
     // Override IDT
     install_handlers();
 
+    // Nothing runs on the firmware's page tables, stack, GDT or IDT any
+    // more, so the boot services memory the loader left alone is free
+    let memory_map = unsafe {
+        let address = ::mem::PhysicalAddress::new(boot_info.memory_map.address as usize).to_direct_map();
+        gnu_efi::def::MemoryDescriptors::new(
+            address.as_ptr() as *const gnu_efi::def::MemoryDescriptor,
+            boot_info.memory_map.descriptor_count as usize,
+            boot_info.memory_map.descriptor_size as usize)
+    };
+    if let Err(error) = frame_allocator.reclaim_boot_services(&memory_map) {
+        println!("Can't reclaim boot services memory: {:?}", error);
+    }
+
     println!("");
+    println!("Command line: {}", command_line::command_line().as_str());
+    println!("Kernel image at {:#x}, slide {:#x}, direct map at {:#x}",
+        layout.virtual_base, layout.slide, layout.direct_map_offset);
+    println!("Kernel SHA-256 {}", sha256::Digest(boot_info.kernel_digest));
+    println!("{} memory map entries at {:#x}",
+        boot_info.memory_map.descriptor_count, boot_info.memory_map.address);
+    if let Some(framebuffer) = boot_info.framebuffer() {
+        println!("{}x{} {:?} framebuffer at {:#x}",
+            framebuffer.width, framebuffer.height, framebuffer.pixel_format, framebuffer.address);
+    }
+    for module in modules::modules() {
+        println!("Module {}, {} bytes at {:p}, SHA-256 {}",
+            module.name, module.data.len(), module.data.as_ptr(), module.digest);
+    }
 
     //divide_by_zero();
 
@@ -133,6 +217,8 @@ pub extern fn kernel_entry(system_table:&gnu_efi::api::SystemTable, mut frame_al
         core::mem::replace(&mut falloc::FRAME_ALLOCATOR, frame_allocator);
     }
 
+    print_memory_usage();
+
     if asm_routines::cpuid_lapic_present() {
         println!("lapic present");
     }
@@ -142,7 +228,7 @@ pub extern fn kernel_entry(system_table:&gnu_efi::api::SystemTable, mut frame_al
     }
 
     // Get properties of the LAPIC
-    let (mut lapic_registers, bootstrap, enabled, extended) =
+    let (lapic_registers, bootstrap, enabled, extended) =
         asm_routines::cpuid_lapic_enabled();
     if bootstrap {
         println!("lapic is in bootstrap mode");
@@ -154,21 +240,25 @@ pub extern fn kernel_entry(system_table:&gnu_efi::api::SystemTable, mut frame_al
         println!("x2apic is enabled");
     }
 
-    // Get the RSDP from the ACPI table. Relies on
-    // vendor table from UEFI
-    let rsdp = gnu_efi::acpi::get_rsdp(system_table);
+    // The loader looked the RSDP up while UEFI's configuration table
+    // could still be trusted
+    let rsdp = match boot_info.rsdp() {
+        Some(address) => unsafe { gnu_efi::acpi::rsdp_at(address) },
+        None => panic!("No ACPI RSDP"),
+    };
 
     if rsdp.verify() {
         println!("Found valid RSDP");
     }
 
     // Verify the extended system description table.
-    if rsdp.xsdt.verify() {
+    if rsdp.xsdt().verify() {
         println!("Found valid XSDT");
     }
 
     // Find the Multiple Apic Description Table
-    if let Some(madt) = rsdp.xsdt.find_madt() {
+    let madt = rsdp.xsdt().find_madt();
+    if let Some(madt) = madt {
         println!("Found valid MADT");
 
         println!("Enumerated MADT types:");
@@ -192,24 +282,36 @@ pub extern fn kernel_entry(system_table:&gnu_efi::api::SystemTable, mut frame_al
     // Page in LAPIC
     lapic_registers.page_in(&mut page_table);
 
-    println!("lapic APIC ID: {:x}", lapic_registers.get_apic_id_register());
-    unsafe {
-        let address: *mut u32 = 0x3100 as *mut u32;
-        *address = page_table.physical_address();
-        let address: *mut u64 = 0x3200 as *mut u64;
-        *address = (ap_bootstrap) as u64;
-        lapic_registers.send_startup_ipi();
+    if serial::log_enabled(serial::LogLevel::Debug) {
+        println!("Kernel page table:");
+        page_table.print_mappings();
     }
+
+    println!("lapic APIC ID: {:x}", lapic_registers.get_apic_id_register());
     unsafe {
+        // The APs enable their LAPIC through LAPIC_REGISTERS, so it's
+        // set before they are started
         LAPIC_REGISTERS = Some(lapic_registers);
         if let Some(ref mut lapic_registers) = LAPIC_REGISTERS {
             lapic_registers.enable_lapic(0xff);
+            tlb::cpu_online(lapic_registers.apic_id());
 
             println!("{:08x}", lapic_registers.get_lvt_timer_register());
             println!("{:08x}", lapic_registers.get_timer_initial_count_register());
 
             lapic_registers.set_lvt_timer_register(apic::TimerMode::Periodic, false, 0x20);
             //lapic_registers.set_timer_initial_count_register(8000000);
+
+            write_trampoline_parameter(TRAMPOLINE_PML4, page_table.physical_address());
+            write_trampoline_parameter(TRAMPOLINE_ENTRY, (ap_bootstrap) as u64);
+            let count = command_line::parse::<usize>("cpus").map_or(usize::max_value(), |cpus| cpus.saturating_sub(1));
+            // Nothing but the APs' trampoline runs at physical addresses,
+            // so the identity map goes once they have all left it
+            if start_processors(lapic_registers, madt, count) {
+                drop_identity_map(&mut page_table, &memory_map);
+            } else {
+                println!("Keeping the identity map for the CPUs that didn't come up");
+            }
         }
     }
 
@@ -220,6 +322,8 @@ pub extern fn kernel_entry(system_table:&gnu_efi::api::SystemTable, mut frame_al
     }
 
 
+    print_memory_usage();
+
     // Shutdown the computer
     system_table.runtime_services.reset_system(
         gnu_efi::api::ResetType::ResetShutdown,
@@ -228,10 +332,131 @@ pub extern fn kernel_entry(system_table:&gnu_efi::api::SystemTable, mut frame_al
         core::ptr::null());
 }
 
+/// Starts at most `count` of the other enabled CPUs the MADT lists,
+/// one at a time as they share the trampoline. `cpus=1` leaves them
+/// all parked. Gives whether every CPU started reached `ap_bootstrap`.
+unsafe fn start_processors(lapic_registers: &mut apic::LapicRegisters,
+                           madt: Option<&gnu_efi::acpi::MultipleApicDescriptionTable>, count: usize) -> bool {
+    let madt = match madt {
+        Some(madt) => madt,
+        None => {
+            println!("No MADT to find the other CPUs in, not starting them");
+            return true;
+        }
+    };
+    let own_id = lapic_registers.apic_id();
+    let others = madt.controllers()
+        .filter(|header| header.structure_type == gnu_efi::acpi::ApicStructureType::LocalApic)
+        .map(|header| header.to_local_apic())
+        .filter(|local_apic| local_apic.enabled() && local_apic.apic_id != own_id)
+        .take(count);
+    for local_apic in others {
+        let stack = match falloc::FRAME_ALLOCATOR.get_multiple_frames(AP_STACK_PAGES, falloc::FrameUsage::Stack) {
+            Ok(frame) => ::mem::PhysicalAddress::from(frame).to_direct_map(),
+            Err(error) => {
+                println!("No stack for CPU with APIC ID {:x}: {:?}", local_apic.apic_id, error);
+                return true;
+            },
+        };
+        write_trampoline_parameter(TRAMPOLINE_STACK, usize::from(stack) + AP_STACK_PAGES * 0x1000);
+
+        println!("Starting CPU with APIC ID {:x}", local_apic.apic_id);
+        let online = tlb::online_cpus();
+        lapic_registers.send_startup_ipi_to(local_apic.apic_id);
+        // The next CPU would overwrite this one's stack in the trampoline
+        let mut spins = 0;
+        while tlb::online_cpus() == online {
+            spins += 1;
+            if spins == AP_STARTUP_SPINS {
+                println!("CPU with APIC ID {:x} didn't come up", local_apic.apic_id);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Hands the trampoline a value at the low physical `address`
+unsafe fn write_trampoline_parameter<T>(address: usize, value: T) {
+    let mut address = ::mem::PhysicalAddress::new(address).to_direct_map();
+    core::ptr::write_volatile(address.as_mut_ptr() as *mut T, value);
+}
+
+/// Unmaps the loader's identity map of low memory and of its own and
+/// the ACPI regions. UEFI runtime services run at their physical
+/// addresses, so their regions stay mapped for `reset_system`.
+fn drop_identity_map(page_table: &mut page_table::KernelPageTable, memory_map: &gnu_efi::def::MemoryDescriptors) {
+    use gnu_efi::def::MemoryType;
+
+    let is_runtime = |page: usize| memory_map.into_iter().any(|memory_descriptor| {
+        let start: ::mem::Frame = memory_descriptor.physical_start.into();
+        let start: usize = start.into();
+        let runtime = match memory_descriptor.region_type {
+            MemoryType::RuntimeServicesCode | MemoryType::RuntimeServicesData => true,
+            _ => false,
+        };
+        runtime && page >= start && page < start + memory_descriptor.number_of_pages as usize
+    });
+    let loader_regions = memory_map.into_iter()
+        .filter(|memory_descriptor| match memory_descriptor.region_type {
+            MemoryType::LoaderCode |
+            MemoryType::LoaderData |
+            MemoryType::ACPIReclaimMemory |
+            MemoryType::ACPIMemoryNVS => true,
+            _ => false,
+        })
+        .flat_map(|memory_descriptor| {
+            let start: ::mem::Frame = memory_descriptor.physical_start.into();
+            let start: usize = start.into();
+            start..start + memory_descriptor.number_of_pages as usize
+        });
+
+    let mut unmapped = 0;
+    for page in (1..page_table::LOW_IDENTITY_PAGES).chain(loader_regions) {
+        let address = ::mem::VirtualAddress::new(page * 0x1000);
+        let identity = match page_table.translate(address) {
+            Some((physical_address, _, _)) => usize::from(physical_address) == page * 0x1000,
+            None => false,
+        };
+        if identity && !is_runtime(page) && tlb::unmap_page(page_table, ::mem::Page::new(page)).is_some() {
+            unmapped += 1;
+        }
+    }
+    if serial::log_enabled(serial::LogLevel::Debug) {
+        println!("Dropped {} identity mappings", unmapped);
+    }
+}
+
+/// Loads `GDT` and reloads the segment registers from it
+unsafe fn load_gdt() {
+    if let Some(ref gdt) = GDT {
+        x86::shared::dtables::lgdt(gdt);
+    }
+
+    asm!("\
+            pushq $$0x10
+            lea 0x3(%rip), %rax
+            pushq %rax
+            lretq
+            1: nop
+            " : );
+
+    asm!("\
+            mov $$0x0, %eax
+            mov %ax, %ds
+            mov %ax, %es
+            mov %ax, %fs
+            mov %ax, %gs
+            mov %ax, %ss
+            " : );
+}
+
 fn ap_bootstrap() {
     println!("hello from processor 2");
     unsafe {
         x86_64::instructions::interrupts::disable();
+        // The trampoline's GDT is in low memory, which goes away
+        load_gdt();
         IDT.load();
         x86_64::instructions::interrupts::enable();
     }
@@ -240,6 +465,7 @@ fn ap_bootstrap() {
     unsafe {
         if let Some(ref mut lapic_registers) = LAPIC_REGISTERS {
             lapic_registers.enable_lapic(0xff);
+            tlb::cpu_online(lapic_registers.apic_id());
             println!("lapic APIC ID: {:x}", lapic_registers.get_apic_id_register());
 
             println!("{:08x}", lapic_registers.get_lvt_timer_register());
@@ -262,6 +488,40 @@ fn ap_bootstrap() {
     }
 }
 
+/// Applies the log_level and serial_baud options
+fn configure_serial() {
+    if let Some(name) = command_line::get("log_level") {
+        match serial::LogLevel::from_name(name) {
+            Some(log_level) => serial::set_log_level(log_level),
+            None => println!("Unknown log level {}", name),
+        }
+    }
+    if let Some(serial_baud) = command_line::parse::<u32>("serial_baud") {
+        // Not in the if let, the port would still be locked when
+        // printing the error
+        let result = serial::SERIAL_WRITER.lock().set_baud_rate(serial_baud);
+        if let Err(error) = result {
+            println!("Can't set the serial port to {:?}", error);
+        }
+    }
+}
+
+/// Prints the frame allocator's view of physical memory
+fn print_memory_usage() {
+    let statistics = unsafe { falloc::FRAME_ALLOCATOR.statistics() };
+    println!("Frames total: {}, free: {}, reserved: {}, allocated: {}",
+        statistics.total,
+        statistics.free,
+        statistics.reserved,
+        statistics.total_allocated());
+    for usage in &falloc::FRAME_USAGES {
+        println!("Usage: {:?}, frames: {}, bytes: {:#x}",
+            usage,
+            statistics.allocated(*usage),
+            statistics.allocated(*usage) * 0x1000);
+    }
+}
+
 fn divide_by_zero() {
     unsafe {
         asm!("mov dx, 0; div dx" ::: "ax", "dx" : "volatile", "intel")
@@ -352,6 +612,7 @@ extern fn rust_begin_unwind(
         let mut writer = serial::SerialWriter::new();
         let _ = writer.write_fmt(msg);
         let _ = writer.write_fmt(format_args!(" in file {} on line {}\n", file, line));
+        let _ = writer.write_fmt(format_args!("Kernel slide {:#x}\n", KERNEL_SLIDE));
     }
     loop {}
 }
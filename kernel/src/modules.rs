@@ -0,0 +1,74 @@
+//! Files the loader read into memory for us, such as an initial
+//! ramdisk. They stay where the loader put them and are only read.
+
+use core::slice;
+
+static mut MODULES: &'static [::boot_info::Module] = &[];
+
+/// Takes the modules from the loader's BootInfo, making sure
+/// `frame_allocator` never hands out the frames they are in
+pub unsafe fn init(boot_info: &'static ::boot_info::BootInfo, frame_allocator: &mut ::falloc::FrameAllocator) {
+    MODULES = boot_info.modules();
+    for module in MODULES.iter().filter(|module| module.size > 0) {
+        let result = frame_allocator.reserve_range(
+            ::mem::PhysicalAddress::new(module.address as usize),
+            module.size as usize);
+        if let Err(error) = result {
+            println!("Can't reserve module {}: {:?}", module.name(), error);
+        }
+    }
+}
+
+/// A boot module, as the loader left it
+#[derive(Clone, Copy)]
+pub struct Module {
+    /// The path the loader read it from
+    pub name: &'static str,
+    pub data: &'static [u8],
+    /// SHA-256 of `data`, as the loader computed it
+    pub digest: ::sha256::Digest,
+}
+
+impl Module {
+    fn from_boot_info(module: &'static ::boot_info::Module) -> Module {
+        let data: &'static [u8] = if module.size == 0 {
+            &[]
+        } else {
+            let address = ::mem::PhysicalAddress::new(module.address as usize).to_direct_map();
+            unsafe { slice::from_raw_parts(address.as_ptr() as *const u8, module.size as usize) }
+        };
+        Module {
+            name: module.name(),
+            data: data,
+            digest: ::sha256::Digest(module.digest),
+        }
+    }
+}
+
+/// Iterator over the boot modules, see `modules`
+pub struct Modules {
+    modules: slice::Iter<'static, ::boot_info::Module>,
+}
+
+impl Iterator for Modules {
+    type Item = Module;
+
+    fn next(&mut self) -> Option<Module> {
+        self.modules.next().map(Module::from_boot_info)
+    }
+}
+
+/// The boot modules, in the order the boot configuration lists them
+pub fn modules() -> Modules {
+    Modules {
+        modules: unsafe { MODULES }.iter(),
+    }
+}
+
+/// The contents of the module read from `name`, which can be the whole
+/// path or just the file name
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    modules().find(|module| {
+        module.name == name || module.name.rsplit('\\').next() == Some(name)
+    }).map(|module| module.data)
+}
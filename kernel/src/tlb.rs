@@ -0,0 +1,149 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::structures::idt::ExceptionStackFrame;
+
+/// Interrupt vector other CPUs are sent to invalidate their TLBs
+pub const SHOOTDOWN_VECTOR: u8 = 0xF0;
+
+/// Shootdowns of more pages than this flush the whole TLB instead
+const FULL_FLUSH_THRESHOLD: usize = 32;
+
+/// APIC IDs of the CPUs that take part in shootdowns, one bit each.
+/// Only these are sent `SHOOTDOWN_VECTOR`, so every interrupt sent is
+/// acknowledged exactly once.
+static ONLINE_CPUS: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Held by the CPU whose shootdown is in progress
+static SHOOTDOWN_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Request being handled by the other CPUs. A page count of 0 asks for
+/// a full flush.
+static REQUEST_START: AtomicUsize = AtomicUsize::new(0);
+static REQUEST_PAGES: AtomicUsize = AtomicUsize::new(0);
+/// CPUs that haven't acknowledged the current request yet
+static PENDING_ACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Called on each CPU once it can receive `SHOOTDOWN_VECTOR`, which
+/// needs both the IDT and an enabled LAPIC. The TLB is flushed as the
+/// CPU joins, as it wasn't told about changes made before.
+pub fn cpu_online(apic_id: u8) {
+    lock();
+    ONLINE_CPUS[apic_id as usize / 64].fetch_or(1usize << (apic_id % 64), Ordering::SeqCst);
+    flush(0, 0);
+    unlock();
+}
+
+/// Number of CPUs that take part in shootdowns
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.iter().map(|cpus| cpus.load(Ordering::SeqCst).count_ones() as usize).sum()
+}
+
+/// Invalidates `pages` pages starting at `page` on every CPU. Must be
+/// called with interrupts enabled, or two CPUs shooting down at once
+/// would wait on each other forever. Use `shootdown_all` for a full
+/// flush; a count of 0 panics.
+pub fn shootdown(page: ::mem::Page, pages: usize) {
+    assert!(pages > 0, "TLB shootdown of 0 pages");
+    if pages > FULL_FLUSH_THRESHOLD {
+        shootdown_all();
+    } else {
+        let address: ::mem::VirtualAddress = page.into();
+        request(address.into(), pages);
+    }
+}
+
+/// Flushes every non-global TLB entry on every CPU
+pub fn shootdown_all() {
+    request(0, 0);
+}
+
+/// Maps `page` in a table that may be live on other CPUs. A new
+/// mapping can't be stale anywhere, so the other CPUs are only told
+/// when it replaces one.
+pub fn insert_page(page_table: &mut ::page_table::KernelPageTable, frame: ::mem::Frame, page: ::mem::Page,
+                   page_size: ::page_table::PageSize, flags: ::page_table::MappingFlags) {
+    if page_table.translate(page.into()).is_some() {
+        unmap_page(page_table, page);
+    }
+    page_table.insert_page(frame, page, page_size, flags);
+}
+
+/// Unmaps `page` from a table that may be live on other CPUs, see
+/// `PageTable::unmap_page`
+pub fn unmap_page(page_table: &mut ::page_table::KernelPageTable, page: ::mem::Page) -> Option<::mem::Frame> {
+    // The first page of a 2MB or 1GB page takes the whole entry along
+    let pages = match page_table.translate(page.into()) {
+        Some((_, page_size, _)) if usize::from(page) % (page_size as usize / 0x1000) == 0 => page_size as usize / 0x1000,
+        _ => 1,
+    };
+    let result = page_table.unmap_page(page);
+    if result.is_some() {
+        shootdown(page, pages);
+    }
+    result
+}
+
+fn lock() {
+    while SHOOTDOWN_LOCK.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+    }
+}
+
+fn unlock() {
+    SHOOTDOWN_LOCK.store(false, Ordering::Release);
+}
+
+fn request(start: usize, pages: usize) {
+    lock();
+
+    flush(start, pages);
+
+    unsafe {
+        // No CPU can be online without a LAPIC
+        if let Some(ref mut lapic_registers) = ::LAPIC_REGISTERS {
+            let own_id = lapic_registers.apic_id() as usize;
+            let mut targets = [0; 4];
+            for (index, cpus) in ONLINE_CPUS.iter().enumerate() {
+                targets[index] = cpus.load(Ordering::SeqCst);
+            }
+            targets[own_id / 64] &= !(1usize << (own_id % 64));
+
+            REQUEST_START.store(start, Ordering::SeqCst);
+            REQUEST_PAGES.store(pages, Ordering::SeqCst);
+            PENDING_ACKS.store(targets.iter().map(|cpus| cpus.count_ones() as usize).sum(), Ordering::SeqCst);
+            for apic_id in 0..256 {
+                if targets[apic_id / 64] & (1usize << (apic_id % 64)) != 0 {
+                    lapic_registers.send_ipi_to(apic_id as u8, SHOOTDOWN_VECTOR);
+                }
+            }
+            while PENDING_ACKS.load(Ordering::SeqCst) > 0 {
+            }
+        }
+    }
+
+    unlock();
+}
+
+fn flush(start: usize, pages: usize) {
+    unsafe {
+        if pages == 0 {
+            ::x86::shared::tlb::flush_all();
+        } else {
+            for page in 0..pages {
+                ::x86::shared::tlb::flush(start + page * 0x1000);
+            }
+        }
+    }
+}
+
+pub extern "x86-interrupt" fn shootdown_handler(_: &mut ExceptionStackFrame) {
+    flush(REQUEST_START.load(Ordering::SeqCst), REQUEST_PAGES.load(Ordering::SeqCst));
+    PENDING_ACKS.fetch_sub(1, Ordering::SeqCst);
+    unsafe {
+        ::LAPIC_REGISTERS.as_mut().unwrap().eoi();
+    }
+}
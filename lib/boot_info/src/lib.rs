@@ -0,0 +1,355 @@
+//! What the loader tells the kernel about the machine and about where
+//! it put things. The loader and kernel are built separately, so the
+//! layout is `#[repr(C)]` and versioned: the kernel checks `magic` and
+//! `version` before trusting anything else, and exports the version it
+//! understands as `KERNEL_BOOT_INFO_VERSION` for the loader to check
+//! before jumping to it.
+//!
+//! Addresses are physical unless they say otherwise. The kernel reaches
+//! them through the direct map at `kernel.direct_map_offset`.
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+/// "BOOTINFO" read as a little endian u64
+pub const MAGIC: u64 = 0x4f46_4e49_544f_4f42;
+
+/// Bumped whenever the layout of `BootInfo` changes
+pub const VERSION: u32 = 4;
+
+/// Most boot modules the loader passes on
+pub const MAX_MODULES: usize = 8;
+
+/// Longest module name, in bytes
+pub const MODULE_NAME_SIZE: usize = 48;
+
+/// Bytes in a SHA-256 digest
+pub const DIGEST_SIZE: usize = 32;
+
+/// Name of the u32 symbol the kernel declares the version it
+/// understands with
+pub const VERSION_SYMBOL: &'static str = "KERNEL_BOOT_INFO_VERSION";
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct BootInfo {
+    pub magic: u64,
+    pub version: u32,
+    /// `size_of::<BootInfo>()` in the loader
+    pub size: u32,
+    /// The UEFI system table, for runtime services. Boot services have
+    /// been exited.
+    pub system_table: u64,
+    pub memory_map: MemoryMap,
+    pub command_line: CommandLine,
+    pub framebuffer: Framebuffer,
+    /// The ACPI 2.0 RSDP, 0 if the firmware has none
+    pub rsdp: u64,
+    pub kernel: KernelLayout,
+    /// The PML4 the kernel is entered on
+    pub page_table: u64,
+    /// The loader's `FrameAllocator`, which the kernel takes over. Both
+    /// are built from the same frame_allocator crate.
+    pub frame_allocator: u64,
+    /// Files the boot configuration asked for next to the kernel, the
+    /// first `module_count` of them used
+    pub modules: [Module; MAX_MODULES],
+    pub module_count: u64,
+    /// SHA-256 of the kernel file the loader read, for the kernel to
+    /// log
+    pub kernel_digest: [u8; DIGEST_SIZE],
+}
+
+/// The UEFI memory map from exiting boot services
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMap {
+    pub address: u64,
+    pub descriptor_count: u64,
+    /// Descriptors may be larger than `EFI_MEMORY_DESCRIPTOR`
+    pub descriptor_size: u64,
+}
+
+/// ASCII kernel command line, not null terminated
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CommandLine {
+    pub address: u64,
+    pub length: u64,
+}
+
+/// The linear framebuffer GOP left set up, absent if `address` is 0
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    pub address: u64,
+    /// Size in bytes
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Pixels from the start of one line to the next
+    pub stride: u32,
+    pub pixel_format: PixelFormat,
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// One byte each of red, green, blue and padding
+    Rgb,
+    /// One byte each of blue, green, red and padding
+    Bgr,
+    /// Any other layout
+    Unknown,
+}
+
+/// Where the loader put the kernel this boot
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KernelLayout {
+    /// Virtual address the image starts at
+    pub virtual_base: u64,
+    /// Where the image is in physical memory, in one contiguous run
+    pub physical_base: u64,
+    /// Size in bytes of the image, including bss
+    pub size: u64,
+    /// How far the image was moved from its link address
+    pub slide: u64,
+    /// Virtual address just above the boot stack
+    pub stack_top: u64,
+    /// Size in bytes of the boot stack. It is also physically
+    /// contiguous, starting at `stack_physical_base`.
+    pub stack_size: u64,
+    pub stack_physical_base: u64,
+    /// Virtual address physical address 0 is mapped at
+    pub direct_map_offset: u64,
+}
+
+/// A file the loader read into memory for the kernel, such as an
+/// initial ramdisk. Its frames are already reserved.
+#[repr(C)]
+#[derive(Copy)]
+pub struct Module {
+    pub address: u64,
+    /// Size in bytes
+    pub size: u64,
+    /// Path the file was read from, NUL padded
+    pub name: [u8; MODULE_NAME_SIZE],
+    /// Bytes of `name` in use
+    pub name_length: u64,
+    /// SHA-256 of the file
+    pub digest: [u8; DIGEST_SIZE],
+}
+
+impl Clone for Module {
+    fn clone(&self) -> Module {
+        *self
+    }
+}
+
+impl Module {
+    /// A module at `address`, or `None` if `name` is longer than
+    /// `MODULE_NAME_SIZE` bytes. A cut off path could no longer be
+    /// found by its file name.
+    pub fn new(address: u64, size: u64, name: &str, digest: [u8; DIGEST_SIZE]) -> Option<Module> {
+        if name.len() > MODULE_NAME_SIZE {
+            return None;
+        }
+        let mut module = Module {
+            address: address,
+            size: size,
+            name: [0; MODULE_NAME_SIZE],
+            name_length: name.len() as u64,
+            digest: digest,
+        };
+        module.name[..name.len()].copy_from_slice(name.as_bytes());
+        Some(module)
+    }
+
+    fn empty() -> Module {
+        Module {
+            address: 0,
+            size: 0,
+            name: [0; MODULE_NAME_SIZE],
+            name_length: 0,
+            digest: [0; DIGEST_SIZE],
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        let length = ::core::cmp::min(self.name_length as usize, MODULE_NAME_SIZE);
+        ::core::str::from_utf8(&self.name[..length]).unwrap_or("")
+    }
+}
+
+impl ::core::fmt::Debug for Module {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_struct("Module")
+            .field("address", &format_args!("{:#x}", self.address))
+            .field("size", &self.size)
+            .field("name", &self.name())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoError {
+    BadMagic(u64),
+    UnsupportedVersion(u32),
+    /// The loader's `BootInfo` is smaller than this one
+    Truncated(u32),
+}
+
+impl BootInfo {
+    /// An empty `BootInfo` of the current version, for the loader to
+    /// fill in
+    pub fn new() -> BootInfo {
+        BootInfo {
+            magic: MAGIC,
+            version: VERSION,
+            size: ::core::mem::size_of::<BootInfo>() as u32,
+            system_table: 0,
+            memory_map: MemoryMap {
+                address: 0,
+                descriptor_count: 0,
+                descriptor_size: 0,
+            },
+            command_line: CommandLine {
+                address: 0,
+                length: 0,
+            },
+            framebuffer: Framebuffer {
+                address: 0,
+                size: 0,
+                width: 0,
+                height: 0,
+                stride: 0,
+                pixel_format: PixelFormat::Unknown,
+            },
+            rsdp: 0,
+            kernel: KernelLayout {
+                virtual_base: 0,
+                physical_base: 0,
+                size: 0,
+                slide: 0,
+                stack_top: 0,
+                stack_size: 0,
+                stack_physical_base: 0,
+                direct_map_offset: 0,
+            },
+            page_table: 0,
+            frame_allocator: 0,
+            modules: [Module::empty(); MAX_MODULES],
+            module_count: 0,
+            kernel_digest: [0; DIGEST_SIZE],
+        }
+    }
+
+    /// Whether this was written by a loader speaking our version
+    pub fn check(&self) -> Result<(), BootInfoError> {
+        if self.magic != MAGIC {
+            Err(BootInfoError::BadMagic(self.magic))
+        } else if self.version != VERSION {
+            Err(BootInfoError::UnsupportedVersion(self.version))
+        } else if (self.size as usize) < ::core::mem::size_of::<BootInfo>() {
+            Err(BootInfoError::Truncated(self.size))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn framebuffer(&self) -> Option<&Framebuffer> {
+        if self.framebuffer.address != 0 {
+            Some(&self.framebuffer)
+        } else {
+            None
+        }
+    }
+
+    pub fn rsdp(&self) -> Option<u64> {
+        if self.rsdp != 0 {
+            Some(self.rsdp)
+        } else {
+            None
+        }
+    }
+
+    pub fn modules(&self) -> &[Module] {
+        let count = ::core::cmp::min(self.module_count as usize, MAX_MODULES);
+        &self.modules[..count]
+    }
+
+    /// Adds a module, giving it back if all `MAX_MODULES` are in use
+    pub fn add_module(&mut self, module: Module) -> Result<(), Module> {
+        let count = self.module_count as usize;
+        if count >= MAX_MODULES {
+            return Err(module);
+        }
+        self.modules[count] = module;
+        self.module_count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BootInfo, BootInfoError, Module, MAGIC, VERSION, MAX_MODULES, MODULE_NAME_SIZE};
+
+    #[test]
+    fn magic_spells_bootinfo() {
+        let magic = b"BOOTINFO".iter().rev().fold(0u64, |magic, &byte| magic << 8 | byte as u64);
+        assert_eq!(magic, MAGIC);
+    }
+
+    #[test]
+    fn check() {
+        assert_eq!(BootInfo::new().check(), Ok(()));
+
+        let mut boot_info = BootInfo::new();
+        boot_info.magic = 0;
+        assert_eq!(boot_info.check(), Err(BootInfoError::BadMagic(0)));
+
+        let mut boot_info = BootInfo::new();
+        boot_info.version = VERSION + 1;
+        assert_eq!(boot_info.check(), Err(BootInfoError::UnsupportedVersion(VERSION + 1)));
+
+        let mut boot_info = BootInfo::new();
+        boot_info.size = 16;
+        assert_eq!(boot_info.check(), Err(BootInfoError::Truncated(16)));
+    }
+
+    #[test]
+    fn absent_parts() {
+        let mut boot_info = BootInfo::new();
+        assert!(boot_info.framebuffer().is_none());
+        assert!(boot_info.rsdp().is_none());
+        boot_info.framebuffer.address = 0x8000_0000;
+        boot_info.rsdp = 0xe_0000;
+        assert_eq!(boot_info.framebuffer().unwrap().address, 0x8000_0000);
+        assert_eq!(boot_info.rsdp(), Some(0xe_0000));
+    }
+
+    #[test]
+    fn modules() {
+        let mut boot_info = BootInfo::new();
+        assert!(boot_info.modules().is_empty());
+        for index in 0..MAX_MODULES {
+            let module = Module::new(0x10_0000 * index as u64, 0x1000, "EFI\\OS\\INITRD.TAR", [index as u8; 32]);
+            boot_info.add_module(module.unwrap()).unwrap();
+        }
+        assert!(boot_info.add_module(Module::new(0, 0, "one too many", [0; 32]).unwrap()).is_err());
+        assert_eq!(boot_info.modules().len(), MAX_MODULES);
+        assert_eq!(boot_info.modules()[1].address, 0x10_0000);
+        assert_eq!(boot_info.modules()[1].name(), "EFI\\OS\\INITRD.TAR");
+        assert_eq!(boot_info.modules()[1].digest, [1; 32]);
+
+        // Names are kept whole or not at all
+        let name = [b'a'; MODULE_NAME_SIZE + 1];
+        let name = ::std::str::from_utf8(&name).unwrap();
+        assert_eq!(Module::new(0, 0, &name[..MODULE_NAME_SIZE], [0; 32]).unwrap().name(), &name[..MODULE_NAME_SIZE]);
+        assert!(Module::new(0, 0, name, [0; 32]).is_none());
+    }
+}
@@ -1,171 +1,1142 @@
 #![no_std]
-use ::core::marker::PhantomData;
 
-//pub use File;
+#[cfg(test)]
+extern crate std;
 
+/// The bytes every ELF file starts with
+pub const MAGIC: &'static [u8; 4] = b"\x7fELF";
 
-pub struct File<'a> {
-    buffer: *mut u8,
-    size: usize,
-    phantom_data: PhantomData<&'a u8>,
+/// Size of the ELF64 file header
+const FILE_HEADER_SIZE: usize = 64;
+/// Size of an ELF64 program header
+const PROGRAM_HEADER_SIZE: usize = 56;
+/// Size of an ELF64 section header
+const SECTION_HEADER_SIZE: usize = 64;
+/// Size of an ELF64 symbol table entry
+const SYMBOL_SIZE: usize = 24;
+/// Size of an ELF64 relocation with an addend
+const RELOCATION_SIZE: usize = 24;
+
+/// Reasons a buffer is rejected as an ELF file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ElfError {
+    /// The buffer is smaller than the file header
+    Truncated,
+    /// The buffer doesn't start with `\x7fELF`
+    BadMagic,
+    /// Only ELF64 is supported
+    UnsupportedClass(u8),
+    /// Only little endian files are supported
+    UnsupportedEndianness(u8),
+    UnsupportedVersion(u32),
+    /// Only x86_64 is supported
+    UnsupportedMachine(InstructionSetArchitecture),
+    /// A header table's entries are smaller than the headers they hold
+    BadEntrySize,
+    ProgramHeadersOutOfBounds,
+    SectionHeadersOutOfBounds,
+    /// The segment at this index has file bytes outside the buffer, or
+    /// more file bytes than memory bytes
+    SegmentOutOfBounds(usize),
+    /// The section at this index has file bytes outside the buffer
+    SectionOutOfBounds(usize),
+    /// `section_header_string_index` doesn't name a section
+    BadStringTableIndex,
+    /// The symbol table at this index has entries smaller than a
+    /// symbol, or links to a string table that doesn't exist
+    BadSymbolTable(usize),
+    /// The relocation table at this index has entries smaller than a
+    /// relocation, or links to a section that isn't a symbol table
+    BadRelocationTable(usize),
 }
 
+/// An ELF64 file that has been checked to be little endian x86_64,
+/// with every header and every section and segment inside the buffer
+#[derive(Clone, Copy)]
+pub struct File<'a> {
+    buffer: &'a [u8],
+    header: FileHeader,
+}
 
 impl<'a> File<'a> {
-    pub fn from_buffer<'b>(buffer: &'b mut [u8]) -> File<'b> {
-        File {
-            buffer: buffer.as_mut_ptr(),
-            size: buffer.len(),
-            phantom_data: PhantomData,
+    pub fn from_buffer(buffer: &'a [u8]) -> Result<File<'a>, ElfError> {
+        let header = FileHeader::parse(buffer)?;
+        let file = File {
+            buffer: buffer,
+            header: header,
+        };
+
+        if header.program_header_number > 0 {
+            if (header.program_header_entry_size as usize) < PROGRAM_HEADER_SIZE {
+                return Err(ElfError::BadEntrySize);
+            }
+            if !table_in_bounds(buffer, header.program_header_offset, header.program_header_number, header.program_header_entry_size) {
+                return Err(ElfError::ProgramHeadersOutOfBounds);
+            }
+        }
+        if header.section_header_number > 0 {
+            if (header.section_header_entry_size as usize) < SECTION_HEADER_SIZE {
+                return Err(ElfError::BadEntrySize);
+            }
+            if !table_in_bounds(buffer, header.section_header_offset, header.section_header_number, header.section_header_entry_size) {
+                return Err(ElfError::SectionHeadersOutOfBounds);
+            }
+        }
+        if header.section_header_string_index != 0 &&
+            header.section_header_string_index >= header.section_header_number {
+            return Err(ElfError::BadStringTableIndex);
+        }
+
+        for (index, program_header) in file.program_headers().enumerate() {
+            if program_header.file_size > program_header.memory_size ||
+                !range_in_bounds(buffer, program_header.file_offset, program_header.file_size) {
+                return Err(ElfError::SegmentOutOfBounds(index));
+            }
+        }
+        for index in 0..header.section_header_number as usize {
+            let section_header = file.section_header(index).unwrap();
+            if section_header.section_type != SectionType::NoBits &&
+                !range_in_bounds(buffer, section_header.file_offset, section_header.size) {
+                return Err(ElfError::SectionOutOfBounds(index));
+            }
+            if section_header.is_symbol_table() &&
+                (section_header.entry_size < SYMBOL_SIZE ||
+                 section_header.link >= header.section_header_number as u32) {
+                return Err(ElfError::BadSymbolTable(index));
+            }
+            if section_header.section_type == SectionType::Rela &&
+                (section_header.entry_size < RELOCATION_SIZE ||
+                 !(section_header.link == 0 || file.section_header(section_header.link as usize)
+                    .map_or(false, |symbol_table| symbol_table.is_symbol_table()))) {
+                return Err(ElfError::BadRelocationTable(index));
+            }
         }
+
+        Ok(file)
     }
 
     pub fn size(&self) -> usize {
-        self.size
+        self.buffer.len()
     }
 
     pub fn file_header(&self) -> &FileHeader {
-        unsafe {
-            ::core::mem::transmute(self.buffer)
-        }
+        &self.header
     }
 
-    pub fn section_headers(&'a self) -> SectionHeaders<'a> {
-        let pointer = unsafe { self.buffer.offset(self.file_header().section_header_offset) as *const SectionHeader };
+    /// Every section but the null section at index 0
+    pub fn section_headers(&self) -> SectionHeaders<'a> {
         SectionHeaders {
-            start: pointer,
+            file: *self,
+            current: 1,
+        }
+    }
+
+    pub fn section_header(&self, index: usize) -> Option<SectionHeader> {
+        if index < self.header.section_header_number as usize {
+            let offset = self.header.section_header_offset + index * self.header.section_header_entry_size as usize;
+            Some(SectionHeader::parse(&self.buffer[offset..offset + SECTION_HEADER_SIZE]))
+        } else {
+            None
+        }
+    }
+
+    pub fn program_headers(&self) -> ProgramHeaders<'a> {
+        ProgramHeaders {
+            file: *self,
+            current: 0,
+        }
+    }
+
+    pub fn program_header(&self, index: usize) -> Option<ProgramHeader> {
+        if index < self.header.program_header_number as usize {
+            let offset = self.header.program_header_offset + index * self.header.program_header_entry_size as usize;
+            Some(ProgramHeader::parse(&self.buffer[offset..offset + PROGRAM_HEADER_SIZE]))
+        } else {
+            None
+        }
+    }
+
+    /// Name of the section from the section name string table
+    pub fn section_name(&self, section_header: &SectionHeader) -> Option<&'a str> {
+        match self.header.section_header_string_index {
+            0 => None,
+            index => self.string(index as usize, section_header.name_index as usize),
+        }
+    }
+
+    pub fn section_by_name(&self, name: &str) -> Option<SectionHeader> {
+        self.section_headers().find(|section_header| self.section_name(section_header) == Some(name))
+    }
+
+    /// The NUL terminated string at `offset` in the string table
+    /// section at `table_index`. `None` if the section isn't a string
+    /// table or the string isn't terminated or UTF-8.
+    pub fn string(&self, table_index: usize, offset: usize) -> Option<&'a str> {
+        let table = match self.section_header(table_index) {
+            Some(table) if table.section_type == SectionType::StringTable => table,
+            _ => return None,
+        };
+        let data = table.offset_buffer(self);
+        if offset >= data.len() {
+            return None;
+        }
+        let data = &data[offset..];
+        data.iter().position(|&byte| byte == 0).and_then(|length| {
+            ::core::str::from_utf8(&data[..length]).ok()
+        })
+    }
+
+    /// Symbols of the static symbol table, or of the dynamic symbol
+    /// table if the file has been stripped
+    pub fn symbols(&self) -> Symbols<'a> {
+        let table = self.section_headers().find(|section_header| {
+            section_header.section_type == SectionType::SymbolTable
+        }).or_else(|| self.section_headers().find(|section_header| {
+            section_header.section_type == SectionType::DynamicLoaderSymbolTable
+        }));
+        match table {
+            Some(table) => self.symbols_in(&table),
+            None => Symbols {
+                file: *self,
+                data: &[],
+                entry_size: SYMBOL_SIZE,
+                string_table: 0,
+                current: 1,
+            },
+        }
+    }
+
+    /// Symbols of a symbol table section
+    pub fn symbols_in(&self, table: &SectionHeader) -> Symbols<'a> {
+        Symbols {
+            file: *self,
+            data: table.offset_buffer(self),
+            entry_size: table.entry_size,
+            string_table: table.link as usize,
+            // Symbol 0 is the undefined symbol
             current: 1,
-            file: self,
         }
     }
+
+    /// Relocations of a `Rela` section, with the symbols they refer to
+    pub fn relocations(&self, table: &SectionHeader) -> Relocations<'a> {
+        let symbols = match table.link {
+            0 => None,
+            link => self.section_header(link as usize).map(|symbol_table| self.symbols_in(&symbol_table)),
+        };
+        Relocations {
+            data: table.offset_buffer(self),
+            entry_size: table.entry_size,
+            symbols: symbols,
+            current: 0,
+        }
+    }
+
+    /// Relocations of every `Rela` section, which for a linked
+    /// executable is `.rela.dyn`
+    pub fn all_relocations(&self) -> AllRelocations<'a> {
+        AllRelocations {
+            file: *self,
+            sections: self.section_headers(),
+            current: None,
+        }
+    }
+
+    pub fn find_symbol_by_name(&self, name: &str) -> Option<Symbol<'a>> {
+        self.symbols().find(|symbol| symbol.name == name)
+    }
+
+    /// The bytes of the file a defined symbol covers, e.g. to read a
+    /// constant the file exports. None if they aren't in the file.
+    pub fn symbol_data(&self, symbol: &Symbol) -> Option<&'a [u8]> {
+        if symbol.is_undefined() || symbol.is_absolute() {
+            return None;
+        }
+        self.section_header(symbol.section_index as usize).and_then(|section| {
+            let data = section.offset_buffer(self);
+            symbol.value.checked_sub(section.virtual_address).and_then(|offset| {
+                if range_in_bounds(data, offset, symbol.size) {
+                    Some(&data[offset..offset + symbol.size])
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
+    /// The function or object symbol `address` falls in, and how far
+    /// into the symbol it is
+    pub fn symbolize(&self, address: usize) -> Option<(Symbol<'a>, usize)> {
+        self.symbols().filter(|symbol| {
+            match symbol.symbol_type {
+                SymbolType::Function | SymbolType::Object => symbol.contains(address),
+                _ => false,
+            }
+        }).map(|symbol| (symbol, address - symbol.value)).min_by_key(|&(_, offset)| offset)
+    }
 }
 
-#[repr(packed)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct FileHeader {
-    identifier: FileHeaderIdentifier,
-    elf_type: ElfType,
-    machine: InstructionSetArchitecture,
-    version: u32,
-    entry: usize,
-    program_header_offset: isize,
-    section_header_offset: isize,
-    flags: u32,
-    elf_header_size: u16,
-    program_header_entry_size: u16,
-    program_header_humber: u16,
-    section_header_entry_size: u16,
-    section_header_number: u16,
-    section_header_string_index: u16,
+    pub elf_type: ElfType,
+    pub machine: InstructionSetArchitecture,
+    pub entry: usize,
+    pub program_header_offset: usize,
+    pub section_header_offset: usize,
+    pub flags: u32,
+    pub program_header_entry_size: u16,
+    pub program_header_number: u16,
+    pub section_header_entry_size: u16,
+    pub section_header_number: u16,
+    pub section_header_string_index: u16,
 }
 
 impl FileHeader {
+    /// Reads the header, checking the identification bytes, version and
+    /// machine
+    fn parse(buffer: &[u8]) -> Result<FileHeader, ElfError> {
+        if buffer.len() < FILE_HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if &buffer[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if buffer[4] != 2 {
+            return Err(ElfError::UnsupportedClass(buffer[4]));
+        }
+        if buffer[5] != 1 {
+            return Err(ElfError::UnsupportedEndianness(buffer[5]));
+        }
+        if buffer[6] != 1 {
+            return Err(ElfError::UnsupportedVersion(buffer[6] as u32));
+        }
+        let machine = InstructionSetArchitecture::from(read_u16(buffer, 18));
+        if machine != InstructionSetArchitecture::X86_64 {
+            return Err(ElfError::UnsupportedMachine(machine));
+        }
+        let version = read_u32(buffer, 20);
+        if version != 1 {
+            return Err(ElfError::UnsupportedVersion(version));
+        }
+
+        Ok(FileHeader {
+            elf_type: read_u16(buffer, 16).into(),
+            machine: machine,
+            entry: read_u64(buffer, 24) as usize,
+            program_header_offset: read_u64(buffer, 32) as usize,
+            section_header_offset: read_u64(buffer, 40) as usize,
+            flags: read_u32(buffer, 48),
+            program_header_entry_size: read_u16(buffer, 54),
+            program_header_number: read_u16(buffer, 56),
+            section_header_entry_size: read_u16(buffer, 58),
+            section_header_number: read_u16(buffer, 60),
+            section_header_string_index: read_u16(buffer, 62),
+        })
+    }
+
     pub fn entry_ptr(&self) -> *const u8 {
         self.entry as *const u8
     }
 }
 
-#[repr(packed)]
-#[derive(Debug)]
-pub struct FileHeaderIdentifier {
-    magic_number: [u8; 4],
-    class: u8,
-    data: u8,
-    version: u8,
-    os_abi: u8,
-    abi_version: u8,
-    abi_padding: [u8; 7],
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ElfType {
+    None,
+    Relocatable,
+    Executable,
+    Dynamic,
+    Core,
+    Other(u16),
+}
+
+impl From<u16> for ElfType {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => ElfType::None,
+            1 => ElfType::Relocatable,
+            2 => ElfType::Executable,
+            3 => ElfType::Dynamic,
+            4 => ElfType::Core,
+            other => ElfType::Other(other),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InstructionSetArchitecture {
+    NoSpecific,
+    Sparc,
+    X86,
+    Ia64,
+    X86_64,
+    Other(u16),
 }
 
-#[repr(u16)]
-#[derive(Debug)]
-pub enum ElfType {
-    None = 0,
-    Relocatable = 1,
-    Executable = 2,
-    Dynamic = 3,
-    Core = 4,
-    LOOS = 0xfe00,
-    HIOS = 0xfeff,
-    LOPROC = 0xff00,
-    HIPROC = 0xffff,
-}
-
-#[repr(u16)]
-#[derive(Debug)]
-enum InstructionSetArchitecture {
-    NoSpecific = 0,
-    SPAC = 0x02,
-    x86 = 0x03,
-    IA_64 = 0x32,
-    x86_64 = 0x3e,
+impl From<u16> for InstructionSetArchitecture {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => InstructionSetArchitecture::NoSpecific,
+            0x02 => InstructionSetArchitecture::Sparc,
+            0x03 => InstructionSetArchitecture::X86,
+            0x32 => InstructionSetArchitecture::Ia64,
+            0x3e => InstructionSetArchitecture::X86_64,
+            other => InstructionSetArchitecture::Other(other),
+        }
+    }
 }
 
 pub struct SectionHeaders<'a> {
-    start: *const SectionHeader,
+    file: File<'a>,
     current: usize,
-    file: &'a File<'a>,
 }
 
 impl<'a> Iterator for SectionHeaders<'a> {
-    type Item = &'a SectionHeader;
+    type Item = SectionHeader;
     fn next(&mut self) -> Option<Self::Item> {
-        match self.current {
-            _ if self.current < self.file.file_header().section_header_number as usize => {
-                let pointer = self.start as *const u8;
-                let result = unsafe { Some(::core::mem::transmute(pointer.offset(((self.file.file_header().section_header_entry_size as usize) * self.current) as isize))) };
-                self.current += 1;
-                result
-            },
-            _ => None,
+        let result = self.file.section_header(self.current);
+        if result.is_some() {
+            self.current += 1;
         }
-
+        result
     }
 }
 
-#[repr(packed)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct SectionHeader {
-    name_index: u32,
+    pub name_index: u32,
     pub section_type: SectionType,
-    flags: usize,
-    pub virtual_address: *mut u8,
-    file_offset: isize,
+    pub flags: usize,
+    pub virtual_address: usize,
+    pub file_offset: usize,
     pub size: usize,
-    link: u32,
-    info: u32,
-    address_align: u64,
-    entry_size: u64,
+    pub link: u32,
+    pub info: u32,
+    pub address_align: usize,
+    pub entry_size: usize,
 }
 
 impl SectionHeader {
-    pub fn offset_buffer(&self, file: &File) -> &[u8] {
-        unsafe {
-            ::core::slice::from_raw_parts(file.buffer.offset(self.file_offset), self.size)
+    fn parse(bytes: &[u8]) -> SectionHeader {
+        SectionHeader {
+            name_index: read_u32(bytes, 0),
+            section_type: read_u32(bytes, 4).into(),
+            flags: read_u64(bytes, 8) as usize,
+            virtual_address: read_u64(bytes, 16) as usize,
+            file_offset: read_u64(bytes, 24) as usize,
+            size: read_u64(bytes, 32) as usize,
+            link: read_u32(bytes, 40),
+            info: read_u32(bytes, 44),
+            address_align: read_u64(bytes, 48) as usize,
+            entry_size: read_u64(bytes, 56) as usize,
+        }
+    }
+    /// Contents of the section in the file, empty for `NoBits`
+    pub fn offset_buffer<'b>(&self, file: &File<'b>) -> &'b [u8] {
+        match self.section_type {
+            SectionType::NoBits => &[],
+            _ => &file.buffer[self.file_offset..self.file_offset + self.size],
         }
     }
     pub fn addr_ptr(&self) -> *mut u8 {
-        self.virtual_address
+        self.virtual_address as *mut u8
+    }
+    /// SHF_WRITE
+    pub fn is_writable(&self) -> bool {
+        self.flags & 0x1 == 0x1
+    }
+    /// SHF_EXECINSTR
+    pub fn is_executable(&self) -> bool {
+        self.flags & 0x4 == 0x4
+    }
+    fn is_symbol_table(&self) -> bool {
+        match self.section_type {
+            SectionType::SymbolTable | SectionType::DynamicLoaderSymbolTable => true,
+            _ => false,
+        }
     }
 }
 
-#[repr(u32)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum SectionType {
-    Null = 0,
-    ProgramBits = 1,
-    SymbolTable = 2,
-    StringTable = 3,
-    Rela = 4,
-    SymbolHashTable = 5,
-    DynamicLinkingTable = 6,
-    Note = 7,
-    NoBits = 8,
-    Rel = 9,
-    SectionHeaderLib = 10,
-    DynamicLoaderSymbolTable = 11,
-    LOOS = 0x60000000,
-    HIOS = 0x6FFFFFFF,
-    LOPROC = 0x70000000,
-    HIPROC = 0x7FFFFFFF,
+    Null,
+    ProgramBits,
+    SymbolTable,
+    StringTable,
+    Rela,
+    SymbolHashTable,
+    DynamicLinkingTable,
+    Note,
+    NoBits,
+    Rel,
+    SectionHeaderLib,
+    DynamicLoaderSymbolTable,
+    Other(u32),
+}
+
+impl From<u32> for SectionType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => SectionType::Null,
+            1 => SectionType::ProgramBits,
+            2 => SectionType::SymbolTable,
+            3 => SectionType::StringTable,
+            4 => SectionType::Rela,
+            5 => SectionType::SymbolHashTable,
+            6 => SectionType::DynamicLinkingTable,
+            7 => SectionType::Note,
+            8 => SectionType::NoBits,
+            9 => SectionType::Rel,
+            10 => SectionType::SectionHeaderLib,
+            11 => SectionType::DynamicLoaderSymbolTable,
+            other => SectionType::Other(other),
+        }
+    }
+}
+
+pub struct ProgramHeaders<'a> {
+    file: File<'a>,
+    current: usize,
+}
+
+impl<'a> Iterator for ProgramHeaders<'a> {
+    type Item = ProgramHeader;
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.file.program_header(self.current);
+        if result.is_some() {
+            self.current += 1;
+        }
+        result
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ProgramHeader {
+    pub segment_type: SegmentType,
+    pub flags: u32,
+    pub file_offset: usize,
+    pub virtual_address: usize,
+    pub physical_address: usize,
+    pub file_size: usize,
+    pub memory_size: usize,
+    pub align: usize,
+}
+
+impl ProgramHeader {
+    fn parse(bytes: &[u8]) -> ProgramHeader {
+        ProgramHeader {
+            segment_type: read_u32(bytes, 0).into(),
+            flags: read_u32(bytes, 4),
+            file_offset: read_u64(bytes, 8) as usize,
+            virtual_address: read_u64(bytes, 16) as usize,
+            physical_address: read_u64(bytes, 24) as usize,
+            file_size: read_u64(bytes, 32) as usize,
+            memory_size: read_u64(bytes, 40) as usize,
+            align: read_u64(bytes, 48) as usize,
+        }
+    }
+    pub fn segment_type(&self) -> SegmentType {
+        self.segment_type
+    }
+    /// The first `file_size` bytes of the segment as stored in the file.
+    /// The rest of the segment, up to `memory_size`, is zero.
+    pub fn file_buffer<'b>(&self, file: &File<'b>) -> &'b [u8] {
+        &file.buffer[self.file_offset..self.file_offset + self.file_size]
+    }
+    /// PF_X
+    pub fn is_executable(&self) -> bool {
+        self.flags & 0x1 == 0x1
+    }
+    /// PF_W
+    pub fn is_writable(&self) -> bool {
+        self.flags & 0x2 == 0x2
+    }
+    /// PF_R
+    pub fn is_readable(&self) -> bool {
+        self.flags & 0x4 == 0x4
+    }
+}
+
+/// Types other than these, such as the GNU stack and relro
+/// segments, are kept as their raw value
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SegmentType {
+    Null,
+    Load,
+    Dynamic,
+    Interpreter,
+    Note,
+    Shlib,
+    ProgramHeader,
+    ThreadLocalStorage,
+    Other(u32),
+}
+
+impl From<u32> for SegmentType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => SegmentType::Null,
+            1 => SegmentType::Load,
+            2 => SegmentType::Dynamic,
+            3 => SegmentType::Interpreter,
+            4 => SegmentType::Note,
+            5 => SegmentType::Shlib,
+            6 => SegmentType::ProgramHeader,
+            7 => SegmentType::ThreadLocalStorage,
+            other => SegmentType::Other(other),
+        }
+    }
+}
+
+pub struct Symbols<'a> {
+    file: File<'a>,
+    data: &'a [u8],
+    entry_size: usize,
+    string_table: usize,
+    current: usize,
+}
+
+impl<'a> Symbols<'a> {
+    /// The symbol at `index` in the table, regardless of how far the
+    /// iterator has got
+    pub fn get(&self, index: usize) -> Option<Symbol<'a>> {
+        let offset = match index.checked_mul(self.entry_size) {
+            Some(offset) if range_in_bounds(self.data, offset, SYMBOL_SIZE) => offset,
+            _ => return None,
+        };
+        let bytes = &self.data[offset..offset + SYMBOL_SIZE];
+        let info = bytes[4];
+        Some(Symbol {
+            // Symbols with unreadable names are still returned, so
+            // addresses inside them aren't blamed on their neighbours
+            name: self.file.string(self.string_table, read_u32(bytes, 0) as usize).unwrap_or(""),
+            value: read_u64(bytes, 8) as usize,
+            size: read_u64(bytes, 16) as usize,
+            symbol_type: (info & 0xf).into(),
+            binding: (info >> 4).into(),
+            section_index: read_u16(bytes, 6),
+        })
+    }
+}
+
+impl<'a> Iterator for Symbols<'a> {
+    type Item = Symbol<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.get(self.current);
+        if result.is_some() {
+            self.current += 1;
+        }
+        result
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub value: usize,
+    pub size: usize,
+    pub symbol_type: SymbolType,
+    pub binding: SymbolBinding,
+    /// Section the symbol is defined in, 0 if it is undefined
+    pub section_index: u16,
+}
+
+impl<'a> Symbol<'a> {
+    /// SHN_UNDEF, a symbol some other object has to define
+    pub fn is_undefined(&self) -> bool {
+        self.section_index == 0
+    }
+    /// SHN_ABS, a value that doesn't move with the file's sections
+    pub fn is_absolute(&self) -> bool {
+        self.section_index == 0xfff1
+    }
+    /// Whether `address` is inside the symbol. Symbols without a size
+    /// only contain their own address.
+    pub fn contains(&self, address: usize) -> bool {
+        match self.size {
+            0 => address == self.value,
+            size => self.value <= address && address - self.value < size,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SymbolType {
+    NoType,
+    Object,
+    Function,
+    Section,
+    File,
+    Common,
+    ThreadLocalStorage,
+    Other(u8),
+}
+
+impl From<u8> for SymbolType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => SymbolType::NoType,
+            1 => SymbolType::Object,
+            2 => SymbolType::Function,
+            3 => SymbolType::Section,
+            4 => SymbolType::File,
+            5 => SymbolType::Common,
+            6 => SymbolType::ThreadLocalStorage,
+            other => SymbolType::Other(other),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SymbolBinding {
+    Local,
+    Global,
+    Weak,
+    Other(u8),
+}
+
+impl From<u8> for SymbolBinding {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => SymbolBinding::Local,
+            1 => SymbolBinding::Global,
+            2 => SymbolBinding::Weak,
+            other => SymbolBinding::Other(other),
+        }
+    }
+}
+
+pub struct Relocations<'a> {
+    data: &'a [u8],
+    entry_size: usize,
+    symbols: Option<Symbols<'a>>,
+    current: usize,
+}
+
+impl<'a> Iterator for Relocations<'a> {
+    type Item = Relocation<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = match self.current.checked_mul(self.entry_size) {
+            Some(offset) if range_in_bounds(self.data, offset, RELOCATION_SIZE) => offset,
+            _ => return None,
+        };
+        self.current += 1;
+        let bytes = &self.data[offset..offset + RELOCATION_SIZE];
+        let symbol_index = read_u32(bytes, 12) as usize;
+        Some(Relocation {
+            offset: read_u64(bytes, 0) as usize,
+            relocation_type: read_u32(bytes, 8).into(),
+            symbol: match symbol_index {
+                0 => None,
+                index => self.symbols.as_ref().and_then(|symbols| symbols.get(index)),
+            },
+            symbol_index: symbol_index as u32,
+            addend: read_u64(bytes, 16) as isize,
+        })
+    }
+}
+
+/// Relocations of every `Rela` section in turn
+pub struct AllRelocations<'a> {
+    file: File<'a>,
+    sections: SectionHeaders<'a>,
+    current: Option<Relocations<'a>>,
+}
+
+impl<'a> Iterator for AllRelocations<'a> {
+    type Item = Relocation<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(relocation) = self.current.as_mut().and_then(|relocations| relocations.next()) {
+                return Some(relocation);
+            }
+            match self.sections.next() {
+                Some(section_header) => if section_header.section_type == SectionType::Rela {
+                    self.current = Some(self.file.relocations(&section_header));
+                },
+                None => return None,
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Relocation<'a> {
+    /// Link address of the bytes to patch
+    pub offset: usize,
+    pub relocation_type: RelocationType,
+    /// The symbol the relocation refers to, `None` for relocations
+    /// that don't use one, or whose symbol couldn't be read
+    pub symbol: Option<Symbol<'a>>,
+    pub symbol_index: u32,
+    pub addend: isize,
+}
+
+/// x86_64 relocation types
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RelocationType {
+    None,
+    /// R_X86_64_64, S + A
+    Direct64,
+    /// R_X86_64_GLOB_DAT, S
+    GlobalData,
+    /// R_X86_64_JUMP_SLOT, S
+    JumpSlot,
+    /// R_X86_64_RELATIVE, B + A
+    Relative,
+    Other(u32),
+}
+
+impl From<u32> for RelocationType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => RelocationType::None,
+            1 => RelocationType::Direct64,
+            6 => RelocationType::GlobalData,
+            7 => RelocationType::JumpSlot,
+            8 => RelocationType::Relative,
+            other => RelocationType::Other(other),
+        }
+    }
+}
+
+/// Whether `size` bytes at `offset` are inside the buffer
+fn range_in_bounds(buffer: &[u8], offset: usize, size: usize) -> bool {
+    match offset.checked_add(size) {
+        Some(end) => end <= buffer.len(),
+        None => false,
+    }
+}
+
+/// Whether a table of `number` entries `entry_size` bytes apart at
+/// `offset` is inside the buffer
+fn table_in_bounds(buffer: &[u8], offset: usize, number: u16, entry_size: u16) -> bool {
+    match (number as usize).checked_mul(entry_size as usize) {
+        Some(size) => range_in_bounds(buffer, offset, size),
+        None => false,
+    }
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    buffer[offset] as u16 | (buffer[offset + 1] as u16) << 8
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    read_u16(buffer, offset) as u32 | (read_u16(buffer, offset + 2) as u32) << 16
+}
+
+fn read_u64(buffer: &[u8], offset: usize) -> u64 {
+    read_u32(buffer, offset) as u64 | (read_u32(buffer, offset + 4) as u64) << 32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::{ElfError, ElfType, File, InstructionSetArchitecture, SectionType, SegmentType};
+    use super::{RelocationType, SymbolBinding, SymbolType};
+
+    const PROGRAM_HEADER_OFFSET: usize = 0x40;
+    const TEXT_OFFSET: usize = 0x100;
+    const STRING_TABLE_OFFSET: usize = 0x110;
+    const SYMBOL_TABLE_OFFSET: usize = 0x140;
+    const SYMBOL_STRING_TABLE_OFFSET: usize = 0x188;
+    const RELOCATION_OFFSET: usize = 0x198;
+    const SECTION_HEADER_OFFSET: usize = 0x1c8;
+    const SECTIONS: usize = 6;
+    const STRING_TABLE: &'static [u8] = b"\0.text\0.shstrtab\0.symtab\0.strtab\0.rela.dyn\0";
+    const SYMBOL_STRING_TABLE: &'static [u8] = b"\0_start\0data\0";
+
+    fn put(buffer: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+        if buffer.len() < offset + bytes.len() {
+            buffer.resize(offset + bytes.len(), 0);
+        }
+        buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn put_u16(buffer: &mut Vec<u8>, offset: usize, value: u16) {
+        put(buffer, offset, &[value as u8, (value >> 8) as u8]);
+    }
+
+    fn put_u32(buffer: &mut Vec<u8>, offset: usize, value: u32) {
+        put_u16(buffer, offset, value as u16);
+        put_u16(buffer, offset + 2, (value >> 16) as u16);
+    }
+
+    fn put_u64(buffer: &mut Vec<u8>, offset: usize, value: u64) {
+        put_u32(buffer, offset, value as u32);
+        put_u32(buffer, offset + 4, (value >> 32) as u32);
+    }
+
+    fn put_section(buffer: &mut Vec<u8>, index: usize, name: u32, section_type: u32, offset: usize, size: usize) {
+        let header = SECTION_HEADER_OFFSET + index * 64;
+        put_u32(buffer, header, name);
+        put_u32(buffer, header + 4, section_type);
+        put_u64(buffer, header + 24, offset as u64);
+        put_u64(buffer, header + 32, size as u64);
+    }
+
+    fn put_symbol(buffer: &mut Vec<u8>, index: usize, name: u32, info: u8, section_index: u16, value: u64, size: u64) {
+        let symbol = SYMBOL_TABLE_OFFSET + index * 24;
+        put_u32(buffer, symbol, name);
+        put(buffer, symbol + 4, &[info, 0]);
+        put_u16(buffer, symbol + 6, section_index);
+        put_u64(buffer, symbol + 8, value);
+        put_u64(buffer, symbol + 16, size);
+    }
+
+    fn put_relocation(buffer: &mut Vec<u8>, index: usize, offset: u64, relocation_type: u32, symbol: u32, addend: u64) {
+        let relocation = RELOCATION_OFFSET + index * 24;
+        put_u64(buffer, relocation, offset);
+        put_u32(buffer, relocation + 8, relocation_type);
+        put_u32(buffer, relocation + 12, symbol);
+        put_u64(buffer, relocation + 16, addend);
+    }
+
+    /// An executable with one loadable segment holding `.text`, a
+    /// section name string table, a symbol table naming the two
+    /// halves of `.text`, and two relocations into `.text`. The
+    /// section headers end the file.
+    fn minimal_file() -> Vec<u8> {
+        let mut buffer = Vec::new();
+        put(&mut buffer, 0, b"\x7fELF\x02\x01\x01");
+        put_u16(&mut buffer, 16, 2);
+        put_u16(&mut buffer, 18, 0x3e);
+        put_u32(&mut buffer, 20, 1);
+        put_u64(&mut buffer, 24, 0x80_0000_0000);
+        put_u64(&mut buffer, 32, PROGRAM_HEADER_OFFSET as u64);
+        put_u64(&mut buffer, 40, SECTION_HEADER_OFFSET as u64);
+        put_u16(&mut buffer, 52, 64);
+        put_u16(&mut buffer, 54, 56);
+        put_u16(&mut buffer, 56, 1);
+        put_u16(&mut buffer, 58, 64);
+        put_u16(&mut buffer, 60, SECTIONS as u16);
+        put_u16(&mut buffer, 62, 2);
+
+        put_u32(&mut buffer, PROGRAM_HEADER_OFFSET, 1);
+        put_u32(&mut buffer, PROGRAM_HEADER_OFFSET + 4, 0x5);
+        put_u64(&mut buffer, PROGRAM_HEADER_OFFSET + 8, TEXT_OFFSET as u64);
+        put_u64(&mut buffer, PROGRAM_HEADER_OFFSET + 16, 0x80_0000_0000);
+        put_u64(&mut buffer, PROGRAM_HEADER_OFFSET + 32, 0x10);
+        put_u64(&mut buffer, PROGRAM_HEADER_OFFSET + 40, 0x20);
+
+        put(&mut buffer, TEXT_OFFSET, &[0x90; 0x10]);
+        put(&mut buffer, STRING_TABLE_OFFSET, STRING_TABLE);
+
+        put_section(&mut buffer, 0, 0, 0, 0, 0);
+        put_section(&mut buffer, 1, 1, 1, TEXT_OFFSET, 0x10);
+        put_section(&mut buffer, 2, 7, 3, STRING_TABLE_OFFSET, STRING_TABLE.len());
+
+        put_symbol(&mut buffer, 0, 0, 0, 0, 0, 0);
+        put_symbol(&mut buffer, 1, 1, 0x12, 1, 0x80_0000_0000, 0x8);
+        put_symbol(&mut buffer, 2, 8, 0x01, 1, 0x80_0000_0008, 0x8);
+        put_section(&mut buffer, 3, 17, 2, SYMBOL_TABLE_OFFSET, 3 * 24);
+        put_u32(&mut buffer, SECTION_HEADER_OFFSET + 3 * 64 + 40, 4);
+        put_u64(&mut buffer, SECTION_HEADER_OFFSET + 3 * 64 + 56, 24);
+        put(&mut buffer, SYMBOL_STRING_TABLE_OFFSET, SYMBOL_STRING_TABLE);
+        put_section(&mut buffer, 4, 25, 3, SYMBOL_STRING_TABLE_OFFSET, SYMBOL_STRING_TABLE.len());
+
+        put_relocation(&mut buffer, 0, 0x80_0000_0000, 8, 0, 0x80_0000_0008);
+        put_relocation(&mut buffer, 1, 0x80_0000_0008, 1, 1, 4);
+        put_section(&mut buffer, 5, 33, 4, RELOCATION_OFFSET, 2 * 24);
+        put_u32(&mut buffer, SECTION_HEADER_OFFSET + 5 * 64 + 40, 3);
+        put_u64(&mut buffer, SECTION_HEADER_OFFSET + 5 * 64 + 56, 24);
+        buffer.resize(SECTION_HEADER_OFFSET + SECTIONS * 64, 0);
+        buffer
+    }
+
+    /// Touches everything the file exposes, which must not panic once
+    /// the file has parsed
+    fn walk(file: &File) {
+        for program_header in file.program_headers() {
+            program_header.file_buffer(file);
+        }
+        for section_header in file.section_headers() {
+            section_header.offset_buffer(file);
+            file.section_name(&section_header);
+        }
+        for symbol in file.symbols() {
+            file.symbolize(symbol.value);
+        }
+        for _ in file.all_relocations() {
+        }
+    }
+
+    #[test]
+    fn parses_minimal_file() {
+        let buffer = minimal_file();
+        let file = File::from_buffer(&buffer).unwrap();
+        assert_eq!(file.file_header().elf_type, ElfType::Executable);
+        assert_eq!(file.file_header().entry, 0x80_0000_0000);
+
+        let program_headers: Vec<_> = file.program_headers().collect();
+        assert_eq!(program_headers.len(), 1);
+        assert_eq!(program_headers[0].segment_type(), SegmentType::Load);
+        assert!(program_headers[0].is_executable() && !program_headers[0].is_writable());
+        assert_eq!(program_headers[0].file_buffer(&file), &[0x90; 0x10]);
+        assert_eq!(program_headers[0].memory_size, 0x20);
+
+        let section_headers: Vec<_> = file.section_headers().collect();
+        assert_eq!(section_headers.len(), SECTIONS - 1);
+        assert_eq!(section_headers[0].section_type, SectionType::ProgramBits);
+        assert_eq!(section_headers[1].offset_buffer(&file), STRING_TABLE);
+    }
+
+    #[test]
+    fn rejects_bad_identification() {
+        let cases: &[(usize, u8, ElfError)] = &[
+            (0, 0x7e, ElfError::BadMagic),
+            (4, 1, ElfError::UnsupportedClass(1)),
+            (5, 2, ElfError::UnsupportedEndianness(2)),
+            (6, 0, ElfError::UnsupportedVersion(0)),
+            (18, 0x03, ElfError::UnsupportedMachine(InstructionSetArchitecture::X86)),
+            (20, 2, ElfError::UnsupportedVersion(2)),
+        ];
+        for &(offset, value, error) in cases {
+            let mut buffer = minimal_file();
+            buffer[offset] = value;
+            assert_eq!(File::from_buffer(&buffer).err(), Some(error));
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_headers() {
+        let mut buffer = minimal_file();
+        put_u64(&mut buffer, 32, !0);
+        assert_eq!(File::from_buffer(&buffer).err(), Some(ElfError::ProgramHeadersOutOfBounds));
+
+        let mut buffer = minimal_file();
+        put_u16(&mut buffer, 58, 32);
+        assert_eq!(File::from_buffer(&buffer).err(), Some(ElfError::BadEntrySize));
+
+        let mut buffer = minimal_file();
+        put_section(&mut buffer, 1, 1, 1, !0 - 4, 0x10);
+        assert_eq!(File::from_buffer(&buffer).err(), Some(ElfError::SectionOutOfBounds(1)));
+
+        // Sections without file bytes may say anything about them
+        let mut buffer = minimal_file();
+        put_section(&mut buffer, 1, 1, 8, !0, !0);
+        assert!(File::from_buffer(&buffer).is_ok());
+
+        let mut buffer = minimal_file();
+        put_u64(&mut buffer, PROGRAM_HEADER_OFFSET + 32, 0x40);
+        assert_eq!(File::from_buffer(&buffer).err(), Some(ElfError::SegmentOutOfBounds(0)));
+
+        let mut buffer = minimal_file();
+        put_u16(&mut buffer, 62, SECTIONS as u16);
+        assert_eq!(File::from_buffer(&buffer).err(), Some(ElfError::BadStringTableIndex));
+    }
+
+    #[test]
+    fn rejects_bad_symbol_tables() {
+        let mut buffer = minimal_file();
+        put_u64(&mut buffer, SECTION_HEADER_OFFSET + 3 * 64 + 56, 8);
+        assert_eq!(File::from_buffer(&buffer).err(), Some(ElfError::BadSymbolTable(3)));
+
+        let mut buffer = minimal_file();
+        put_u32(&mut buffer, SECTION_HEADER_OFFSET + 3 * 64 + 40, SECTIONS as u32);
+        assert_eq!(File::from_buffer(&buffer).err(), Some(ElfError::BadSymbolTable(3)));
+    }
+
+    #[test]
+    fn section_names() {
+        let buffer = minimal_file();
+        let file = File::from_buffer(&buffer).unwrap();
+        let names: Vec<_> = file.section_headers().map(|section_header| file.section_name(&section_header)).collect();
+        assert_eq!(names, [Some(".text"), Some(".shstrtab"), Some(".symtab"), Some(".strtab"), Some(".rela.dyn")]);
+        assert_eq!(file.section_by_name(".strtab").unwrap().file_offset, SYMBOL_STRING_TABLE_OFFSET);
+        assert!(file.section_by_name(".data").is_none());
+        // Offsets past the table, or into a section that isn't a string table
+        assert_eq!(file.string(2, STRING_TABLE.len()), None);
+        assert_eq!(file.string(1, 0), None);
+    }
+
+    #[test]
+    fn symbols() {
+        let buffer = minimal_file();
+        let file = File::from_buffer(&buffer).unwrap();
+        let symbols: Vec<_> = file.symbols().collect();
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols[0].name, "_start");
+        assert_eq!(symbols[0].symbol_type, SymbolType::Function);
+        assert_eq!(symbols[0].binding, SymbolBinding::Global);
+        assert_eq!(symbols[1].symbol_type, SymbolType::Object);
+        assert_eq!(symbols[1].binding, SymbolBinding::Local);
+
+        assert_eq!(file.find_symbol_by_name("data").unwrap().value, 0x80_0000_0008);
+        assert!(file.find_symbol_by_name("main").is_none());
+
+        let (symbol, offset) = file.symbolize(0x80_0000_0004).unwrap();
+        assert_eq!((symbol.name, offset), ("_start", 4));
+        assert_eq!(file.symbolize(0x80_0000_000f).unwrap().0.name, "data");
+        assert!(file.symbolize(0x80_0000_0010).is_none());
+    }
+
+    #[test]
+    fn symbol_data() {
+        let mut buffer = minimal_file();
+        put(&mut buffer, TEXT_OFFSET + 8, b"\x01\x02\x03\x04\x05\x06\x07\x08");
+        put_u64(&mut buffer, SECTION_HEADER_OFFSET + 64 + 16, 0x80_0000_0000);
+        let file = File::from_buffer(&buffer).unwrap();
+        let data = file.find_symbol_by_name("data").unwrap();
+        assert_eq!(file.symbol_data(&data), Some(&b"\x01\x02\x03\x04\x05\x06\x07\x08"[..]));
+
+        // Past the end of its section
+        put_symbol(&mut buffer, 2, 8, 0x01, 1, 0x80_0000_000c, 0x8);
+        let file = File::from_buffer(&buffer).unwrap();
+        let data = file.find_symbol_by_name("data").unwrap();
+        assert_eq!(file.symbol_data(&data), None);
+    }
+
+    #[test]
+    fn relocations() {
+        let buffer = minimal_file();
+        let file = File::from_buffer(&buffer).unwrap();
+        let relocations: Vec<_> = file.all_relocations().collect();
+        assert_eq!(relocations.len(), 2);
+        assert_eq!(relocations[0].relocation_type, RelocationType::Relative);
+        assert_eq!(relocations[0].symbol, None);
+        assert_eq!(relocations[0].addend, 0x80_0000_0008);
+        assert_eq!(relocations[1].relocation_type, RelocationType::Direct64);
+        assert_eq!(relocations[1].symbol.unwrap().name, "_start");
+        assert_eq!(relocations[1].offset, 0x80_0000_0008);
+
+        // Relocations may only refer to symbol tables
+        let mut buffer = minimal_file();
+        put_u32(&mut buffer, SECTION_HEADER_OFFSET + 5 * 64 + 40, 4);
+        assert_eq!(File::from_buffer(&buffer).err(), Some(ElfError::BadRelocationTable(5)));
+    }
+
+    #[test]
+    fn keeps_unknown_types() {
+        let mut buffer = minimal_file();
+        put_u16(&mut buffer, 16, 0xfe01);
+        put_u32(&mut buffer, PROGRAM_HEADER_OFFSET, 0x6474_e551);
+        put_section(&mut buffer, 1, 1, 0x6fff_fff6, TEXT_OFFSET, 0x10);
+        let file = File::from_buffer(&buffer).unwrap();
+        assert_eq!(file.file_header().elf_type, ElfType::Other(0xfe01));
+        assert_eq!(file.program_headers().next().unwrap().segment_type(), SegmentType::Other(0x6474_e551));
+        assert_eq!(file.section_headers().next().unwrap().section_type, SectionType::Other(0x6fff_fff6));
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let buffer = minimal_file();
+        for length in 0..buffer.len() {
+            assert!(File::from_buffer(&buffer[..length]).is_err(), "accepted {} bytes", length);
+        }
+    }
+
+    #[test]
+    fn corrupt_files_do_not_panic() {
+        let original = minimal_file();
+        for offset in 0..original.len() {
+            for &value in &[0x00, 0x01, 0x7f, 0x80, 0xff] {
+                let mut buffer = original.clone();
+                buffer[offset] = value;
+                if let Ok(file) = File::from_buffer(&buffer) {
+                    walk(&file);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,46 @@
+/// Smallest order whose blocks hold at least `num_frames` frames. A
+/// block of order `n` is 2^n frames long and starts at a multiple of
+/// 2^n.
+pub fn order_for_frames(num_frames: usize) -> usize {
+    let mut order = 0;
+    while (1 << order) < num_frames {
+        order += 1;
+    }
+    order
+}
+
+/// First frame of the lowest run of `num_frames` frames within the
+/// half open range [start, end) that is aligned like a buddy block
+/// holding it, to `1 << order_for_frames(num_frames)` frames
+pub fn aligned_run(start: usize, end: usize, num_frames: usize) -> Option<usize> {
+    let alignment = 1 << order_for_frames(num_frames);
+    let run = (start + alignment - 1) & !(alignment - 1);
+    if run + num_frames <= end {
+        Some(run)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{order_for_frames, aligned_run};
+
+    #[test]
+    fn order_for_frames_rounds_up() {
+        assert_eq!(order_for_frames(1), 0);
+        assert_eq!(order_for_frames(2), 1);
+        assert_eq!(order_for_frames(3), 2);
+        assert_eq!(order_for_frames(512), 9);
+        assert_eq!(order_for_frames(513), 10);
+    }
+
+    #[test]
+    fn aligned_runs() {
+        assert_eq!(aligned_run(5, 8, 1), Some(5));
+        assert_eq!(aligned_run(5, 8, 2), Some(6));
+        assert_eq!(aligned_run(5, 8, 3), None);
+        assert_eq!(aligned_run(0x103, 0x200, 0x30), Some(0x140));
+        assert_eq!(aligned_run(0x103, 0x16f, 0x30), None);
+    }
+}
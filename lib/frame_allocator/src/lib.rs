@@ -1,30 +1,523 @@
 #![no_std]
 
 extern crate mem;
+extern crate gnu_efi;
 
 use ::mem::{Frame, PhysicalAddress};
+use ::gnu_efi::def::{MemoryDescriptors, MemoryType};
+
+/// Power-of-two alignment of contiguous runs, as a buddy allocator
+/// would place them
+pub mod buddy;
 
 /* Frame 1 is unused, frame 2 is for the AP Trampoline, and frame 3
  * is the AP Trampoline stack
  */
+const FIRST_USABLE_FRAME: usize = 4;
+
+/// Maximum number of disjoint free regions the allocator can track
+const MAX_REGIONS: usize = 128;
+
+const EMPTY_REGION: FrameRegion = FrameRegion {
+    start: 0,
+    end: 0,
+};
+
 pub static mut FRAME_ALLOCATOR: FrameAllocator = FrameAllocator {
-    next_frame: 4,
+    regions: [EMPTY_REGION; MAX_REGIONS],
+    number_of_regions: 0,
+    total_frames: 0,
+    reserved_frames: 0,
+    allocated_frames: [0; NUMBER_OF_USAGES],
 };
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// No free region is large enough for the request
+    OutOfMemory,
+    /// The frames being freed overlap frames that are already free
+    AlreadyFree,
+    /// The allocator has no room left to track another free region
+    TooManyRegions,
+    /// More frames are being freed than are allocated for that usage
+    UsageMismatch,
+}
+
+/// What an allocated frame is used for, so the allocator can report
+/// where memory is going
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FrameUsage {
+    PageTable,
+    KernelImage,
+    Heap,
+    Stack,
+    Dma,
+    /// Memory mapped into a user address space
+    User,
+    Other,
+}
+
+const NUMBER_OF_USAGES: usize = 7;
+
+pub const FRAME_USAGES: [FrameUsage; NUMBER_OF_USAGES] = [
+    FrameUsage::PageTable,
+    FrameUsage::KernelImage,
+    FrameUsage::Heap,
+    FrameUsage::Stack,
+    FrameUsage::Dma,
+    FrameUsage::User,
+    FrameUsage::Other,
+];
+
+/// Snapshot of the allocator's view of physical memory, in frames.
+/// `total` is always `free + reserved` plus everything allocated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FrameStatistics {
+    pub total: usize,
+    pub free: usize,
+    pub reserved: usize,
+    allocated: [usize; NUMBER_OF_USAGES],
+}
+
+impl FrameStatistics {
+    /// Frames currently allocated for the given usage
+    pub fn allocated(&self, usage: FrameUsage) -> usize {
+        self.allocated[usage as usize]
+    }
+
+    /// Frames currently allocated for any usage
+    pub fn total_allocated(&self) -> usize {
+        self.allocated.iter().fold(0, |total, count| total + count)
+    }
+}
+
+/// Half open range of free frame numbers, [start, end)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct FrameRegion {
+    start: usize,
+    end: usize,
+}
+
+impl FrameRegion {
+    fn len(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+/// Physical frame allocator built from the UEFI memory map.
+///
+/// Free memory is kept as a sorted list of disjoint, non-adjacent
+/// regions, so the allocator never has to touch the frames it hands
+/// out. Allocations are served lowest address first, and runs of
+/// frames are aligned like buddy blocks, see `buddy::aligned_run`.
 pub struct FrameAllocator {
-    next_frame: usize,
+    regions: [FrameRegion; MAX_REGIONS],
+    number_of_regions: usize,
+    total_frames: usize,
+    reserved_frames: usize,
+    allocated_frames: [usize; NUMBER_OF_USAGES],
+}
+
+/// Whether the memory in a region of this type can be handed out
+/// once boot services have been exited
+fn is_usable(region_type: MemoryType) -> bool {
+    match region_type {
+        MemoryType::ConventionalMemory => true,
+        MemoryType::LoaderData => true,
+        _ => false,
+    }
+}
+
+/// Whether the memory in a region of this type is free once nothing
+/// runs on the firmware's page tables, stack or descriptor tables
+fn is_reclaimable(region_type: MemoryType) -> bool {
+    match region_type {
+        MemoryType::BootServicesCode => true,
+        MemoryType::BootServicesData => true,
+        _ => false,
+    }
+}
+
+/// Calls `f` with the half open frame range [start, end) of every
+/// usable region in the memory map
+fn for_each_usable_region<F>(memory_map: &MemoryDescriptors, f: F) -> Result<(), Error>
+        where F: FnMut(usize, usize) -> Result<(), Error> {
+    for_each_region(memory_map, is_usable, f)
+}
+
+/// Calls `f` with the half open frame range [start, end) of every
+/// region in the memory map whose type `wanted` accepts
+fn for_each_region<F>(memory_map: &MemoryDescriptors, wanted: fn(MemoryType) -> bool, mut f: F) -> Result<(), Error>
+        where F: FnMut(usize, usize) -> Result<(), Error> {
+    for memory_descriptor in memory_map {
+        if !wanted(memory_descriptor.region_type) {
+            continue;
+        }
+
+        let start_frame: Frame = memory_descriptor.physical_start.into();
+        let start: usize = start_frame.into();
+        let end = start + memory_descriptor.number_of_pages as usize;
+        let start = if start < FIRST_USABLE_FRAME { FIRST_USABLE_FRAME } else { start };
+
+        if start < end {
+            f(start, end)?;
+        }
+    }
+
+    Ok(())
 }
 
 impl FrameAllocator {
-    pub fn get_frame(&mut self) -> Frame {
-        let result = Frame::new(self.next_frame);
-        self.next_frame += 1;
+    /// Builds the free set from every usable region in the memory map.
+    /// Frames below `FIRST_USABLE_FRAME` are never handed out. Boot
+    /// services memory is left out until `reclaim_boot_services`, as the
+    /// loader still runs on the firmware's page tables and stack.
+    pub fn new(memory_map: &MemoryDescriptors) -> Result<FrameAllocator, Error> {
+        let mut result = FrameAllocator {
+            regions: [EMPTY_REGION; MAX_REGIONS],
+            number_of_regions: 0,
+            total_frames: 0,
+            reserved_frames: 0,
+            allocated_frames: [0; NUMBER_OF_USAGES],
+        };
+
+        for_each_usable_region(memory_map, |start, end| {
+            result.insert_region(FrameRegion { start: start, end: end })
+        })?;
+        result.total_frames = result.free_frame_count();
+
+        Ok(result)
+    }
+
+    /// Adds the memory map's boot services regions to the free set.
+    /// Only call this once, after loading our own page tables, stack,
+    /// GDT and IDT.
+    pub fn reclaim_boot_services(&mut self, memory_map: &MemoryDescriptors) -> Result<(), Error> {
+        let free_before = self.free_frame_count();
+        let result = for_each_region(memory_map, is_reclaimable, |start, end| {
+            self.insert_region(FrameRegion { start: start, end: end })
+        });
+        self.total_frames += self.free_frame_count() - free_before;
         result
     }
 
-    pub fn get_multiple_frames(&mut self, num_frames: usize) -> Frame {
-        let result = Frame::new(self.next_frame);
-        self.next_frame += num_frames;
+    pub fn get_frame(&mut self, usage: FrameUsage) -> Result<Frame, Error> {
+        self.get_multiple_frames(1, usage)
+    }
+
+    /// Allocates `num_frames` physically contiguous frames, returning
+    /// the first one. The run starts at a multiple of the smallest
+    /// power of two that is at least `num_frames`, see
+    /// `buddy::aligned_run`.
+    pub fn get_multiple_frames(&mut self, num_frames: usize, usage: FrameUsage) -> Result<Frame, Error> {
+        let start = self.regions[..self.number_of_regions].iter()
+            .filter_map(|region| buddy::aligned_run(region.start, region.end, num_frames))
+            .next()
+            .ok_or(Error::OutOfMemory)?;
+
+        self.remove_frames(Frame::new(start), num_frames)?;
+        self.allocated_frames[usage as usize] += num_frames;
+        Ok(Frame::new(start))
+    }
+
+    pub fn free_frame(&mut self, frame: Frame, usage: FrameUsage) -> Result<(), Error> {
+        self.free_frames(frame, 1, usage)
+    }
+
+    /// Returns `num_frames` contiguous frames starting at `frame` to
+    /// the free set. `usage` must match the one they were allocated
+    /// with, and nothing changes if fewer frames than that are
+    /// allocated for it.
+    pub fn free_frames(&mut self, frame: Frame, num_frames: usize, usage: FrameUsage) -> Result<(), Error> {
+        let start: usize = frame.into();
+        if num_frames == 0 {
+            return Ok(());
+        }
+        let allocated = self.allocated_frames[usage as usize].checked_sub(num_frames)
+            .ok_or(Error::UsageMismatch)?;
+        self.insert_region(FrameRegion { start: start, end: start + num_frames })?;
+        self.allocated_frames[usage as usize] = allocated;
+        Ok(())
+    }
+
+    /// Removes `num_frames` frames starting at `frame` from the free
+    /// set. Frames that are not currently free are skipped, so this
+    /// can be used to protect memory still in use by the caller.
+    pub fn reserve_frames(&mut self, frame: Frame, num_frames: usize) -> Result<(), Error> {
+        let free_before = self.free_frame_count();
+        let result = self.remove_frames(frame, num_frames);
+        self.reserved_frames += free_before - self.free_frame_count();
         result
     }
+
+    fn remove_frames(&mut self, frame: Frame, num_frames: usize) -> Result<(), Error> {
+        let start: usize = frame.into();
+        let end = start + num_frames;
+
+        let mut index = 0;
+        while index < self.number_of_regions {
+            let region = self.regions[index];
+            if region.end <= start || region.start >= end {
+                index += 1;
+                continue;
+            }
+
+            if region.start < start && region.end > end {
+                // Split the region in two around the reserved frames,
+                // leaving it whole if there's no room for the second
+                self.insert_at(index + 1, FrameRegion { start: end, end: region.end })?;
+                self.regions[index].end = start;
+                return Ok(());
+            } else if region.start < start {
+                self.regions[index].end = start;
+                index += 1;
+            } else if region.end > end {
+                self.regions[index].start = end;
+                index += 1;
+            } else {
+                self.remove_region(index);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reserves the frames backing `size` bytes at `address`
+    pub fn reserve_range(&mut self, address: PhysicalAddress, size: usize) -> Result<(), Error> {
+        let start: usize = address.into();
+        let first_frame = start / 0x1000;
+        let end_frame = (start + size + 0x1000 - 1) / 0x1000;
+        self.reserve_frames(Frame::new(first_frame), end_frame - first_frame)
+    }
+
+    /// Number of frames currently available
+    pub fn free_frame_count(&self) -> usize {
+        self.regions[..self.number_of_regions].iter()
+            .fold(0, |total, region| total + region.len())
+    }
+
+    pub fn statistics(&self) -> FrameStatistics {
+        FrameStatistics {
+            total: self.total_frames,
+            free: self.free_frame_count(),
+            reserved: self.reserved_frames,
+            allocated: self.allocated_frames,
+        }
+    }
+
+    /// Adds a region to the free set, merging it with its neighbours
+    fn insert_region(&mut self, region: FrameRegion) -> Result<(), Error> {
+        let index = self.regions[..self.number_of_regions].iter()
+            .position(|existing| existing.start >= region.start)
+            .unwrap_or(self.number_of_regions);
+
+        let merge_previous = if index > 0 {
+            let previous = self.regions[index - 1];
+            if previous.end > region.start {
+                return Err(Error::AlreadyFree);
+            }
+            previous.end == region.start
+        } else {
+            false
+        };
+
+        let merge_next = if index < self.number_of_regions {
+            let next = self.regions[index];
+            if region.end > next.start {
+                return Err(Error::AlreadyFree);
+            }
+            region.end == next.start
+        } else {
+            false
+        };
+
+        match (merge_previous, merge_next) {
+            (true, true) => {
+                self.regions[index - 1].end = self.regions[index].end;
+                self.remove_region(index);
+            },
+            (true, false) => {
+                self.regions[index - 1].end = region.end;
+            },
+            (false, true) => {
+                self.regions[index].start = region.start;
+            },
+            (false, false) => {
+                self.insert_at(index, region)?;
+            },
+        }
+
+        Ok(())
+    }
+
+    fn insert_at(&mut self, index: usize, region: FrameRegion) -> Result<(), Error> {
+        if self.number_of_regions == MAX_REGIONS {
+            return Err(Error::TooManyRegions);
+        }
+
+        let mut current = self.number_of_regions;
+        while current > index {
+            self.regions[current] = self.regions[current - 1];
+            current -= 1;
+        }
+        self.regions[index] = region;
+        self.number_of_regions += 1;
+        Ok(())
+    }
+
+    fn remove_region(&mut self, index: usize) {
+        for current in index..self.number_of_regions - 1 {
+            self.regions[current] = self.regions[current + 1];
+        }
+        self.number_of_regions -= 1;
+        self.regions[self.number_of_regions] = EMPTY_REGION;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameAllocator, FrameUsage, Error, MAX_REGIONS};
+    use ::mem::{Frame, PhysicalAddress, VirtualAddress};
+    use ::gnu_efi::def::{MemoryDescriptor, MemoryDescriptors, MemoryType};
+
+    fn descriptor(region_type: MemoryType, start_frame: usize, pages: u64) -> MemoryDescriptor {
+        MemoryDescriptor {
+            region_type: region_type,
+            physical_start: PhysicalAddress::new(start_frame * 0x1000),
+            virtual_start: VirtualAddress::new(0),
+            number_of_pages: pages,
+            attribute: 0,
+        }
+    }
+
+    fn allocator(descriptors: &[MemoryDescriptor]) -> FrameAllocator {
+        let memory_map = MemoryDescriptors::new(
+            descriptors.as_ptr(),
+            descriptors.len(),
+            ::core::mem::size_of::<MemoryDescriptor>());
+        FrameAllocator::new(&memory_map).unwrap()
+    }
+
+    #[test]
+    fn skips_unusable_and_low_frames() {
+        let mut allocator = allocator(&[
+            descriptor(MemoryType::ConventionalMemory, 0, 8),
+            descriptor(MemoryType::RuntimeServicesData, 8, 8),
+            descriptor(MemoryType::BootServicesData, 16, 2),
+        ]);
+
+        assert_eq!(allocator.free_frame_count(), 4);
+        assert_eq!(allocator.get_frame(FrameUsage::Other), Ok(Frame::new(4)));
+        assert_eq!(allocator.get_multiple_frames(3, FrameUsage::Other), Err(Error::OutOfMemory));
+        assert_eq!(allocator.get_multiple_frames(2, FrameUsage::Other), Ok(Frame::new(6)));
+        assert_eq!(allocator.get_frame(FrameUsage::Other), Ok(Frame::new(5)));
+        assert_eq!(allocator.get_frame(FrameUsage::Other), Err(Error::OutOfMemory));
+    }
+
+    #[test]
+    fn runs_are_aligned() {
+        let mut allocator = allocator(&[
+            descriptor(MemoryType::ConventionalMemory, 0x103, 0x200),
+        ]);
+
+        assert_eq!(allocator.get_multiple_frames(0x30, FrameUsage::Stack), Ok(Frame::new(0x140)));
+        assert_eq!(allocator.get_multiple_frames(0x10, FrameUsage::Stack), Ok(Frame::new(0x110)));
+        assert_eq!(allocator.get_frame(FrameUsage::Other), Ok(Frame::new(0x103)));
+        assert_eq!(allocator.free_frame_count(), 0x200 - 0x41);
+    }
+
+    #[test]
+    fn reclaims_boot_services_later() {
+        let descriptors = [
+            descriptor(MemoryType::ConventionalMemory, 0x10, 4),
+            descriptor(MemoryType::BootServicesCode, 0x14, 2),
+            descriptor(MemoryType::RuntimeServicesCode, 0x16, 2),
+            descriptor(MemoryType::BootServicesData, 0x20, 4),
+        ];
+        let mut allocator = allocator(&descriptors);
+        assert_eq!(allocator.statistics().total, 4);
+
+        let memory_map = MemoryDescriptors::new(
+            descriptors.as_ptr(),
+            descriptors.len(),
+            ::core::mem::size_of::<MemoryDescriptor>());
+        allocator.reclaim_boot_services(&memory_map).unwrap();
+        assert_eq!(allocator.statistics().total, 10);
+        assert_eq!(allocator.get_multiple_frames(6, FrameUsage::Other), Ok(Frame::new(0x10)));
+        assert_eq!(allocator.get_multiple_frames(4, FrameUsage::Other), Ok(Frame::new(0x20)));
+    }
+
+    #[test]
+    fn free_coalesces_regions() {
+        let mut allocator = allocator(&[
+            descriptor(MemoryType::ConventionalMemory, 0x100, 0x10),
+        ]);
+
+        let first = allocator.get_multiple_frames(4, FrameUsage::Other).unwrap();
+        let second = allocator.get_multiple_frames(4, FrameUsage::Other).unwrap();
+        assert_eq!(allocator.free_frame_count(), 8);
+
+        allocator.free_frames(first, 4, FrameUsage::Other).unwrap();
+        assert_eq!(allocator.free_frames(first, 1, FrameUsage::Other), Err(Error::AlreadyFree));
+        allocator.free_frames(second, 4, FrameUsage::Other).unwrap();
+
+        assert_eq!(allocator.free_frame_count(), 0x10);
+        assert_eq!(allocator.get_multiple_frames(0x10, FrameUsage::Other), Ok(Frame::new(0x100)));
+    }
+
+    #[test]
+    fn reserve_splits_regions() {
+        let mut allocator = allocator(&[
+            descriptor(MemoryType::LoaderData, 0x10, 0x10),
+        ]);
+
+        allocator.reserve_frames(Frame::new(0x14), 4).unwrap();
+        assert_eq!(allocator.free_frame_count(), 0xC);
+        assert_eq!(allocator.get_multiple_frames(5, FrameUsage::Other), Ok(Frame::new(0x18)));
+        assert_eq!(allocator.get_multiple_frames(4, FrameUsage::Other), Ok(Frame::new(0x10)));
+    }
+
+    #[test]
+    fn failed_split_keeps_region() {
+        let mut allocator = allocator(&[
+            descriptor(MemoryType::LoaderData, 0x100, 0x400),
+        ]);
+        for region in 1..MAX_REGIONS {
+            allocator.reserve_frames(Frame::new(0x100 + region * 2 - 1), 1).unwrap();
+        }
+        let free = allocator.free_frame_count();
+
+        assert_eq!(allocator.reserve_frames(Frame::new(0x400), 1), Err(Error::TooManyRegions));
+        assert_eq!(allocator.free_frame_count(), free);
+    }
+
+    #[test]
+    fn statistics_track_usage() {
+        let mut allocator = allocator(&[
+            descriptor(MemoryType::ConventionalMemory, 0x100, 0x20),
+        ]);
+
+        allocator.reserve_frames(Frame::new(0xF0), 0x14).unwrap();
+        let table = allocator.get_frame(FrameUsage::PageTable).unwrap();
+        let stack = allocator.get_multiple_frames(4, FrameUsage::Stack).unwrap();
+        allocator.get_multiple_frames(2, FrameUsage::Dma).unwrap();
+
+        let statistics = allocator.statistics();
+        assert_eq!(statistics.total, 0x20);
+        assert_eq!(statistics.reserved, 4);
+        assert_eq!(statistics.allocated(FrameUsage::PageTable), 1);
+        assert_eq!(statistics.allocated(FrameUsage::Stack), 4);
+        assert_eq!(statistics.allocated(FrameUsage::Dma), 2);
+        assert_eq!(statistics.total_allocated(), 7);
+        assert_eq!(statistics.free, 0x20 - 4 - 7);
+
+        assert_eq!(allocator.free_frame(table, FrameUsage::Heap), Err(Error::UsageMismatch));
+        assert_eq!(allocator.free_frames(Frame::new(0xF0), 4, FrameUsage::Other), Err(Error::UsageMismatch));
+        assert_eq!(allocator.statistics().free, 0x20 - 4 - 7);
+        allocator.free_frame(table, FrameUsage::PageTable).unwrap();
+        allocator.free_frames(stack, 4, FrameUsage::Stack).unwrap();
+        let statistics = allocator.statistics();
+        assert_eq!(statistics.total_allocated(), 2);
+        assert_eq!(statistics.free, 0x20 - 4 - 2);
+    }
 }
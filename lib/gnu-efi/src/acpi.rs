@@ -2,10 +2,15 @@
 use core::mem;
 use core::slice;
 
-use api;
-
 const RSDP_SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
 
+/// Tables refer to each other by physical address, so they are read
+/// through the direct map
+unsafe fn direct_map<T>(address: u64) -> &'static T {
+    let virtual_address = ::mem::PhysicalAddress::new(address as usize).to_direct_map();
+    &*(virtual_address.as_ptr() as *const T)
+}
+
 unsafe fn verify_checksum(ptr: *const u8, length: usize) -> bool {
     let mut total: u8 = 0;
     for byte in slice::from_raw_parts(ptr, length) {
@@ -22,7 +27,7 @@ pub struct RootSystemDescriptorPointer {
     revision:           u8,
     _rsdt_address:      u32,
     length:             u32,
-    pub xsdt:           &'static ExtendedSystemDescriptorTable,
+    xsdt_address:       u64,
     _extended_checksum: u8,
     _reserved:          [u8; 3],
 }
@@ -54,6 +59,11 @@ impl RootSystemDescriptorPointer {
             self.verify_length() &&
             self.verify_revision()
     }
+    pub fn xsdt(&self) -> &'static ExtendedSystemDescriptorTable {
+        unsafe {
+            direct_map(self.xsdt_address)
+        }
+    }
 }
 
 #[repr(packed)]
@@ -74,7 +84,7 @@ const XSDT_SIGNATURE: &'static [u8; 4] = b"XSDT";
 #[repr(packed)]
 pub struct ExtendedSystemDescriptorTable {
     header: SystemDescriptionTableHeader,
-    entry:  &'static SystemDescriptionTableHeader,
+    entry:  u64,
 }
 
 impl ExtendedSystemDescriptorTable {
@@ -91,7 +101,8 @@ impl ExtendedSystemDescriptorTable {
         }
     }
 
-    pub fn get_tables<'a>(&'a self) -> &'a [&'static SystemDescriptionTableHeader] {
+    /// Physical addresses of every table the XSDT points to
+    pub fn get_table_addresses<'a>(&'a self) -> &'a [u64] {
         unsafe {
             let mut bytes = self.header.length as usize;
             bytes = bytes - mem::size_of::<SystemDescriptionTableHeader>();
@@ -101,7 +112,8 @@ impl ExtendedSystemDescriptorTable {
     }
 
     pub fn find_sdt_by_signature(&self, signature:&[u8; 4]) -> Option<&'static SystemDescriptionTableHeader> {
-        for table in self.get_tables() {
+        for address in self.get_table_addresses() {
+            let table: &'static SystemDescriptionTableHeader = unsafe { direct_map(*address) };
             if table.signature == *signature {
                 return Some(table);
             }
@@ -146,6 +158,13 @@ pub struct InterruptControllerHeader {
 }
 
 impl InterruptControllerHeader {
+    pub fn to_local_apic<'a>(&'a self) -> &'a ProcessorLocalApic {
+        assert!(self.structure_type == ApicStructureType::LocalApic);
+        unsafe {
+            mem::transmute(self)
+        }
+    }
+
     pub fn to_interrupt_source_override<'a>(&'a self) -> &'a InterruptSourceOverride {
         assert!(self.structure_type ==
             ApicStructureType::InterruptSourceOverride);
@@ -193,6 +212,23 @@ impl MultipleApicDescriptionTable {
     }
 }
 
+#[repr(C)]
+pub struct ProcessorLocalApic {
+    structure_type: u8,
+    length: u8,
+    pub processor_id: u8,
+    pub apic_id: u8,
+    flags: u32,
+}
+
+impl ProcessorLocalApic {
+    /// Whether the CPU can be started. Disabled entries are CPUs the
+    /// firmware found unusable.
+    pub fn enabled(&self) -> bool {
+        self.flags & 0x1 == 0x1
+    }
+}
+
 #[repr(C)]
 pub struct InterruptSourceOverride {
     structure_type: u8,
@@ -203,14 +239,8 @@ pub struct InterruptSourceOverride {
     flags: u16,
 }
 
-pub fn get_rsdp(system_table: &api::SystemTable) ->
-        &'static RootSystemDescriptorPointer {
-    let rsdp: &'static RootSystemDescriptorPointer =
-        unsafe {
-            mem::transmute(
-                system_table.get_vendor_table(
-                    &api::types::ACPI_20_TABLE_GUID).expect(
-                        "No ACPI Table found."))
-        };
-    rsdp
+/// The RSDP at physical `address`, e.g. as found before exiting boot
+/// services. Unsafe as nothing checks there is one there.
+pub unsafe fn rsdp_at(address: u64) -> &'static RootSystemDescriptorPointer {
+    direct_map(address)
 }
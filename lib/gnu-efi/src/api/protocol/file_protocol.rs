@@ -2,21 +2,170 @@
 use super::Protocol;
 use ::api::types::FunctionPointer;
 use ::api::types::Guid;
+use ::def::Time;
+
+/// `EFI_FILE_INFO` attribute bits
+pub const FILE_READ_ONLY: u64 = 0x01;
+pub const FILE_HIDDEN: u64 = 0x02;
+pub const FILE_SYSTEM: u64 = 0x04;
+pub const FILE_DIRECTORY: u64 = 0x10;
+pub const FILE_ARCHIVE: u64 = 0x20;
+
+/// Longest file name `File::get_info` returns, in UTF-16 code units
+pub const MAX_FILE_NAME: usize = 256;
+
+/// `EFI_FILE_INFO` up to the file name that follows it
+#[repr(C)]
+#[allow(dead_code)]
+struct FileInfoHeader {
+    size: u64,
+    file_size: u64,
+    physical_size: u64,
+    create_time: Time,
+    last_access_time: Time,
+    modification_time: Time,
+    attribute: u64,
+}
+
+/// Bytes an `EFI_FILE_INFO` buffer has room for: the 80 byte header,
+/// the name and its NUL, rounded up to a whole number of u64s
+const FILE_INFO_BUFFER_SIZE: usize = 80 + 2 * MAX_FILE_NAME + 8;
+
+/// What `File::get_info` says about a file
+pub struct FileInfo {
+    pub file_size: u64,
+    /// Bytes the file takes up on the volume
+    pub physical_size: u64,
+    pub create_time: Time,
+    pub last_access_time: Time,
+    pub modification_time: Time,
+    /// `FILE_*` bits
+    pub attribute: u64,
+    name: [u16; MAX_FILE_NAME],
+    name_length: usize,
+}
+
+impl FileInfo {
+    /// Parses the `EFI_FILE_INFO` at the start of `buffer`
+    fn from_bytes(buffer: &[u8]) -> FileInfo {
+        let header_size = ::core::mem::size_of::<FileInfoHeader>();
+        assert!(buffer.len() >= header_size);
+        let header: FileInfoHeader = unsafe {
+            ::core::ptr::read_unaligned(buffer.as_ptr() as *const FileInfoHeader)
+        };
+
+        let mut name = [0; MAX_FILE_NAME];
+        let mut name_length = 0;
+        for unit in buffer[header_size..].chunks(2).take(MAX_FILE_NAME) {
+            if unit.len() < 2 || (unit[0] == 0 && unit[1] == 0) {
+                break;
+            }
+            name[name_length] = unit[0] as u16 | (unit[1] as u16) << 8;
+            name_length += 1;
+        }
+
+        FileInfo {
+            file_size: header.file_size,
+            physical_size: header.physical_size,
+            create_time: header.create_time,
+            last_access_time: header.last_access_time,
+            modification_time: header.modification_time,
+            attribute: header.attribute,
+            name: name,
+            name_length: name_length,
+        }
+    }
+
+    /// Writes `self` as an `EFI_FILE_INFO` to the start of `buffer`,
+    /// giving its size
+    fn to_bytes(&self, buffer: &mut [u8]) -> usize {
+        let header_size = ::core::mem::size_of::<FileInfoHeader>();
+        let size = header_size + 2 * (self.name_length + 1);
+        assert!(buffer.len() >= size);
+        let header = FileInfoHeader {
+            size: size as u64,
+            file_size: self.file_size,
+            physical_size: self.physical_size,
+            create_time: self.create_time,
+            last_access_time: self.last_access_time,
+            modification_time: self.modification_time,
+            attribute: self.attribute,
+        };
+        unsafe {
+            ::core::ptr::write_unaligned(buffer.as_mut_ptr() as *mut FileInfoHeader, header);
+        }
+
+        let nul = 0;
+        let name = self.name_utf16().iter().chain(Some(&nul));
+        for (bytes, &unit) in buffer[header_size..size].chunks_mut(2).zip(name) {
+            bytes[0] = unit as u8;
+            bytes[1] = (unit >> 8) as u8;
+        }
+        size
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attribute & FILE_DIRECTORY != 0
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.attribute & FILE_READ_ONLY != 0
+    }
+
+    /// The file's name without its directory, as UTF-16
+    pub fn name_utf16(&self) -> &[u16] {
+        &self.name[..self.name_length]
+    }
+
+    pub fn name(&self) -> FileName {
+        FileName {
+            name: self.name_utf16(),
+        }
+    }
+}
+
+impl ::core::fmt::Debug for FileInfo {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_struct("FileInfo")
+            .field("name", &format_args!("{}", self.name()))
+            .field("file_size", &self.file_size)
+            .field("attribute", &format_args!("{:#x}", self.attribute))
+            .field("modification_time", &self.modification_time)
+            .finish()
+    }
+}
+
+/// A UTF-16 file name, which prints with anything that isn't valid
+/// UTF-16 replaced
+#[derive(Clone, Copy)]
+pub struct FileName<'a> {
+    name: &'a [u16],
+}
+
+impl<'a> ::core::fmt::Display for FileName<'a> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        use ::core::fmt::Write;
+        for c in ::core::char::decode_utf16(self.name.iter().cloned()) {
+            f.write_char(c.unwrap_or(::core::char::REPLACEMENT_CHARACTER))?;
+        }
+        Ok(())
+    }
+}
 
 #[repr(C)]
 #[allow(non_snake_case)]
 pub struct FileProtocol {
     revision: usize,
     Open:           extern fn(&mut FileProtocol, &mut *mut FileProtocol, *const u16, u64, u64) -> ::def::Status,
-    Close:  FunctionPointer,
-    Delete:     FunctionPointer,
+    Close:          extern fn(&mut FileProtocol) -> ::def::Status,
+    Delete:         extern fn(&mut FileProtocol) -> ::def::Status,
     Read:           extern fn(&mut FileProtocol, &mut usize, *mut u8) -> ::def::Status,
-    Write:  FunctionPointer,
-    GetPosition:    FunctionPointer,
-    SetPosition:    FunctionPointer,
-    GetInfo:    FunctionPointer,
-    SetInfo:    FunctionPointer,
-    Flush:  FunctionPointer,
+    Write:          extern fn(&mut FileProtocol, &mut usize, *const u8) -> ::def::Status,
+    GetPosition:    extern fn(&mut FileProtocol, &mut u64) -> ::def::Status,
+    SetPosition:    extern fn(&mut FileProtocol, u64) -> ::def::Status,
+    GetInfo:        extern fn(&mut FileProtocol, &Guid, &mut usize, *mut u8) -> ::def::Status,
+    SetInfo:        extern fn(&mut FileProtocol, &Guid, usize, *const u8) -> ::def::Status,
+    Flush:          extern fn(&mut FileProtocol) -> ::def::Status,
     OpenEx:     FunctionPointer,
     ReadEx:     FunctionPointer,
     WriteEx:    FunctionPointer,
@@ -29,46 +178,326 @@ impl Protocol for FileProtocol {
     }
 }
 
-impl FileProtocol {
-    pub fn open(&mut self, file_name: &str) -> Result<&mut FileProtocol, ::def::Status> {
-        let mut result = 0 as *mut FileProtocol;
+/// Longest path `File::open` takes, in UTF-16 code units
+pub const MAX_PATH: usize = 512;
+
+/// The position `File::seek_to_end` moves to
+const END_OF_FILE: u64 = 0xFFFF_FFFF_FFFF_FFFF;
+
+/// `EFI_FILE_MODE_*` bits
+const MODE_READ: u64 = 0x01;
+const MODE_WRITE: u64 = 0x02;
+const MODE_CREATE: u64 = 0x8000_0000_0000_0000;
 
-        let mut wide_file_name: [u16; 30] = [0; 30];
+/// How `File::open` opens a file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OpenMode {
+    Read,
+    ReadWrite,
+    /// Read and write, creating the file if it doesn't exist
+    Create,
+}
 
-        for (i, c) in file_name.as_bytes().iter().enumerate() {
-            wide_file_name[i] = *c as u16;
+impl OpenMode {
+    fn bits(&self) -> u64 {
+        match *self {
+            OpenMode::Read => MODE_READ,
+            OpenMode::ReadWrite => MODE_READ | MODE_WRITE,
+            OpenMode::Create => MODE_READ | MODE_WRITE | MODE_CREATE,
         }
+    }
+}
+
+/// Turns `path` into the NUL terminated UTF-16 the firmware takes
+fn encode_path(path: &str, buffer: &mut [u16; MAX_PATH + 1]) -> Result<(), ::def::Status> {
+    let mut length = 0;
+    for unit in path.encode_utf16() {
+        if length == MAX_PATH || unit == 0 {
+            return Err(::def::Status::InvalidParameter);
+        }
+        buffer[length] = unit;
+        length += 1;
+    }
+    buffer[length] = 0;
+    Ok(())
+}
 
+fn to_result(status: ::def::Status) -> Result<(), ::def::Status> {
+    if status == ::def::Status::Success {
+        Ok(())
+    } else {
+        Err(status)
+    }
+}
+
+/// An open file or directory, closed when dropped
+pub struct File {
+    protocol: *mut FileProtocol,
+}
+
+impl File {
+    /// Takes ownership of a handle the firmware opened
+    pub unsafe fn from_protocol(protocol: *mut FileProtocol) -> File {
+        File {
+            protocol: protocol,
+        }
+    }
+
+    fn protocol(&mut self) -> &mut FileProtocol {
+        unsafe { &mut *self.protocol }
+    }
+
+    fn open_with(&mut self, path: &str, mode: u64, attributes: u64) -> Result<File, ::def::Status> {
+        let mut wide_path = [0u16; MAX_PATH + 1];
+        encode_path(path, &mut wide_path)?;
+
+        let mut result = 0 as *mut FileProtocol;
+        let protocol = self.protocol();
         let status = ::bind::safe_efi_call5(
-            self.Open,
-            self,
+            protocol.Open,
+            protocol,
             &mut result,
-            wide_file_name.as_mut_ptr(),
-            0x01,
-            0x00);
+            wide_path.as_ptr(),
+            mode,
+            attributes);
 
-        if status == ::def::Status::Success {
-            unsafe {
-                Ok(::core::mem::transmute(result))
-            }
-        } else {
-            Err(status)
-        }
+        to_result(status).map(|_| unsafe { File::from_protocol(result) })
+    }
+
+    /// Opens `path`, relative to this directory unless it starts with
+    /// a backslash. Paths longer than `MAX_PATH` are refused.
+    pub fn open(&mut self, path: &str, mode: OpenMode) -> Result<File, ::def::Status> {
+        self.open_with(path, mode.bits(), 0)
+    }
+
+    /// Opens the directory `path`, creating it if it doesn't exist
+    pub fn create_directory(&mut self, path: &str) -> Result<File, ::def::Status> {
+        self.open_with(path, OpenMode::Create.bits(), FILE_DIRECTORY)
+    }
+
+    /// Reads from the current position into `buffer`, giving how many
+    /// bytes were read. 0 means the end of the file.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ::def::Status> {
+        let mut size = buffer.len();
+        let protocol = self.protocol();
+        let status = ::bind::safe_efi_call3(
+            protocol.Read,
+            protocol,
+            &mut size,
+            buffer.as_mut_ptr());
+
+        to_result(status).map(|_| size)
     }
 
-    pub fn read<'a>(&mut self, mut size: usize, buffer: &'a *mut u8) -> Result<&'a mut [u8], ::def::Status> {
+    /// Writes `data` at the current position, giving how many bytes
+    /// were written
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, ::def::Status> {
+        let mut size = data.len();
+        let protocol = self.protocol();
         let status = ::bind::safe_efi_call3(
-            self.Read,
-            self,
+            protocol.Write,
+            protocol,
+            &mut size,
+            data.as_ptr());
+
+        to_result(status).map(|_| size)
+    }
+
+    pub fn position(&mut self) -> Result<u64, ::def::Status> {
+        let mut position = 0;
+        let protocol = self.protocol();
+        let status = ::bind::safe_efi_call2(
+            protocol.GetPosition,
+            protocol,
+            &mut position);
+
+        to_result(status).map(|_| position)
+    }
+
+    /// Moves to `position` bytes from the start. Directories can only
+    /// go back to 0, which restarts their listing.
+    pub fn seek(&mut self, position: u64) -> Result<(), ::def::Status> {
+        let protocol = self.protocol();
+        to_result(::bind::safe_efi_call2(
+            protocol.SetPosition,
+            protocol,
+            position))
+    }
+
+    /// Moves to the end of the file, to append to it
+    pub fn seek_to_end(&mut self) -> Result<(), ::def::Status> {
+        self.seek(END_OF_FILE)
+    }
+
+    /// Size, attributes, timestamps and name of the file
+    pub fn get_info(&mut self) -> Result<FileInfo, ::def::Status> {
+        // u64s to keep the EFI_FILE_INFO aligned
+        let mut buffer = [0u64; FILE_INFO_BUFFER_SIZE / 8];
+        let mut size = FILE_INFO_BUFFER_SIZE;
+        let protocol = self.protocol();
+        let status = ::bind::safe_efi_call4(
+            protocol.GetInfo,
+            protocol,
+            &::api::types::FILE_INFO_GUID,
             &mut size,
-            *buffer);
+            buffer.as_mut_ptr() as *mut u8);
 
-        if status == ::def::Status::Success {
-            unsafe {
-                Ok(::core::slice::from_raw_parts_mut(*buffer, size))
+        to_result(status).map(|_| {
+            let bytes = unsafe {
+                ::core::slice::from_raw_parts(buffer.as_ptr() as *const u8, size)
+            };
+            FileInfo::from_bytes(bytes)
+        })
+    }
+
+    /// Changes the file's size, attributes, timestamps or name to those
+    /// in `info`
+    pub fn set_info(&mut self, info: &FileInfo) -> Result<(), ::def::Status> {
+        let mut buffer = [0u64; FILE_INFO_BUFFER_SIZE / 8];
+        let size = {
+            let bytes = unsafe {
+                ::core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, FILE_INFO_BUFFER_SIZE)
+            };
+            info.to_bytes(bytes)
+        };
+        let protocol = self.protocol();
+        to_result(::bind::safe_efi_call4(
+            protocol.SetInfo,
+            protocol,
+            &::api::types::FILE_INFO_GUID,
+            size,
+            buffer.as_ptr() as *const u8))
+    }
+
+    /// Grows or cuts the file to `size` bytes
+    pub fn set_size(&mut self, size: u64) -> Result<(), ::def::Status> {
+        let mut info = self.get_info()?;
+        info.file_size = size;
+        self.set_info(&info)
+    }
+
+    /// Writes anything the firmware still has buffered to the volume
+    pub fn flush(&mut self) -> Result<(), ::def::Status> {
+        let protocol = self.protocol();
+        to_result(::bind::safe_efi_call1(protocol.Flush, protocol))
+    }
+
+    /// Deletes the file, closing it. `Status::WarnDeleteFailure` means
+    /// it was closed but is still there.
+    pub fn delete(mut self) -> Result<(), ::def::Status> {
+        let status = {
+            let protocol = self.protocol();
+            ::bind::safe_efi_call1(protocol.Delete, protocol)
+        };
+        // Delete closes the handle whether or not it worked
+        ::core::mem::forget(self);
+        to_result(status)
+    }
+
+    /// The files and directories in this directory, from the start.
+    /// `.` and `..` are left out.
+    pub fn entries(&mut self) -> Result<DirectoryEntries, ::def::Status> {
+        if !self.get_info()?.is_directory() {
+            return Err(::def::Status::InvalidParameter);
+        }
+        self.seek(0)?;
+        Ok(DirectoryEntries {
+            directory: self,
+            done: false,
+        })
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        // Close can't fail
+        let protocol = self.protocol();
+        ::bind::safe_efi_call1(protocol.Close, protocol);
+    }
+}
+
+/// Iterator over a directory, see `File::entries`
+pub struct DirectoryEntries<'a> {
+    directory: &'a mut File,
+    done: bool,
+}
+
+impl<'a> Iterator for DirectoryEntries<'a> {
+    type Item = Result<FileInfo, ::def::Status>;
+
+    fn next(&mut self) -> Option<Result<FileInfo, ::def::Status>> {
+        while !self.done {
+            // Each read of a directory gives the EFI_FILE_INFO of its
+            // next entry, and nothing at the end
+            let mut buffer = [0u64; FILE_INFO_BUFFER_SIZE / 8];
+            let result = {
+                let bytes = unsafe {
+                    ::core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, FILE_INFO_BUFFER_SIZE)
+                };
+                self.directory.read(bytes).map(|size| {
+                    if size == 0 {
+                        None
+                    } else {
+                        Some(FileInfo::from_bytes(&bytes[..size]))
+                    }
+                })
+            };
+            match result {
+                Ok(Some(info)) => {
+                    let dots = {
+                        let name = info.name_utf16();
+                        name.len() <= 2 && name.iter().all(|&unit| unit == '.' as u16)
+                    };
+                    if !dots {
+                        return Some(Ok(info));
+                    }
+                },
+                Ok(None) => self.done = true,
+                Err(status) => {
+                    self.done = true;
+                    return Some(Err(status));
+                },
             }
-        } else {
-            Err(status)
         }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_info_round_trip() {
+        let mut buffer = [0u64; FILE_INFO_BUFFER_SIZE / 8];
+        let bytes = unsafe {
+            ::core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, FILE_INFO_BUFFER_SIZE)
+        };
+        let mut info = FileInfo::from_bytes(bytes);
+        info.file_size = 4096;
+        info.attribute = FILE_ARCHIVE;
+        info.name[..8].copy_from_slice(&[0x42, 0x4f, 0x4f, 0x54, 0x2e, 0x4c, 0x4f, 0x47]);
+        info.name_length = 8;
+
+        let size = info.to_bytes(bytes);
+        assert_eq!(size, 80 + 2 * 9);
+        assert_eq!(&bytes[..8], &[98, 0, 0, 0, 0, 0, 0, 0]);
+        let parsed = FileInfo::from_bytes(&bytes[..size]);
+        assert_eq!(parsed.file_size, 4096);
+        assert!(!parsed.is_directory());
+        assert_eq!(parsed.name_utf16(), info.name_utf16());
+    }
+
+    #[test]
+    fn long_paths() {
+        let mut buffer = [0xffffu16; MAX_PATH + 1];
+        encode_path("EFI\\OS", &mut buffer).unwrap();
+        assert_eq!(&buffer[..7], &[0x45, 0x46, 0x49, 0x5c, 0x4f, 0x53, 0]);
+
+        let path = [b'a'; MAX_PATH + 1];
+        let path = ::core::str::from_utf8(&path).unwrap();
+        assert_eq!(encode_path(&path[..MAX_PATH], &mut buffer), Ok(()));
+        assert_eq!(encode_path(path, &mut buffer), Err(::def::Status::InvalidParameter));
+        assert_eq!(encode_path("a\0b", &mut buffer), Err(::def::Status::InvalidParameter));
     }
 }
@@ -0,0 +1,69 @@
+use super::Protocol;
+use ::api::types::{FunctionPointer, Guid};
+
+/// How a pixel's bytes are laid out in the frame buffer
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    RedGreenBlueReserved8BitPerColor,
+    BlueGreenRedReserved8BitPerColor,
+    /// Described by `ModeInformation::pixel_bitmask`
+    BitMask,
+    /// No linear frame buffer, only Blt works
+    BltOnly,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct ModeInformation {
+    pub version: u32,
+    pub horizontal_resolution: u32,
+    pub vertical_resolution: u32,
+    pub pixel_format: PixelFormat,
+    /// Red, green, blue and reserved masks for `PixelFormat::BitMask`
+    pub pixel_bitmask: [u32; 4],
+    pub pixels_per_scan_line: u32,
+}
+
+#[repr(C)]
+pub struct GraphicsOutputMode {
+    max_mode: u32,
+    mode: u32,
+    info: *const ModeInformation,
+    size_of_info: usize,
+    frame_buffer_base: ::def::PhysicalAddress,
+    frame_buffer_size: usize,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+#[allow(dead_code)]
+pub struct GraphicsOutputProtocol {
+    QueryMode: FunctionPointer,
+    SetMode: FunctionPointer,
+    Blt: FunctionPointer,
+    mode: *const GraphicsOutputMode,
+}
+
+impl Protocol for GraphicsOutputProtocol {
+    fn get_guid() -> Guid {
+        ::api::types::GRAPHICS_OUTPUT_GUID
+    }
+}
+
+impl GraphicsOutputProtocol {
+    /// The current mode
+    pub fn mode_information(&self) -> &ModeInformation {
+        unsafe { &*(*self.mode).info }
+    }
+
+    /// Physical address of the linear frame buffer of the current mode
+    pub fn frame_buffer_base(&self) -> ::mem::PhysicalAddress {
+        unsafe { (*self.mode).frame_buffer_base.into() }
+    }
+
+    /// Size in bytes of the linear frame buffer
+    pub fn frame_buffer_size(&self) -> usize {
+        unsafe { (*self.mode).frame_buffer_size }
+    }
+}
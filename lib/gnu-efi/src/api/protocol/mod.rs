@@ -9,6 +9,8 @@ pub mod load_file_protocol;
 pub mod load_file2_protocol;
 pub mod simple_file_system_protocol;
 pub mod file_protocol;
+pub mod graphics_output_protocol;
+pub mod rng_protocol;
 
 pub use self::loaded_image_protocol::LoadedImageProtocol;
 pub use self::device_path_protocol::DevicePathProtocol;
@@ -18,7 +20,9 @@ pub use self::simple_text_input_protocol::SimpleTextInputProtocol;
 pub use self::load_file_protocol::LoadFileProtocol;
 pub use self::load_file2_protocol::LoadFile2Protocol;
 pub use self::simple_file_system_protocol::SimpleFileSystemProtocol;
-pub use self::file_protocol::FileProtocol;
+pub use self::file_protocol::{File, FileProtocol};
+pub use self::graphics_output_protocol::GraphicsOutputProtocol;
+pub use self::rng_protocol::RngProtocol;
 
 use ::api::types::Guid;
 
@@ -0,0 +1,36 @@
+use super::Protocol;
+use ::api::types::{FunctionPointer, Guid};
+
+/// `EFI_RNG_PROTOCOL`, the firmware's random number generator
+#[repr(C)]
+#[allow(non_snake_case)]
+#[allow(dead_code)]
+pub struct RngProtocol {
+    GetInfo: FunctionPointer,
+    GetRNG: extern fn(&mut RngProtocol, *const Guid, usize, *mut u8) -> ::def::Status,
+}
+
+impl Protocol for RngProtocol {
+    fn get_guid() -> Guid {
+        ::api::types::RNG_GUID
+    }
+}
+
+impl RngProtocol {
+    /// Fills `buffer` with random bytes from the firmware's default
+    /// algorithm
+    pub fn get_rng(&mut self, buffer: &mut [u8]) -> Result<(), ::def::Status> {
+        let status = ::bind::safe_efi_call4(
+            self.GetRNG,
+            self,
+            0 as *const Guid,
+            buffer.len(),
+            buffer.as_mut_ptr());
+
+        if status == ::def::Status::Success {
+            Ok(())
+        } else {
+            Err(status)
+        }
+    }
+}
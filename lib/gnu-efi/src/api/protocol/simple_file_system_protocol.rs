@@ -1,6 +1,5 @@
-
 use super::Protocol;
-use super::file_protocol::FileProtocol;
+use super::file_protocol::{File, FileProtocol};
 use ::api::types::Guid;
 
 #[repr(C)]
@@ -17,7 +16,8 @@ impl Protocol for SimpleFileSystemProtocol {
 }
 
 impl SimpleFileSystemProtocol {
-    pub fn open_volume(&mut self) -> Result<&mut FileProtocol, ::def::Status> {
+    /// Opens the root directory of the volume
+    pub fn open_volume(&mut self) -> Result<File, ::def::Status> {
         let mut root = 0 as *mut FileProtocol;
         let status = ::bind::safe_efi_call2(
             self.OpenVolume,
@@ -26,7 +26,7 @@ impl SimpleFileSystemProtocol {
 
         if status == ::def::Status::Success {
             unsafe {
-                Ok(::core::mem::transmute(root))
+                Ok(File::from_protocol(root))
             }
         } else {
             Err(status)
@@ -1,11 +1,43 @@
-
 use ::api::types::FunctionPointer;
 
+/// `EFI_INPUT_KEY` scan codes for keys that aren't characters
+pub const SCAN_NULL: u16 = 0x00;
+pub const SCAN_UP: u16 = 0x01;
+pub const SCAN_DOWN: u16 = 0x02;
+pub const SCAN_ESC: u16 = 0x17;
+
+/// A key press. `unicode_char` is 0 for keys that only have a
+/// `scan_code`.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct InputKey {
+    pub scan_code: u16,
+    pub unicode_char: u16,
+}
+
 #[repr(C)]
 #[allow(non_snake_case)]
 #[allow(dead_code)]
 pub struct SimpleTextInputProtocol {
     Reset: FunctionPointer,
-    ReadKeyStroke: FunctionPointer,
+    ReadKeyStroke: extern fn(&mut SimpleTextInputProtocol, &mut InputKey) -> ::def::Status,
     WaitForKey: ::api::types::Event,
 }
+
+impl SimpleTextInputProtocol {
+    /// The next key pressed, without waiting for one. `Status::NotReady`
+    /// means no key has been pressed.
+    pub fn read_key_stroke(&mut self) -> Result<InputKey, ::def::Status> {
+        let mut key = InputKey::default();
+        let status = ::bind::safe_efi_call2(
+            self.ReadKeyStroke,
+            self,
+            &mut key);
+
+        if status == ::def::Status::Success {
+            Ok(key)
+        } else {
+            Err(status)
+        }
+    }
+}
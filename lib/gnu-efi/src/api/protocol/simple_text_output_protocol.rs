@@ -1,5 +1,3 @@
-
-
 use ::api::types::FunctionPointer;
 
 #[repr(C)]
@@ -17,13 +15,81 @@ pub struct SimpleTextOutputMode {
 #[allow(dead_code)]
 pub struct SimpleTextOutputProtocol {
     Reset: FunctionPointer,
-    OutputString: FunctionPointer,
+    OutputString: extern fn(&mut SimpleTextOutputProtocol, *const u16) -> ::def::Status,
     TestString: FunctionPointer,
     QueryMode: FunctionPointer,
     SetMode: FunctionPointer,
     SetAttribute: FunctionPointer,
-    ClearScreen: FunctionPointer,
-    SetCursorPosition: FunctionPointer,
+    ClearScreen: extern fn(&mut SimpleTextOutputProtocol) -> ::def::Status,
+    SetCursorPosition: extern fn(&mut SimpleTextOutputProtocol, usize, usize) -> ::def::Status,
     EnableCursor: FunctionPointer,
     mode: *const SimpleTextOutputMode,
 }
+
+/// UTF-16 code units `output_string` hands the firmware at a time
+const OUTPUT_CHUNK: usize = 128;
+
+fn to_result(status: ::def::Status) -> Result<(), ::def::Status> {
+    if status == ::def::Status::Success {
+        Ok(())
+    } else {
+        Err(status)
+    }
+}
+
+impl SimpleTextOutputProtocol {
+    /// Prints `string` at the cursor. Line feeds get the carriage
+    /// return the console needs, and characters outside the Basic
+    /// Multilingual Plane, which the console can't show, become `?`.
+    pub fn output_string(&mut self, string: &str) -> Result<(), ::def::Status> {
+        // A chunk can run over by a CR LF pair's second half, and
+        // needs a NUL after it
+        let mut buffer = [0u16; OUTPUT_CHUNK + 2];
+        let mut length = 0;
+        for c in string.chars() {
+            if c == '\n' {
+                buffer[length] = '\r' as u16;
+                length += 1;
+            }
+            buffer[length] = if (c as u32) < 0x10000 { c as u16 } else { '?' as u16 };
+            length += 1;
+            if length >= OUTPUT_CHUNK {
+                self.output_utf16(&mut buffer, length)?;
+                length = 0;
+            }
+        }
+        if length > 0 {
+            self.output_utf16(&mut buffer, length)?;
+        }
+        Ok(())
+    }
+
+    /// Prints the first `length` code units of `buffer`, which has room
+    /// for a NUL after them
+    fn output_utf16(&mut self, buffer: &mut [u16], length: usize) -> Result<(), ::def::Status> {
+        buffer[length] = 0;
+        to_result(::bind::safe_efi_call2(
+            self.OutputString,
+            self,
+            buffer.as_ptr()))
+    }
+
+    /// Blanks the screen and moves the cursor to the top left
+    pub fn clear_screen(&mut self) -> Result<(), ::def::Status> {
+        to_result(::bind::safe_efi_call1(self.ClearScreen, self))
+    }
+
+    pub fn set_cursor_position(&mut self, column: usize, row: usize) -> Result<(), ::def::Status> {
+        to_result(::bind::safe_efi_call3(
+            self.SetCursorPosition,
+            self,
+            column,
+            row))
+    }
+}
+
+impl ::core::fmt::Write for SimpleTextOutputProtocol {
+    fn write_str(&mut self, string: &str) -> ::core::fmt::Result {
+        self.output_string(string).map_err(|_| ::core::fmt::Error)
+    }
+}
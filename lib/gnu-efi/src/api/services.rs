@@ -20,7 +20,7 @@ pub struct BootServices {
     // Memory functions
     //
 
-    AllocatePages:  extern fn(allocate_type: def::AllocateType, memory_type: def::MemoryType, pages: usize, buffer: *const *mut u8) -> def::Status,
+    AllocatePages:  extern fn(allocate_type: def::AllocateType, memory_type: def::MemoryType, pages: usize, memory: *mut def::PhysicalAddress) -> def::Status,
     FreePages:                  FunctionPointer,
     GetMemoryMap:   extern fn(memory_map_size:&mut usize, memory_map:*const def::MemoryDescriptor, map_key:&mut usize, descriptor_size:&mut usize, descriptor_version:&mut u32) -> def::Status,
     AllocatePool:   extern fn(pool_type: def::MemoryType, size: usize, buffer: *const *mut u8) -> def::Status,
@@ -66,7 +66,7 @@ pub struct BootServices {
     //
 
     GetNextMonotonicCount:                  FunctionPointer,
-    Stall:                  FunctionPointer,
+    Stall:          extern fn(microseconds: usize) -> def::Status,
     SetWatchdogTimer:                   FunctionPointer,
 
     //
@@ -180,6 +180,11 @@ impl BootServices {
         }
     }
 
+    /// Busy waits for at least `microseconds`
+    pub fn stall(&self, microseconds: usize) {
+        bind::safe_efi_call1(self.Stall, microseconds);
+    }
+
     pub fn retrieve_handles_with_protocol<T: Protocol>(&self) -> Result<&[def::Handle], def::Status> {
         let mut buffer_size: usize = 0;
         let mut buffer: *const def::Handle = 0 as *mut def::Handle;
@@ -218,16 +223,17 @@ impl BootServices {
     }
 
     pub fn allocate_pages(&self, pages: usize) -> Result<types::EfiBuffer, def::Status> {
-            let pointer: *mut u8 = 0 as *mut u8;
+            let mut memory = def::PhysicalAddress { address: 0 };
             let status = bind::safe_efi_call4(
                 self.AllocatePages,
                 def::AllocateType::AllocateAnyPages,
                 def::MemoryType::LoaderData,
                 pages,
-                &pointer as *const *mut u8);
+                &mut memory as *mut def::PhysicalAddress);
 
         unsafe {
-            let result = types::EfiBuffer::new(pointer, pages * 0x1000);
+            let mut address: ::mem::PhysicalAddress = memory.into();
+            let result = types::EfiBuffer::new(address.as_mut_ptr() as *mut u8, pages * 0x1000);
 
             if status == def::Status::Success {
                 Ok(result)
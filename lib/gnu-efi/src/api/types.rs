@@ -102,6 +102,20 @@ pub const SIMPLE_FILE_SYSTEM_GUID: Guid = Guid {
     data4: [0x8e,0x39,0x00,0xa0,0xc9,0x69,0x72,0x3b],
 };
 
+pub const GRAPHICS_OUTPUT_GUID: Guid = Guid {
+    data1: 0x9042a9de,
+    data2: 0x23dc,
+    data3: 0x4a38,
+    data4: [0x96,0xfb,0x7a,0xde,0xd0,0x80,0x51,0x6a],
+};
+
+pub const FILE_INFO_GUID: Guid = Guid {
+    data1: 0x09576e92,
+    data2: 0x6d3f,
+    data3: 0x11d2,
+    data4: [0x8e,0x39,0x00,0xa0,0xc9,0x69,0x72,0x3b],
+};
+
 pub const FILE_GUID: Guid = Guid {
     data1: 0,
     data2: 0,
@@ -109,6 +123,13 @@ pub const FILE_GUID: Guid = Guid {
     data4: [0; 8],
 };
 
+pub const RNG_GUID: Guid = Guid {
+    data1: 0x3152bca5,
+    data2: 0xeade,
+    data3: 0x433d,
+    data4: [0x86,0x2e,0xc0,0x1c,0xdc,0x29,0x1f,0x44],
+};
+
 
 /// Single row in the vendor configuration table
 #[repr(C)]
@@ -136,10 +157,10 @@ pub struct SystemTable {
     pub firmware_revision:          u32,
 
     console_in_handle:              def::Handle,
-    con_in: &'static ::api::protocol::SimpleTextInputProtocol,
+    con_in: &'static mut ::api::protocol::SimpleTextInputProtocol,
 
     console_out_handle:             def::Handle,
-    con_out:&'static ::api::protocol::SimpleTextOutputProtocol,
+    con_out:&'static mut ::api::protocol::SimpleTextOutputProtocol,
 
     standard_error_handle:          def::Handle,
     std_err:&'static ::api::protocol::SimpleTextOutputProtocol,
@@ -153,6 +174,16 @@ pub struct SystemTable {
 }
 
 impl SystemTable {
+    /// The console's keyboard, gone once boot services exit
+    pub fn console_in(&mut self) -> &mut ::api::protocol::SimpleTextInputProtocol {
+        self.con_in
+    }
+
+    /// The console's screen, gone once boot services exit
+    pub fn console_out(&mut self) -> &mut ::api::protocol::SimpleTextOutputProtocol {
+        self.con_out
+    }
+
     pub fn configuration_table<'a>(&'a self) -> &'a [ConfigurationTable] {
         unsafe {
             slice::from_raw_parts(
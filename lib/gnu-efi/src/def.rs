@@ -8,6 +8,70 @@ pub struct Handle {
     pub handle:*const ::mem::c_void,
 }
 
+/// `EFI_PHYSICAL_ADDRESS` as UEFI functions take and return it
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PhysicalAddress {
+    pub address: u64,
+}
+
+impl From<PhysicalAddress> for ::mem::PhysicalAddress {
+    fn from(value: PhysicalAddress) -> Self {
+        ::mem::PhysicalAddress::new(value.address as usize)
+    }
+}
+
+impl From<::mem::PhysicalAddress> for PhysicalAddress {
+    fn from(value: ::mem::PhysicalAddress) -> Self {
+        let address: usize = value.into();
+        PhysicalAddress {
+            address: address as u64,
+        }
+    }
+}
+
+/// `EFI_TIME`, a calendar date and time of day
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pad1: u8,
+    pub nanosecond: u32,
+    /// Minutes from UTC, or `UNSPECIFIED_TIMEZONE` for local time
+    pub time_zone: i16,
+    pub daylight: u8,
+    pad2: u8,
+}
+
+pub const UNSPECIFIED_TIMEZONE: i16 = 0x07FF;
+
+/// `EFI_VIRTUAL_ADDRESS` as UEFI functions take and return it
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VirtualAddress {
+    pub address: u64,
+}
+
+impl From<VirtualAddress> for ::mem::VirtualAddress {
+    fn from(value: VirtualAddress) -> Self {
+        ::mem::VirtualAddress::new(value.address as usize)
+    }
+}
+
+impl From<::mem::VirtualAddress> for VirtualAddress {
+    fn from(value: ::mem::VirtualAddress) -> Self {
+        let address: usize = value.into();
+        VirtualAddress {
+            address: address as u64,
+        }
+    }
+}
+
 pub use self::memory_descriptor::{MemoryDescriptor, MemoryDescriptors, MemoryType, AllocateType};
 
 mod memory_descriptor {
@@ -41,6 +105,22 @@ mod memory_descriptor {
             self.number
         }
 
+        /// Pointer to the buffer holding the descriptors
+        pub fn as_ptr(&self) -> *const MemoryDescriptor {
+            self.start
+        }
+
+        /// Size in bytes of the buffer holding the descriptors
+        pub fn buffer_size(&self) -> usize {
+            self.number * self.size
+        }
+
+        /// Bytes from one descriptor to the next, which may be more than
+        /// the size of `MemoryDescriptor`
+        pub fn descriptor_size(&self) -> usize {
+            self.size
+        }
+
         pub fn get(&self, index: usize) -> Option<&MemoryDescriptor> {
             if index < self.number {
                 unsafe { Some(self.get_unchecked(index)) }
@@ -1,7 +1,16 @@
 #![no_std]
 #![feature(step_trait)]
 
-use core::ops::{Add};
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+use core::ops::{Add, Sub};
+
+/// Page and frame ranges
+mod range;
+
+pub use range::{FrameRange, PageRange};
 
 // Use repr(u8) as LLVM expects `void*` to be the same as `i8*` to help enable
 // more optimization opportunities around it recognizing things like
@@ -17,7 +26,48 @@ pub enum c_void {
 }
 
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// Debug formatting in hex, as addresses, pages and frames are always
+/// read next to hex dumps and linker maps
+macro_rules! hex_debug {
+    ($name:ident, $field:ident) => {
+        impl core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                write!(f, concat!(stringify!($name), " {{ ", stringify!($field), ": {:#x} }}"), self.$field)
+            }
+        }
+    };
+}
+
+macro_rules! signed_hex_debug {
+    ($name:ident, $field:ident) => {
+        impl core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                let sign = if self.$field < 0 { "-" } else { "" };
+                write!(f, concat!(stringify!($name), " {{ ", stringify!($field), ": {}{:#x} }}"),
+                    sign, (self.$field as i64).wrapping_abs() as u64)
+            }
+        }
+    };
+}
+
+/// Virtual address all of RAM is mapped at when the loader doesn't
+/// randomize it, the start of the higher half
+pub const DIRECT_MAP_OFFSET: usize = 0xFFFF_8000_0000_0000;
+
+static mut DIRECT_MAP_BASE: usize = DIRECT_MAP_OFFSET;
+
+/// Virtual address all of RAM is mapped at in this boot
+pub fn direct_map_offset() -> usize {
+    unsafe { DIRECT_MAP_BASE }
+}
+
+/// Moves the direct map. Only safe at boot, before anything has been
+/// reached through the direct map and before other CPUs are running.
+pub unsafe fn set_direct_map_offset(offset: usize) {
+    DIRECT_MAP_BASE = offset;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page {
     page: usize,
 }
@@ -30,6 +80,12 @@ impl Page {
     }
 }
 
+impl From<Page> for usize {
+    fn from(value: Page) -> Self {
+        value.page
+    }
+}
+
 impl From<VirtualAddress> for Page {
     fn from(value: VirtualAddress) -> Self {
         Self::new(value.address >> 12)
@@ -50,17 +106,22 @@ impl Add<PageOffset> for Page {
     }
 }
 
-impl core::fmt::Debug for Page {
-    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-        write!(f, "Page {{ page: {:x} }}", self.page)
+impl Sub<Page> for Page {
+    type Output = PageOffset;
+    fn sub(self, rhs: Page) -> Self::Output {
+        PageOffset::new(difference(self.page, rhs.page).expect("Page difference overflowed"))
     }
 }
 
-#[derive(Clone, Copy, PartialOrd, PartialEq, Eq, Debug)]
+hex_debug!(Page, page);
+
+#[derive(Clone, Copy, PartialOrd, PartialEq, Eq)]
 pub struct PageOffset {
     page_offset: isize,
 }
 
+signed_hex_debug!(PageOffset, page_offset);
+
 impl PageOffset {
     pub fn new(page_offset: isize) -> Self {
         PageOffset {
@@ -158,11 +219,13 @@ impl ::core::iter::Step for PageOffset {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Frame {
     frame: usize,
 }
 
+hex_debug!(Frame, frame);
+
 impl Frame {
     pub fn new(frame: usize) -> Frame {
         Frame {
@@ -197,11 +260,20 @@ impl Add<FrameOffset> for Frame {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+impl Sub<Frame> for Frame {
+    type Output = FrameOffset;
+    fn sub(self, rhs: Frame) -> Self::Output {
+        FrameOffset::new(difference(self.frame, rhs.frame).expect("Frame difference overflowed"))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct FrameOffset {
     frame_offset: isize,
 }
 
+signed_hex_debug!(FrameOffset, frame_offset);
+
 impl FrameOffset {
     pub fn new(frame_offset: isize) -> Self {
         FrameOffset {
@@ -210,11 +282,19 @@ impl FrameOffset {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+impl From<FrameOffset> for isize {
+    fn from(value: FrameOffset) -> Self {
+        value.frame_offset
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PhysicalAddress {
     address: usize,
 }
 
+hex_debug!(PhysicalAddress, address);
+
 impl PhysicalAddress {
     pub fn new(address: usize) -> Self {
         PhysicalAddress {
@@ -229,6 +309,43 @@ impl PhysicalAddress {
     pub fn as_mut_ptr(&mut self) -> *mut c_void {
         self.address as *mut c_void
     }
+
+    /// Where this address can be reached through the direct map of
+    /// physical memory the loader sets up
+    pub fn to_direct_map(&self) -> VirtualAddress {
+        VirtualAddress::new(direct_map_offset() + self.address)
+    }
+
+    /// Rounds down to a multiple of `alignment`, a power of two
+    pub fn align_down(self, alignment: usize) -> Self {
+        PhysicalAddress::new(align_down(self.address, alignment))
+    }
+
+    /// Rounds up to a multiple of `alignment`, a power of two
+    pub fn align_up(self, alignment: usize) -> Self {
+        PhysicalAddress::new(align_up(self.address, alignment))
+    }
+
+    pub fn is_aligned(self, alignment: usize) -> bool {
+        self.align_down(alignment) == self
+    }
+
+    /// Distance from `rhs` to `self`, or `None` if it doesn't fit in
+    /// an offset
+    pub fn checked_sub(self, rhs: PhysicalAddress) -> Option<PhysicalAddressOffset> {
+        difference(self.address, rhs.address).map(|address_offset| {
+            PhysicalAddressOffset {
+                address_offset: address_offset,
+            }
+        })
+    }
+}
+
+impl Sub<PhysicalAddress> for PhysicalAddress {
+    type Output = PhysicalAddressOffset;
+    fn sub(self, rhs: PhysicalAddress) -> Self::Output {
+        self.checked_sub(rhs).expect("Address difference overflowed")
+    }
 }
 
 impl From<Frame> for PhysicalAddress {
@@ -245,17 +362,27 @@ impl From<PhysicalAddress> for usize {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct PhysicalAddressOffset {
     address_offset: isize,
 }
 
+signed_hex_debug!(PhysicalAddressOffset, address_offset);
+
+impl From<PhysicalAddressOffset> for isize {
+    fn from(value: PhysicalAddressOffset) -> Self {
+        value.address_offset
+    }
+}
+
 // Maps one to one with pages
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct VirtualAddress {
     address: usize,
 }
 
+hex_debug!(VirtualAddress, address);
+
 impl VirtualAddress {
     pub fn new(address: usize) -> Self {
         VirtualAddress {
@@ -270,6 +397,40 @@ impl VirtualAddress {
     pub fn as_mut_ptr(&mut self) -> *mut usize {
         self.address as *mut usize
     }
+
+    /// Whether bits 48 to 63 are copies of bit 47, which the processor
+    /// requires of every address it translates
+    pub fn is_canonical(&self) -> bool {
+        let upper_bits = self.address >> 47;
+        upper_bits == 0 || upper_bits == 0x1FFFF
+    }
+
+    /// Rounds down to a multiple of `alignment`, a power of two
+    pub fn align_down(self, alignment: usize) -> Self {
+        VirtualAddress::new(align_down(self.address, alignment))
+    }
+
+    /// Rounds up to a multiple of `alignment`, a power of two
+    pub fn align_up(self, alignment: usize) -> Self {
+        VirtualAddress::new(align_up(self.address, alignment))
+    }
+
+    pub fn is_aligned(self, alignment: usize) -> bool {
+        self.align_down(alignment) == self
+    }
+
+    /// Distance from `rhs` to `self`, or `None` if it doesn't fit in
+    /// an offset
+    pub fn checked_sub(self, rhs: VirtualAddress) -> Option<VirtualAddressOffset> {
+        difference(self.address, rhs.address).map(VirtualAddressOffset::new)
+    }
+}
+
+impl Sub<VirtualAddress> for VirtualAddress {
+    type Output = VirtualAddressOffset;
+    fn sub(self, rhs: VirtualAddress) -> Self::Output {
+        self.checked_sub(rhs).expect("Address difference overflowed")
+    }
 }
 
 impl From<Page> for VirtualAddress {
@@ -286,11 +447,13 @@ impl From<VirtualAddress> for usize {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct VirtualAddressOffset {
     address_offset: isize,
 }
 
+signed_hex_debug!(VirtualAddressOffset, address_offset);
+
 impl VirtualAddressOffset {
     pub fn new(address_offset: isize) -> Self {
         VirtualAddressOffset {
@@ -299,30 +462,95 @@ impl VirtualAddressOffset {
     }
 }
 
-// Maps one to one with physical addresses
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub struct PageTableAddress {
-    address: usize,
+impl From<VirtualAddressOffset> for isize {
+    fn from(value: VirtualAddressOffset) -> Self {
+        value.address_offset
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub struct PageTableAddressOffset {
-    address_offset: isize,
+fn align_down(address: usize, alignment: usize) -> usize {
+    assert!(alignment.is_power_of_two(), "Alignment must be a power of two");
+    address & !(alignment - 1)
 }
 
-// Maps one to one with frames {
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub struct PageTablePage {
-    page: usize,
+fn align_up(address: usize, alignment: usize) -> usize {
+    assert!(alignment.is_power_of_two(), "Alignment must be a power of two");
+    address.checked_add(alignment - 1).expect("Aligning the address overflowed") & !(alignment - 1)
 }
 
-// Maps one to one with physical addresses
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub struct UefiAddress {
-    address: usize,
+/// `lhs - rhs` as a signed offset, if it fits
+fn difference(lhs: usize, rhs: usize) -> Option<isize> {
+    if lhs >= rhs {
+        let difference = lhs - rhs;
+        if difference <= ::core::isize::MAX as usize {
+            Some(difference as isize)
+        } else {
+            None
+        }
+    } else {
+        let difference = rhs - lhs;
+        if difference <= ::core::isize::MAX as usize {
+            Some(-(difference as isize))
+        } else {
+            None
+        }
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub struct UefiAddressOffset {
-    address_offset: isize,
+#[cfg(test)]
+mod tests {
+    use super::{Frame, FrameRange, Page, PageOffset, PageRange, PhysicalAddress, VirtualAddress};
+
+    #[test]
+    fn canonical_addresses() {
+        assert!(VirtualAddress::new(0x0000_7FFF_FFFF_FFFF).is_canonical());
+        assert!(VirtualAddress::new(0xFFFF_8000_0000_0000).is_canonical());
+        assert!(!VirtualAddress::new(0x0000_8000_0000_0000).is_canonical());
+        assert!(!VirtualAddress::new(0xFFFF_7FFF_FFFF_FFFF).is_canonical());
+    }
+
+    #[test]
+    fn alignment() {
+        let address = VirtualAddress::new(0x1234);
+        assert_eq!(address.align_down(0x1000), VirtualAddress::new(0x1000));
+        assert_eq!(address.align_up(0x1000), VirtualAddress::new(0x2000));
+        assert_eq!(VirtualAddress::new(0x2000).align_up(0x1000), VirtualAddress::new(0x2000));
+        assert!(PhysicalAddress::new(0x20_0000).is_aligned(0x20_0000));
+        assert!(!PhysicalAddress::new(0x20_1000).is_aligned(0x20_0000));
+    }
+
+    #[test]
+    fn subtraction() {
+        let difference: isize = (VirtualAddress::new(0x1000) - VirtualAddress::new(0x3000)).into();
+        assert_eq!(difference, -0x2000);
+        assert_eq!(Page::new(5) - Page::new(2), PageOffset::new(3));
+        assert!(VirtualAddress::new(!0).checked_sub(VirtualAddress::new(0)).is_none());
+    }
+
+    #[test]
+    fn ranges() {
+        let pages = PageRange::starting_at(Page::new(0x10), 4);
+        assert_eq!(pages.len(), 4);
+        assert!(pages.contains(Page::new(0x13)));
+        assert!(!pages.contains(Page::new(0x14)));
+        let page_numbers: usize = pages.map(usize::from).sum();
+        assert_eq!(page_numbers, 0x10 + 0x11 + 0x12 + 0x13);
+
+        let (low, high) = pages.split_at(Page::new(0x11));
+        assert_eq!(low, PageRange::new(Page::new(0x10), Page::new(0x11)));
+        assert_eq!(high, PageRange::new(Page::new(0x11), Page::new(0x14)));
+        assert!(pages.split_at(Page::new(0x20)).1.is_empty());
+
+        let frames = FrameRange::new(Frame::new(0x8), Frame::new(0x10));
+        let other = FrameRange::starting_at(Frame::new(0xC), 0x10);
+        assert_eq!(frames.intersection(&other), Some(FrameRange::new(Frame::new(0xC), Frame::new(0x10))));
+        assert_eq!(frames.intersection(&FrameRange::starting_at(Frame::new(0x10), 1)), None);
+    }
+
+    #[test]
+    fn debug_is_hex() {
+        assert_eq!(format!("{:?}", Frame::new(0x1F)), "Frame { frame: 0x1f }");
+        assert_eq!(format!("{:?}", VirtualAddress::new(0xB8000)), "VirtualAddress { address: 0xb8000 }");
+        assert_eq!(format!("{:?}", PageOffset::new(-0x10)), "PageOffset { page_offset: -0x10 }");
+    }
 }
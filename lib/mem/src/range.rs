@@ -0,0 +1,104 @@
+use core::cmp::{max, min};
+
+use super::{Frame, Page};
+
+macro_rules! range_type {
+    ($(#[$attribute:meta])* pub struct $name:ident($item:ident);) => {
+        $(#[$attribute])*
+        #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+        pub struct $name {
+            start: usize,
+            end: usize,
+        }
+
+        impl $name {
+            /// Everything from `start` up to but not including `end`
+            pub fn new(start: $item, end: $item) -> Self {
+                let start: usize = start.into();
+                let end: usize = end.into();
+                assert!(start <= end, "Range ends before it starts");
+                $name {
+                    start: start,
+                    end: end,
+                }
+            }
+
+            /// `count` items starting at `start`
+            pub fn starting_at(start: $item, count: usize) -> Self {
+                let start: usize = start.into();
+                $name {
+                    start: start,
+                    end: start.checked_add(count).expect("Range overflowed"),
+                }
+            }
+
+            pub fn start(&self) -> $item {
+                $item::new(self.start)
+            }
+
+            /// First item after the range
+            pub fn end(&self) -> $item {
+                $item::new(self.end)
+            }
+
+            pub fn len(&self) -> usize {
+                self.end - self.start
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.start == self.end
+            }
+
+            pub fn contains(&self, item: $item) -> bool {
+                let item: usize = item.into();
+                self.start <= item && item < self.end
+            }
+
+            /// Splits into the items before `at` and the items from `at`
+            /// on. Either half is empty if `at` is outside the range.
+            pub fn split_at(&self, at: $item) -> ($name, $name) {
+                let at: usize = at.into();
+                let at = min(max(at, self.start), self.end);
+                ($name { start: self.start, end: at }, $name { start: at, end: self.end })
+            }
+
+            /// Items in both ranges, if there are any
+            pub fn intersection(&self, other: &$name) -> Option<$name> {
+                let start = max(self.start, other.start);
+                let end = min(self.end, other.end);
+                if start < end {
+                    Some($name { start: start, end: end })
+                } else {
+                    None
+                }
+            }
+        }
+
+        impl Iterator for $name {
+            type Item = $item;
+
+            fn next(&mut self) -> Option<$item> {
+                if self.start < self.end {
+                    self.start += 1;
+                    Some($item::new(self.start - 1))
+                } else {
+                    None
+                }
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                (self.len(), Some(self.len()))
+            }
+        }
+    };
+}
+
+range_type! {
+    /// Half open range of pages
+    pub struct PageRange(Page);
+}
+
+range_type! {
+    /// Half open range of frames
+    pub struct FrameRange(Frame);
+}
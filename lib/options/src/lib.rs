@@ -0,0 +1,176 @@
+//! `key=value` options, as the kernel command line and the loader's
+//! boot configuration file spell them
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+use core::str::FromStr;
+
+/// Whitespace separated arguments, each either `key=value` or a bare
+/// flag. There is no quoting, so values can't contain spaces.
+#[derive(Clone, Copy, Debug)]
+pub struct CommandLine<'a> {
+    text: &'a str,
+}
+
+/// One argument of a command line. Flags have no value.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Argument<'a> {
+    pub key: &'a str,
+    pub value: Option<&'a str>,
+}
+
+impl<'a> CommandLine<'a> {
+    pub fn new(text: &'a str) -> CommandLine<'a> {
+        CommandLine {
+            text: text,
+        }
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.text
+    }
+
+    pub fn arguments(&self) -> Arguments<'a> {
+        Arguments {
+            words: self.text.split_whitespace(),
+        }
+    }
+
+    /// Value of `key`. When it is given more than once the last one
+    /// wins, so options appended to a command line override it.
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.arguments()
+            .filter(|argument| argument.key == key)
+            .filter_map(|argument| argument.value)
+            .last()
+    }
+
+    /// Value of `key` parsed as a `T`, None if it isn't given
+    pub fn parse<T: FromStr>(&self, key: &str) -> Option<Result<T, T::Err>> {
+        self.get(key).map(|value| value.parse())
+    }
+
+    /// Whether `flag` is given on its own
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.arguments().any(|argument| argument.key == flag && argument.value.is_none())
+    }
+}
+
+pub struct Arguments<'a> {
+    words: core::str::SplitWhitespace<'a>,
+}
+
+impl<'a> Iterator for Arguments<'a> {
+    type Item = Argument<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.words.next().map(|word| {
+            match word.find('=') {
+                Some(index) => Argument {
+                    key: &word[..index],
+                    value: Some(&word[index + 1..]),
+                },
+                None => Argument {
+                    key: word,
+                    value: None,
+                },
+            }
+        })
+    }
+}
+
+/// A line of a configuration file that isn't a `key = value` entry
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ConfigError {
+    /// Counting from 1
+    pub line: usize,
+}
+
+/// Entries of a configuration file with one `key = value` per line.
+/// Blank lines and lines starting with `#` are skipped, and whitespace
+/// around keys and values is dropped.
+pub fn config_entries(text: &str) -> ConfigEntries {
+    ConfigEntries {
+        lines: text.lines().enumerate(),
+    }
+}
+
+pub struct ConfigEntries<'a> {
+    lines: core::iter::Enumerate<core::str::Lines<'a>>,
+}
+
+impl<'a> Iterator for ConfigEntries<'a> {
+    type Item = Result<(&'a str, &'a str), ConfigError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((index, line)) = self.lines.next() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            return Some(match line.find('=') {
+                Some(equals) => Ok((line[..equals].trim(), line[equals + 1..].trim())),
+                None => Err(ConfigError { line: index + 1 }),
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::{config_entries, Argument, CommandLine, ConfigError};
+
+    #[test]
+    fn arguments() {
+        let command_line = CommandLine::new("  quiet log_level=debug\tcpus=2 empty= ");
+        let arguments: Vec<_> = command_line.arguments().collect();
+        assert_eq!(arguments, [
+            Argument { key: "quiet", value: None },
+            Argument { key: "log_level", value: Some("debug") },
+            Argument { key: "cpus", value: Some("2") },
+            Argument { key: "empty", value: Some("") },
+        ]);
+        assert_eq!(CommandLine::new("").arguments().count(), 0);
+    }
+
+    #[test]
+    fn lookups() {
+        let command_line = CommandLine::new("quiet cpus=2 root=a=b cpus=4 cpus");
+        assert_eq!(command_line.get("cpus"), Some("4"));
+        assert_eq!(command_line.get("root"), Some("a=b"));
+        assert_eq!(command_line.get("quiet"), None);
+        assert_eq!(command_line.get("missing"), None);
+
+        assert_eq!(command_line.parse::<usize>("cpus"), Some(Ok(4)));
+        assert!(command_line.parse::<usize>("root").unwrap().is_err());
+        assert!(command_line.parse::<usize>("missing").is_none());
+
+        assert!(command_line.has_flag("quiet"));
+        assert!(command_line.has_flag("cpus"));
+        assert!(!command_line.has_flag("root"));
+    }
+
+    #[test]
+    fn config_files() {
+        let text = "# Boot configuration\n\
+                    kernel = EFI\\OS\\DEBUG.EFI\r\n\
+                    \n\
+                    cmdline = quiet cpus=1\n\
+                    \t# indented comment\n\
+                    serial_baud=115200\n";
+        let entries: Vec<_> = config_entries(text).collect();
+        assert_eq!(entries, [
+            Ok(("kernel", "EFI\\OS\\DEBUG.EFI")),
+            Ok(("cmdline", "quiet cpus=1")),
+            Ok(("serial_baud", "115200")),
+        ]);
+
+        let entries: Vec<_> = config_entries("cpus = 2\nnonsense\n").collect();
+        assert_eq!(entries, [Ok(("cpus", "2")), Err(ConfigError { line: 2 })]);
+    }
+}
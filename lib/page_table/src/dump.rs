@@ -0,0 +1,205 @@
+use core::cmp::Ordering;
+use core::fmt;
+use core::iter::Peekable;
+
+use ::mem::{PhysicalAddress, VirtualAddress};
+
+use super::{FrameSource, Mapping, Mappings, MappingFlags, PageSize, PageTable, PhysicalMemory};
+
+/// Pages that are contiguous both virtually and physically, and have
+/// the same size and flags
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MappingRun {
+    pub virtual_start: VirtualAddress,
+    pub physical_start: PhysicalAddress,
+    /// Length of the run in bytes
+    pub length: usize,
+    pub page_size: PageSize,
+    pub flags: MappingFlags,
+}
+
+impl MappingRun {
+    pub fn virtual_end(&self) -> VirtualAddress {
+        let start: usize = self.virtual_start.into();
+        VirtualAddress::new(start + self.length)
+    }
+
+    pub fn physical_end(&self) -> PhysicalAddress {
+        let start: usize = self.physical_start.into();
+        PhysicalAddress::new(start + self.length)
+    }
+
+    fn extends_to(&self, mapping: &Mapping) -> bool {
+        self.page_size == mapping.page_size &&
+            self.flags == mapping.flags &&
+            self.virtual_end() == mapping.virtual_address &&
+            self.physical_end() == mapping.physical_address
+    }
+}
+
+impl From<Mapping> for MappingRun {
+    fn from(mapping: Mapping) -> Self {
+        MappingRun {
+            virtual_start: mapping.virtual_address,
+            physical_start: mapping.physical_address,
+            length: mapping.page_size as usize,
+            page_size: mapping.page_size,
+            flags: mapping.flags,
+        }
+    }
+}
+
+impl fmt::Display for MappingRun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let virtual_start: usize = self.virtual_start.into();
+        let virtual_end: usize = self.virtual_end().into();
+        let physical_start: usize = self.physical_start.into();
+        let physical_end: usize = self.physical_end().into();
+        let page_size = match self.page_size {
+            PageSize::FourKb => "4K",
+            PageSize::TwoMb => "2M",
+            PageSize::OneGb => "1G",
+        };
+        write!(f, "{:016x}-{:016x} -> {:012x}-{:012x} {} {}",
+            virtual_start, virtual_end, physical_start, physical_end, page_size, self.flags)
+    }
+}
+
+/// `r`, then `w`, `x` and `u` or `s` for supervisor, followed by any
+/// caching or global bits
+impl fmt::Display for MappingFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "r{}{}{}",
+            if self.writable { "w" } else { "-" },
+            if self.no_execute { "-" } else { "x" },
+            if self.user { "u" } else { "s" })?;
+        if self.global {
+            write!(f, " G")?;
+        }
+        if self.write_through {
+            write!(f, " PWT")?;
+        }
+        if self.cache_disable {
+            write!(f, " PCD")?;
+        }
+        if self.pat {
+            write!(f, " PAT")?;
+        }
+        Ok(())
+    }
+}
+
+/// Coalesces the mappings of a table into runs
+pub struct MappingRuns<'a, M: PhysicalMemory + 'a> {
+    mappings: Peekable<Mappings<'a, M>>,
+}
+
+impl<'a, M: PhysicalMemory> Iterator for MappingRuns<'a, M> {
+    type Item = MappingRun;
+
+    fn next(&mut self) -> Option<MappingRun> {
+        let mut run: MappingRun = match self.mappings.next() {
+            Some(mapping) => mapping.into(),
+            None => return None,
+        };
+        loop {
+            match self.mappings.peek() {
+                Some(mapping) if run.extends_to(mapping) => run.length += mapping.page_size as usize,
+                _ => return Some(run),
+            }
+            self.mappings.next();
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Difference {
+    OnlyInFirst(MappingRun),
+    OnlyInSecond(MappingRun),
+}
+
+/// Runs that are not identical in two tables, in order of virtual
+/// address
+pub struct Differences<'a, 'b, M: PhysicalMemory + 'a, N: PhysicalMemory + 'b> {
+    first: Peekable<MappingRuns<'a, M>>,
+    second: Peekable<MappingRuns<'b, N>>,
+}
+
+impl<'a, 'b, M: PhysicalMemory, N: PhysicalMemory> Iterator for Differences<'a, 'b, M, N> {
+    type Item = Difference;
+
+    fn next(&mut self) -> Option<Difference> {
+        loop {
+            let order = match (self.first.peek(), self.second.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(first), Some(second)) => {
+                    if first == second {
+                        Ordering::Equal
+                    } else {
+                        let first_start: usize = first.virtual_start.into();
+                        let second_start: usize = second.virtual_start.into();
+                        if first_start <= second_start { Ordering::Less } else { Ordering::Greater }
+                    }
+                },
+            };
+            match order {
+                Ordering::Less => return self.first.next().map(Difference::OnlyInFirst),
+                Ordering::Greater => return self.second.next().map(Difference::OnlyInSecond),
+                Ordering::Equal => {
+                    self.first.next();
+                    self.second.next();
+                },
+            }
+        }
+    }
+}
+
+impl<A: FrameSource, M: PhysicalMemory> PageTable<A, M> {
+    /// Every present mapping, with contiguous pages merged into runs
+    pub fn mapping_runs(&self) -> MappingRuns<M> {
+        MappingRuns {
+            mappings: self.mappings().peekable(),
+        }
+    }
+
+    /// Runs mapped differently in `self` and `other`
+    pub fn differences<'a, 'b, B, N>(&'a self, other: &'b PageTable<B, N>) -> Differences<'a, 'b, M, N>
+        where B: FrameSource, N: PhysicalMemory {
+        Differences {
+            first: self.mapping_runs().peekable(),
+            second: other.mapping_runs().peekable(),
+        }
+    }
+
+    pub fn write_mappings<W: fmt::Write>(&self, writer: &mut W) -> fmt::Result {
+        for run in self.mapping_runs() {
+            writeln!(writer, "{}", run)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the runs only in `self` prefixed with `-`, and the runs
+    /// only in `other` prefixed with `+`
+    pub fn write_differences<W, B, N>(&self, other: &PageTable<B, N>, writer: &mut W) -> fmt::Result
+        where W: fmt::Write, B: FrameSource, N: PhysicalMemory {
+        for difference in self.differences(other) {
+            match difference {
+                Difference::OnlyInFirst(run) => writeln!(writer, "- {}", run)?,
+                Difference::OnlyInSecond(run) => writeln!(writer, "+ {}", run)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints every mapping run over serial
+    pub fn print_mappings(&self) {
+        let _ = self.write_mappings(&mut *::serial::SERIAL_WRITER.lock());
+    }
+
+    /// Prints how `other` differs from `self` over serial
+    pub fn print_differences<B: FrameSource, N: PhysicalMemory>(&self, other: &PageTable<B, N>) {
+        let _ = self.write_differences(other, &mut *::serial::SERIAL_WRITER.lock());
+    }
+}
@@ -5,27 +5,159 @@ extern crate x86;
 
 extern crate mem;
 extern crate frame_allocator as falloc;
+extern crate serial;
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
 
 
 use core::ptr::Unique;
 
-#[derive(Clone, Copy)]
+/// Walking, printing and comparing the mappings of a table
+pub mod dump;
+
+/// Physical address bits of a 4KB page or a page table entry
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+/// Physical address bits of a 2MB page entry. Bit 12 is the PAT bit.
+const TWO_MB_ADDRESS_MASK: u64 = 0x000F_FFFF_FFE0_0000;
+/// Physical address bits of a 1GB page entry. Bit 12 is the PAT bit.
+const ONE_GB_ADDRESS_MASK: u64 = 0x000F_FFFF_C000_0000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum PageSize {
     FourKb = 0x1000,
     TwoMb = 0x2_00000,
     OneGb = 0x400_00000,
 }
 
-pub struct PageTable {
+/// Source of the frames that hold page tables
+pub trait FrameSource {
+    fn allocate_frame(&mut self) -> Option<::mem::Frame>;
+    fn free_frame(&mut self, frame: ::mem::Frame);
+
+    /// Releases `count` frames that were mapped by a page, when a
+    /// table that owns its leaf frames is dropped
+    fn free_leaf_frames(&mut self, frame: ::mem::Frame, count: usize) {
+        let first: usize = frame.into();
+        for frame_number in first..first + count {
+            self.free_frame(::mem::Frame::new(frame_number));
+        }
+    }
+}
+
+/// Finds where physical memory can be accessed. Table entries hold
+/// physical addresses, so every step of a walk goes through this.
+pub trait PhysicalMemory {
+    fn to_virtual(&self, address: ::mem::PhysicalAddress) -> ::mem::VirtualAddress;
+}
+
+/// Takes page table frames from the global `FRAME_ALLOCATOR`
+#[derive(Clone, Copy, Default)]
+pub struct GlobalFrameAllocator;
+
+impl FrameSource for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<::mem::Frame> {
+        unsafe { ::falloc::FRAME_ALLOCATOR.get_frame(::falloc::FrameUsage::PageTable) }.ok()
+    }
+
+    fn free_frame(&mut self, frame: ::mem::Frame) {
+        unsafe { ::falloc::FRAME_ALLOCATOR.free_frame(frame, ::falloc::FrameUsage::PageTable) }
+            .expect("Freed a page table frame twice");
+    }
+
+    /// Leaf frames of an address space are expected to have been
+    /// allocated as `FrameUsage::User`
+    fn free_leaf_frames(&mut self, frame: ::mem::Frame, count: usize) {
+        unsafe { ::falloc::FRAME_ALLOCATOR.free_frames(frame, count, ::falloc::FrameUsage::User) }
+            .expect("Freed a user frame twice");
+    }
+}
+
+/// Physical memory is mapped at the same virtual address, as it is
+/// under the loader's identity map
+#[derive(Clone, Copy, Default)]
+pub struct IdentityMapped;
+
+impl PhysicalMemory for IdentityMapped {
+    fn to_virtual(&self, address: ::mem::PhysicalAddress) -> ::mem::VirtualAddress {
+        ::mem::VirtualAddress::new(address.into())
+    }
+}
+
+/// Physical memory is mapped at a fixed offset, like a higher half
+/// direct map
+#[derive(Clone, Copy)]
+pub struct OffsetMapped {
+    offset: usize,
+}
+
+impl OffsetMapped {
+    pub fn new(offset: ::mem::VirtualAddress) -> Self {
+        OffsetMapped {
+            offset: offset.into(),
+        }
+    }
+}
+
+impl PhysicalMemory for OffsetMapped {
+    fn to_virtual(&self, address: ::mem::PhysicalAddress) -> ::mem::VirtualAddress {
+        let address: usize = address.into();
+        ::mem::VirtualAddress::new(self.offset + address)
+    }
+}
+
+/// Physical memory is reached through the loader's direct map at
+/// `mem::direct_map_offset()`
+#[derive(Clone, Copy, Default)]
+pub struct DirectMapped;
+
+impl PhysicalMemory for DirectMapped {
+    fn to_virtual(&self, address: ::mem::PhysicalAddress) -> ::mem::VirtualAddress {
+        address.to_direct_map()
+    }
+}
+
+/// Pages of low memory the loader identity maps, for itself and the
+/// AP trampoline. The kernel unmaps them once the APs are up.
+pub const LOW_IDENTITY_PAGES: usize = 0x1000;
+
+/// First PML4 entry of the higher half
+pub const KERNEL_PML4_START: usize = 256;
+/// PML4 entry of the 512GB region the kernel image is loaded in
+pub const KERNEL_IMAGE_PML4_ENTRY: usize = 1;
+/// Start of the kernel image region, 0x8000000000
+pub const KERNEL_IMAGE_START: usize = KERNEL_IMAGE_PML4_ENTRY << 39;
+
+/// Whether a PML4 entry belongs to the kernel half that every address
+/// space shares
+pub fn is_kernel_entry(index: usize) -> bool {
+    index == KERNEL_IMAGE_PML4_ENTRY || index >= KERNEL_PML4_START
+}
+
+/// A PML4 and every table below it. A table made by `new_in` or
+/// `new_address_space_from` owns them, and dropping it frees the
+/// tables, except those shared with the kernel table it was created
+/// from, and the mapped frames too if `set_free_leaf_frames` was set.
+/// A table adopted with `new` or `from_frame`, such as the one in CR3,
+/// is never freed.
+pub struct PageTable<A: FrameSource = GlobalFrameAllocator, M: PhysicalMemory = IdentityMapped> {
     pml4: Unique<level4::PageMap>,
+    pml4_frame: ::mem::Frame,
+    allocator: A,
+    memory: M,
+    shares_kernel_half: bool,
+    free_leaf_frames: bool,
+    /// Whether dropping the table frees it
+    owned: bool,
 }
 
+/// The kernel's table once the direct map is live
+pub type KernelPageTable = PageTable<GlobalFrameAllocator, DirectMapped>;
+
 impl PageTable {
     pub unsafe fn new(frame: ::mem::Frame) -> PageTable {
-        let mut physical_address: ::mem::PhysicalAddress = frame.into();
-        let mut result = PageTable {
-            pml4: Unique::new(physical_address.as_mut_ptr() as *mut level4::PageMap),
-        };
+        let mut result = PageTable::from_frame(frame, GlobalFrameAllocator, IdentityMapped);
         #[cfg(feature = "loader")]
         {
             *result.pml4.get_mut() = level4::PageMap::new();
@@ -35,28 +167,283 @@ impl PageTable {
         }
         result
     }
+}
 
-    pub fn insert_page(&mut self, frame: ::mem::Frame, page: ::mem::Page, page_size: PageSize) {
+impl<A: FrameSource, M: PhysicalMemory> PageTable<A, M> {
+    /// Allocates an empty PML4 from `allocator`
+    pub fn new_in(mut allocator: A, memory: M) -> PageTable<A, M> {
+        let frame = allocate_table_frame(&mut allocator);
         unsafe {
-            (**self.pml4).insert_page(frame, page, page_size);
+            let mut result = PageTable::from_frame(frame, allocator, memory);
+            *result.pml4.get_mut() = level4::PageMap::new();
+            result.owned = true;
+            result
+        }
+    }
+
+    /// Uses the PML4 in `frame` as it is, without taking ownership of
+    /// it. Unsafe as the frame has to hold a valid table reachable
+    /// through `memory`.
+    pub unsafe fn from_frame(frame: ::mem::Frame, allocator: A, memory: M) -> PageTable<A, M> {
+        let pml4: &mut level4::PageMap = table_at(&memory, frame.into());
+        PageTable {
+            pml4: Unique::new(pml4 as *mut level4::PageMap),
+            pml4_frame: frame,
+            allocator: allocator,
+            memory: memory,
+            shares_kernel_half: false,
+            free_leaf_frames: false,
+            owned: false,
+        }
+    }
+
+    /// Creates an address space with an empty user half whose kernel
+    /// half is shared with `kernel_table`. Only the PML4 entries are
+    /// copied, so kernel mappings made later are seen by both tables
+    /// as long as they fall under an entry that was already present.
+    pub fn new_address_space_from(kernel_table: &PageTable<A, M>) -> PageTable<A, M>
+        where A: Clone, M: Clone {
+        let mut result = PageTable::new_in(kernel_table.allocator.clone(), kernel_table.memory.clone());
+        {
+            let source = kernel_table.entries(kernel_table.pml4_frame);
+            let destination = result.entries(result.pml4_frame);
+            for index in 0..0x200 {
+                if is_kernel_entry(index) {
+                    destination[index] = source[index];
+                }
+            }
+        }
+        result.shares_kernel_half = true;
+        result
+    }
+
+    /// Switches how the tables are reached, e.g. to the direct map once
+    /// it has been loaded. Unsafe as `memory` has to reach every table.
+    pub unsafe fn with_physical_memory<N: PhysicalMemory>(self, memory: N) -> PageTable<A, N> {
+        let allocator = ::core::ptr::read(&self.allocator);
+        ::core::mem::drop(::core::ptr::read(&self.memory));
+        let mut result = PageTable::from_frame(self.pml4_frame, allocator, memory);
+        result.shares_kernel_half = self.shares_kernel_half;
+        result.free_leaf_frames = self.free_leaf_frames;
+        result.owned = self.owned;
+        ::core::mem::forget(self);
+        result
+    }
+
+    /// Whether dropping the table also frees the frames it maps
+    pub fn set_free_leaf_frames(&mut self, free_leaf_frames: bool) {
+        self.free_leaf_frames = free_leaf_frames;
+    }
+
+    /// Every present mapping, in order of virtual address
+    pub fn mappings(&self) -> Mappings<M> {
+        Mappings {
+            memory: &self.memory,
+            pml4: self.pml4_frame.into(),
+            indices: [0; 4],
+            done: false,
+        }
+    }
+
+    fn entries(&self, table: ::mem::Frame) -> &mut [u64; 0x200] {
+        unsafe { table_at(&self.memory, table.into()) }
+    }
+
+    /// Frees the table in `table` at `level`, where the PML4 is level 0,
+    /// along with every table below it
+    fn free_table(&mut self, table: ::mem::Frame, level: usize) {
+        for index in 0..0x200 {
+            let entry = self.entries(table)[index];
+            if entry & 0x1 == 0 || (level == 0 && self.shares_kernel_half && is_kernel_entry(index)) {
+                continue;
+            }
+            match leaf_size(level, entry) {
+                Some(page_size) => {
+                    if self.free_leaf_frames {
+                        let frame = leaf_frame(entry, page_size);
+                        self.allocator.free_leaf_frames(frame, page_size as usize / 0x1000);
+                    }
+                },
+                None => {
+                    let next = ::mem::Frame::new(((entry & ADDRESS_MASK) >> 12) as usize);
+                    self.free_table(next, level + 1);
+                },
+            }
+        }
+        self.allocator.free_frame(table);
+    }
+
+    pub fn insert_page(&mut self, frame: ::mem::Frame, page: ::mem::Page, page_size: PageSize, flags: MappingFlags) {
+        unsafe {
+            (**self.pml4).insert_page(frame, page, page_size, flags, &mut self.allocator, &self.memory);
+        }
+    }
+
+    /// Removes the mapping for `page`, returning the frame it was
+    /// mapped to. If `page` is the first page of a 2MB or 1GB entry the
+    /// whole entry is removed and its first frame returned. Any other
+    /// page in one is split out of it, the rest of the entry being
+    /// remapped with smaller pages, which gives `None` if there is no
+    /// frame left for the tables that takes.
+    pub fn unmap_page(&mut self, page: ::mem::Page) -> Option<::mem::Frame> {
+        let result = unsafe {
+            (**self.pml4).unmap_page(page, &mut self.allocator, &self.memory)
+        };
+        if result.is_some() {
+            flush_page(page);
         }
+        result
+    }
+
+    /// Walks the table to find the physical address `address` maps
+    /// to, along with the size and flags of the mapping
+    pub fn translate(&self, address: ::mem::VirtualAddress) -> Option<(::mem::PhysicalAddress, PageSize, MappingFlags)> {
+        unsafe {
+            (**self.pml4).translate(address, &self.memory)
+        }.map(|(physical_address, page_size, entry)| {
+            (physical_address, page_size, MappingFlags::from_entry(entry, page_size))
+        })
     }
 
     pub fn load(&self) {
         unsafe {
-            let cr3 = ::x86::shared::control_regs::cr3();
-            ::x86::shared::control_regs::cr3_write(*self.pml4 as usize);
+            enable_no_execute();
+            let physical_address: ::mem::PhysicalAddress = self.pml4_frame.into();
+            ::x86::shared::control_regs::cr3_write(physical_address.into());
         }
     }
 
+    /// Frame holding the PML4, which is what CR3 points at
+    pub fn pml4_frame(&self) -> ::mem::Frame {
+        self.pml4_frame
+    }
+
     pub fn physical_address(&self) -> u32 {
-        (*self.pml4 as usize) as u32
+        let physical_address: ::mem::PhysicalAddress = self.pml4_frame.into();
+        let physical_address: usize = physical_address.into();
+        physical_address as u32
+    }
+}
+
+impl<A: FrameSource, M: PhysicalMemory> Drop for PageTable<A, M> {
+    fn drop(&mut self) {
+        if self.owned {
+            let pml4_frame = self.pml4_frame;
+            self.free_table(pml4_frame, 0);
+        }
+    }
+}
+
+/// A single page mapping found by walking a table
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Mapping {
+    pub virtual_address: ::mem::VirtualAddress,
+    pub physical_address: ::mem::PhysicalAddress,
+    pub page_size: PageSize,
+    pub flags: MappingFlags,
+}
+
+/// Iterator over the mappings of a `PageTable`
+pub struct Mappings<'a, M: PhysicalMemory + 'a> {
+    memory: &'a M,
+    pml4: ::mem::PhysicalAddress,
+    /// Index into the PML4, PDPT, PD and PT of the next entry to look at
+    indices: [usize; 4],
+    done: bool,
+}
+
+impl<'a, M: PhysicalMemory> Mappings<'a, M> {
+    /// Moves past the entry at `level`, and everything under it
+    fn advance(&mut self, level: usize) {
+        for lower in level + 1..4 {
+            self.indices[lower] = 0;
+        }
+        let mut level = level;
+        loop {
+            self.indices[level] += 1;
+            if self.indices[level] < 0x200 {
+                return;
+            }
+            self.indices[level] = 0;
+            if level == 0 {
+                self.done = true;
+                return;
+            }
+            level -= 1;
+        }
+    }
+
+    fn virtual_address(&self) -> ::mem::VirtualAddress {
+        let address = (self.indices[0] << 39) | (self.indices[1] << 30) |
+            (self.indices[2] << 21) | (self.indices[3] << 12);
+        // Sign extend bit 47 to make the address canonical
+        if address & (1 << 47) != 0 {
+            ::mem::VirtualAddress::new(address | 0xFFFF_0000_0000_0000)
+        } else {
+            ::mem::VirtualAddress::new(address)
+        }
+    }
+}
+
+impl<'a, M: PhysicalMemory> Iterator for Mappings<'a, M> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        while !self.done {
+            let mut table = self.pml4;
+            let mut level = 0;
+            loop {
+                let entry = {
+                    let entries: &[u64; 0x200] = unsafe { table_at(self.memory, table) };
+                    entries[self.indices[level]]
+                };
+                if entry & 0x1 == 0 {
+                    self.advance(level);
+                    break;
+                }
+                if let Some(page_size) = leaf_size(level, entry) {
+                    let result = Mapping {
+                        virtual_address: self.virtual_address(),
+                        physical_address: leaf_frame(entry, page_size).into(),
+                        page_size: page_size,
+                        flags: MappingFlags::from_entry(entry, page_size),
+                    };
+                    self.advance(level);
+                    return Some(result);
+                }
+                table = ::mem::PhysicalAddress::new((entry & ADDRESS_MASK) as usize);
+                level += 1;
+            }
+        }
+        None
+    }
+}
+
+/// Size of the page a present entry maps, or `None` if it points to
+/// another table. The PML4 is level 0.
+fn leaf_size(level: usize, entry: u64) -> Option<PageSize> {
+    let page_size_bit = (entry >> 7) & 0x1 == 0x1;
+    match (level, page_size_bit) {
+        (1, true) => Some(PageSize::OneGb),
+        (2, true) => Some(PageSize::TwoMb),
+        (3, _) => Some(PageSize::FourKb),
+        (_, _) => None,
     }
 }
 
+fn leaf_frame(entry: u64, page_size: PageSize) -> ::mem::Frame {
+    let mask = match page_size {
+        PageSize::FourKb => ADDRESS_MASK,
+        PageSize::TwoMb => TWO_MB_ADDRESS_MASK,
+        PageSize::OneGb => ONE_GB_ADDRESS_MASK,
+    };
+    ::mem::Frame::new(((entry & mask) >> 12) as usize)
+}
+
 mod level4 {
-    use ::mem::{Frame, Page};
-    use super::{level3, PageEntryBuilder, ReadWrite, PageSize};
+    use ::mem::{Frame, Page, PhysicalAddress, VirtualAddress};
+    use super::{level3, PageSize, MappingFlags, table_entry_bits};
+    use super::{FrameSource, PhysicalMemory, allocate_table_frame, table_at};
 
     pub struct PageMap {
         table: [GenericPageEntry; 0x200],
@@ -69,7 +456,8 @@ mod level4 {
             }
         }
 
-        pub fn insert_page(&mut self, frame: Frame, page: Page, page_size: PageSize) {
+        pub fn insert_page<A, M>(&mut self, frame: Frame, page: Page, page_size: PageSize, flags: MappingFlags, allocator: &mut A, memory: &M)
+            where A: FrameSource, M: PhysicalMemory {
             let index = {
                 let virtual_address: ::mem::VirtualAddress = page.into();
                 let virtual_address: usize = virtual_address.into();
@@ -77,17 +465,42 @@ mod level4 {
             };
             if let PageEntryType::NotPresent(_) = self.table[index].as_enum() {
                 // Insert new page map level 3
-                let frame = unsafe { ::falloc::FRAME_ALLOCATOR.get_frame() };
-                let physical_address: ::mem::PhysicalAddress = frame.into();
-                let ptr = physical_address.as_ptr() as *mut level3::PageMap;
-                unsafe {
-                    *ptr = Default::default();
-                }
+                let frame = allocate_table_frame(allocator);
+                let map: &mut level3::PageMap = unsafe { table_at(memory, frame.into()) };
+                *map = Default::default();
+
+                self.table[index] = PageTableEntry::new(frame, flags).into();
+            } else {
+                // Make sure the table doesn't restrict the new mapping
+                self.table[index].entry |= table_entry_bits(flags);
+            }
 
-                self.table[index] = PageTableEntry::new(frame).into();
+            self.table[index].get_map(memory).insert_page(frame, page, page_size, flags, allocator, memory);
+        }
+
+        pub fn unmap_page<A, M>(&mut self, page: Page, allocator: &mut A, memory: &M) -> Option<Frame>
+            where A: FrameSource, M: PhysicalMemory {
+            let index = {
+                let virtual_address: ::mem::VirtualAddress = page.into();
+                let virtual_address: usize = virtual_address.into();
+                (virtual_address >> 39) & 0x1FF
+            };
+            match self.table[index].as_enum() {
+                PageEntryType::NotPresent(_) => None,
+                PageEntryType::PageTableEntry(_) => self.table[index].get_map(memory).unmap_page(page, allocator, memory),
             }
+        }
 
-            self.table[index].get_map().insert_page(frame, page, page_size);
+        pub fn translate<M: PhysicalMemory>(&self, address: VirtualAddress, memory: &M) -> Option<(PhysicalAddress, PageSize, u64)> {
+            let index = {
+                let virtual_address: usize = address.into();
+                (virtual_address >> 39) & 0x1FF
+            };
+            let entry = self.table[index];
+            match entry.as_enum() {
+                PageEntryType::NotPresent(_) => None,
+                PageEntryType::PageTableEntry(_) => entry.get_map(memory).translate(address, memory),
+            }
         }
     }
 
@@ -118,10 +531,9 @@ mod level4 {
             (self.entry & 0x1) == 0x1
         }
 
-        pub fn get_map(&mut self) -> &mut level3::PageMap {
-            let mut physical_address = ::mem::PhysicalAddress::new(self.entry as usize & (0xFFFFFFFFFF << 12));
-            let ptr = physical_address.as_mut_ptr() as *mut level3::PageMap;
-            unsafe { ::core::mem::transmute(ptr) }
+        pub fn get_map<M: PhysicalMemory>(&self, memory: &M) -> &mut level3::PageMap {
+            let physical_address = PhysicalAddress::new(self.entry as usize & (0xFFFFFFFFFF << 12));
+            unsafe { table_at(memory, physical_address) }
         }
     }
 
@@ -147,12 +559,8 @@ mod level4 {
     }
 
     impl PageTableEntry {
-        pub fn new(frame: Frame) -> Self {
-            let mut entry: u64 = 
-                (PageEntryBuilder {
-                    read_write: ReadWrite::Writeable,
-                    ..Default::default()
-                }).to_entry();
+        pub fn new(frame: Frame, flags: MappingFlags) -> Self {
+            let mut entry: u64 = table_entry_bits(flags);
             entry |= 0x1 << 0;
             let frame_number: usize = frame.into();
             entry |= (frame_number as u64 & 0xFFFFF_FFFFF) << 12;
@@ -172,15 +580,18 @@ mod level4 {
 }
 
 mod level3 {
-    use ::mem::{Frame, Page};
-    use super::{level2, PageEntryBuilder, ReadWrite, PageSize};
+    use ::mem::{Frame, Page, PhysicalAddress, VirtualAddress};
+    use super::{level2, PageEntryBuilder, PageSize, MappingFlags, table_entry_bits};
+    use super::{FrameSource, PhysicalMemory, allocate_table_frame, table_at};
+    use super::ONE_GB_ADDRESS_MASK;
 
     pub struct PageMap {
         table: [GenericPageEntry; 0x200],
     }
 
     impl PageMap {
-        pub fn insert_page(&mut self, frame: Frame, page: Page, page_size: PageSize) {
+        pub fn insert_page<A, M>(&mut self, frame: Frame, page: Page, page_size: PageSize, flags: MappingFlags, allocator: &mut A, memory: &M)
+            where A: FrameSource, M: PhysicalMemory {
             let index = {
                 let virtual_address: ::mem::VirtualAddress = page.into();
                 let virtual_address: usize = virtual_address.into();
@@ -188,7 +599,10 @@ mod level3 {
             };
             match (page_size, self.table[index].as_enum()) {
                 (PageSize::OneGb, PageEntryType::NotPresent(_)) => {
-                    self.table[index] = PageEntry::new(frame).into();
+                    if usize::from(page) & 0x3FFFF != 0 {
+                        panic!("Page isn't aligned for a 1gb page {:?}", page);
+                    }
+                    self.table[index] = PageEntry::new(frame, flags).into();
                 },
                 (PageSize::OneGb, PageEntryType::PageTableEntry(_)) => {
                     panic!("Can't insert a 1gb page. There's already a page table here");
@@ -199,28 +613,88 @@ mod level3 {
                 (_, PageEntryType::NotPresent(_)) => {
                     // Insert a new page table entry
                     let entry = {
-                        let frame = unsafe { ::falloc::FRAME_ALLOCATOR.get_frame() };
-                        let physical_address: ::mem::PhysicalAddress = frame.into();
-                        let entry = PageTableEntry::new(frame);
+                        let frame = allocate_table_frame(allocator);
+                        let entry = PageTableEntry::new(frame, flags);
                         self.table[index] = entry.into();
 
-                        let ptr = physical_address.as_ptr() as *mut level2::PageMap;
-                        unsafe {
-                            *ptr = Default::default();
-                        }
+                        let map: &mut level2::PageMap = unsafe { table_at(memory, frame.into()) };
+                        *map = Default::default();
                         entry
                     };
                         
-                    entry.get_map().insert_page(frame, page, page_size);
+                    entry.get_map(memory).insert_page(frame, page, page_size, flags, allocator, memory);
                 },
                 (_, PageEntryType::PageTableEntry(entry)) => {
-                    entry.get_map().insert_page(frame, page, page_size);
+                    self.table[index].entry |= table_entry_bits(flags);
+                    entry.get_map(memory).insert_page(frame, page, page_size, flags, allocator, memory);
                 },
                 (_, PageEntryType::PageEntry(_)) => {
                     panic!("Can't insert a smaller page. There's already a 1gb page here.");
                 },
             };
         }
+
+        pub fn unmap_page<A, M>(&mut self, page: Page, allocator: &mut A, memory: &M) -> Option<Frame>
+            where A: FrameSource, M: PhysicalMemory {
+            let virtual_address: ::mem::VirtualAddress = page.into();
+            let virtual_address: usize = virtual_address.into();
+            let index = (virtual_address >> 30) & 0x1FF;
+            match self.table[index].as_enum() {
+                PageEntryType::NotPresent(_) => None,
+                PageEntryType::PageTableEntry(entry) => entry.get_map(memory).unmap_page(page, allocator, memory),
+                PageEntryType::PageEntry(entry) => {
+                    if virtual_address & (PageSize::OneGb as usize - 1) == 0 {
+                        self.table[index] = Default::default();
+                        return Some(entry.frame());
+                    }
+                    if !self.split(index, entry, allocator, memory) {
+                        return None;
+                    }
+                    match self.table[index].as_enum() {
+                        PageEntryType::PageTableEntry(entry) => {
+                            entry.get_map(memory).unmap_four_kb_page(page, allocator, memory)
+                        },
+                        _ => None,
+                    }
+                },
+            }
+        }
+
+        /// Replaces the 1GB page `entry` at `index` with a table mapping
+        /// the same memory with 2MB pages. False if there is no frame
+        /// for the table.
+        fn split<A, M>(&mut self, index: usize, entry: PageEntry, allocator: &mut A, memory: &M) -> bool
+            where A: FrameSource, M: PhysicalMemory {
+            let flags = MappingFlags::from_entry(entry.entry, PageSize::OneGb);
+            let table_frame = match allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            let map: &mut level2::PageMap = unsafe { table_at(memory, table_frame.into()) };
+            *map = Default::default();
+            let first: usize = entry.frame().into();
+            for offset in 0..0x200 {
+                map.insert_page(Frame::new(first + (offset << 9)), Page::new(offset << 9),
+                                PageSize::TwoMb, flags, allocator, memory);
+            }
+            self.table[index] = PageTableEntry::new(table_frame, flags).into();
+            true
+        }
+
+        pub fn translate<M: PhysicalMemory>(&self, address: VirtualAddress, memory: &M) -> Option<(PhysicalAddress, PageSize, u64)> {
+            let virtual_address: usize = address.into();
+            let index = (virtual_address >> 30) & 0x1FF;
+            match self.table[index].as_enum() {
+                PageEntryType::NotPresent(_) => None,
+                PageEntryType::PageTableEntry(entry) => entry.get_map(memory).translate(address, memory),
+                PageEntryType::PageEntry(entry) => {
+                    let frame_address: PhysicalAddress = entry.frame().into();
+                    let frame_address: usize = frame_address.into();
+                    let offset = virtual_address & (PageSize::OneGb as usize - 1);
+                    Some((PhysicalAddress::new(frame_address + offset), PageSize::OneGb, entry.entry))
+                },
+            }
+        }
     }
 
     impl Default for PageMap {
@@ -295,12 +769,8 @@ mod level3 {
     }
 
     impl PageTableEntry {
-        pub fn new(frame: Frame) -> Self {
-            let mut entry: u64 = 
-                (PageEntryBuilder {
-                    read_write: ReadWrite::Writeable,
-                    ..Default::default()
-                }).to_entry();
+        pub fn new(frame: Frame, flags: MappingFlags) -> Self {
+            let mut entry: u64 = table_entry_bits(flags);
             entry |= 0x1 << 0;
             let frame_number: usize = frame.into();
             entry |= (frame_number as u64 & 0xFFFFF_FFFFF) << 12;
@@ -309,10 +779,9 @@ mod level3 {
             }
         }
 
-        pub fn get_map(&self) -> &mut level2::PageMap {
-            let mut physical_address = ::mem::PhysicalAddress::new(self.entry as usize & (0xFFFFFFFFFF << 12));
-            let ptr = physical_address.as_mut_ptr() as *mut level2::PageMap;
-            unsafe { ::core::mem::transmute(ptr) }
+        pub fn get_map<M: PhysicalMemory>(&self, memory: &M) -> &mut level2::PageMap {
+            let physical_address = PhysicalAddress::new(self.entry as usize & (0xFFFFFFFFFF << 12));
+            unsafe { table_at(memory, physical_address) }
         }
     }
 
@@ -330,20 +799,24 @@ mod level3 {
     }
 
     impl PageEntry {
-        pub fn new(frame: Frame) -> Self {
-            let mut entry: u64 = 
-                (PageEntryBuilder {
-                    read_write: ReadWrite::Writeable,
-                    ..Default::default()
-                }).to_entry();
+        pub fn new(frame: Frame, flags: MappingFlags) -> Self {
+            let frame_number: usize = frame.into();
+            if frame_number & 0x3FFFF != 0 {
+                panic!("Frame isn't aligned for a 1gb page {:?}", frame);
+            }
+            let mut entry: u64 = PageEntryBuilder::from(flags).to_entry();
             entry |= 0x1 << 0;
             entry |= 0x1 << 7;
-            let frame_number: usize = frame.into();
-            entry |= (frame_number as u64 & 0x3FFFFF << 18) << 12;
+            entry |= (flags.pat as u64) << 12;
+            entry |= ((frame_number as u64) << 12) & ONE_GB_ADDRESS_MASK;
             PageEntry {
                 entry: entry,
             }
         }
+
+        pub fn frame(&self) -> Frame {
+            Frame::new(((self.entry & ONE_GB_ADDRESS_MASK) >> 12) as usize)
+        }
     }
 
     impl From<PageEntry> for GenericPageEntry {
@@ -356,8 +829,10 @@ mod level3 {
 }
 
 mod level2 {
-    use ::mem::{Frame, Page};
-    use super::{level1, PageEntryBuilder, ReadWrite, PageSize};
+    use ::mem::{Frame, Page, PhysicalAddress, VirtualAddress};
+    use super::{level1, PageEntryBuilder, PageSize, MappingFlags, table_entry_bits};
+    use super::{FrameSource, PhysicalMemory, allocate_table_frame, table_at};
+    use super::TWO_MB_ADDRESS_MASK;
 
     pub struct PageMap {
         table: [GenericPageEntry; 0x200],
@@ -372,7 +847,8 @@ mod level2 {
     }
 
     impl PageMap {
-        pub fn insert_page(&mut self, frame: Frame, page: Page, page_size: PageSize) {
+        pub fn insert_page<A, M>(&mut self, frame: Frame, page: Page, page_size: PageSize, flags: MappingFlags, allocator: &mut A, memory: &M)
+            where A: FrameSource, M: PhysicalMemory {
             let index = {
                 let virtual_address: ::mem::VirtualAddress = page.into();
                 let virtual_address: usize = virtual_address.into();
@@ -380,7 +856,10 @@ mod level2 {
             };
             match (page_size, self.table[index].as_enum()) {
                 (PageSize::TwoMb, PageEntryType::NotPresent(_)) => {
-                    self.table[index] = PageEntry::new(frame).into();
+                    if usize::from(page) & 0x1FF != 0 {
+                        panic!("Page isn't aligned for a 2mb page {:?}", page);
+                    }
+                    self.table[index] = PageEntry::new(frame, flags).into();
                 },
                 (PageSize::TwoMb, PageEntryType::PageTableEntry(_)) => {
                     panic!("Can't insert a 2mb page. There's already a page table here");
@@ -391,22 +870,20 @@ mod level2 {
                 (PageSize::FourKb, PageEntryType::NotPresent(_)) => {
                     // Insert a new page table entry
                     let entry = {
-                        let frame = unsafe { ::falloc::FRAME_ALLOCATOR.get_frame() };
-                        let physical_address: ::mem::PhysicalAddress = frame.into();
-                        let entry = PageTableEntry::new(frame);
+                        let frame = allocate_table_frame(allocator);
+                        let entry = PageTableEntry::new(frame, flags);
                         self.table[index] = entry.into();
 
-                        let ptr = physical_address.as_ptr() as *mut level1::PageMap;
-                        unsafe {
-                            *ptr = Default::default();
-                        }
+                        let map: &mut level1::PageMap = unsafe { table_at(memory, frame.into()) };
+                        *map = Default::default();
                         entry
                     };
 
-                    entry.get_map().insert_page(frame, page, page_size);
+                    entry.get_map(memory).insert_page(frame, page, page_size, flags, allocator, memory);
                 },
                 (PageSize::FourKb, PageEntryType::PageTableEntry(entry)) => {
-                    entry.get_map().insert_page(frame, page, page_size);
+                    self.table[index].entry |= table_entry_bits(flags);
+                    entry.get_map(memory).insert_page(frame, page, page_size, flags, allocator, memory);
                 },
                 (PageSize::FourKb, PageEntryType::PageEntry(_)) => {
                     panic!("Can't insert a smaller page. There's already a 1gb page here.");
@@ -414,6 +891,74 @@ mod level2 {
                 (PageSize::OneGb, _) => unreachable!(),
             };
         }
+
+        pub fn unmap_page<A, M>(&mut self, page: Page, allocator: &mut A, memory: &M) -> Option<Frame>
+            where A: FrameSource, M: PhysicalMemory {
+            let virtual_address: ::mem::VirtualAddress = page.into();
+            let virtual_address: usize = virtual_address.into();
+            let index = (virtual_address >> 21) & 0x1FF;
+            match self.table[index].as_enum() {
+                PageEntryType::PageEntry(entry) if virtual_address & (PageSize::TwoMb as usize - 1) == 0 => {
+                    self.table[index] = Default::default();
+                    Some(entry.frame())
+                },
+                _ => self.unmap_four_kb_page(page, allocator, memory),
+            }
+        }
+
+        /// Unmaps just `page`, splitting the 2MB page it is in if it is
+        /// in one
+        pub fn unmap_four_kb_page<A, M>(&mut self, page: Page, allocator: &mut A, memory: &M) -> Option<Frame>
+            where A: FrameSource, M: PhysicalMemory {
+            let virtual_address: ::mem::VirtualAddress = page.into();
+            let virtual_address: usize = virtual_address.into();
+            let index = (virtual_address >> 21) & 0x1FF;
+            if let PageEntryType::PageEntry(entry) = self.table[index].as_enum() {
+                if !self.split(index, entry, allocator, memory) {
+                    return None;
+                }
+            }
+            match self.table[index].as_enum() {
+                PageEntryType::PageTableEntry(entry) => entry.get_map(memory).unmap_page(page, memory),
+                _ => None,
+            }
+        }
+
+        /// Replaces the 2MB page `entry` at `index` with a table mapping
+        /// the same memory with 4KB pages. False if there is no frame
+        /// for the table.
+        fn split<A, M>(&mut self, index: usize, entry: PageEntry, allocator: &mut A, memory: &M) -> bool
+            where A: FrameSource, M: PhysicalMemory {
+            let flags = MappingFlags::from_entry(entry.entry, PageSize::TwoMb);
+            let table_frame = match allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            let map: &mut level1::PageMap = unsafe { table_at(memory, table_frame.into()) };
+            *map = Default::default();
+            let first: usize = entry.frame().into();
+            for offset in 0..0x200 {
+                map.insert_page(Frame::new(first + offset), Page::new(offset),
+                                PageSize::FourKb, flags, allocator, memory);
+            }
+            self.table[index] = PageTableEntry::new(table_frame, flags).into();
+            true
+        }
+
+        pub fn translate<M: PhysicalMemory>(&self, address: VirtualAddress, memory: &M) -> Option<(PhysicalAddress, PageSize, u64)> {
+            let virtual_address: usize = address.into();
+            let index = (virtual_address >> 21) & 0x1FF;
+            match self.table[index].as_enum() {
+                PageEntryType::NotPresent(_) => None,
+                PageEntryType::PageTableEntry(entry) => entry.get_map(memory).translate(address, memory),
+                PageEntryType::PageEntry(entry) => {
+                    let frame_address: PhysicalAddress = entry.frame().into();
+                    let frame_address: usize = frame_address.into();
+                    let offset = virtual_address & (PageSize::TwoMb as usize - 1);
+                    Some((PhysicalAddress::new(frame_address + offset), PageSize::TwoMb, entry.entry))
+                },
+            }
+        }
     }
 
     #[derive(Clone, Copy)]
@@ -471,12 +1016,8 @@ mod level2 {
     }
 
     impl PageTableEntry {
-        pub fn new(frame: Frame) -> Self {
-            let mut entry: u64 = 
-                (PageEntryBuilder {
-                    read_write: ReadWrite::Writeable,
-                    ..Default::default()
-                }).to_entry();
+        pub fn new(frame: Frame, flags: MappingFlags) -> Self {
+            let mut entry: u64 = table_entry_bits(flags);
             entry |= 0x1 << 0;
             let frame_number: usize = frame.into();
             entry |= (frame_number as u64 & 0xFFFFF_FFFFF) << 12;
@@ -485,10 +1026,9 @@ mod level2 {
             }
         }
 
-        pub fn get_map(&self) -> &mut level1::PageMap {
-            let mut physical_address = ::mem::PhysicalAddress::new(self.entry as usize & (0xFFFFFFFFFF << 12));
-            let ptr = physical_address.as_mut_ptr() as *mut level1::PageMap;
-            unsafe { ::core::mem::transmute(ptr) }
+        pub fn get_map<M: PhysicalMemory>(&self, memory: &M) -> &mut level1::PageMap {
+            let physical_address = PhysicalAddress::new(self.entry as usize & (0xFFFFFFFFFF << 12));
+            unsafe { table_at(memory, physical_address) }
         }
     }
 
@@ -506,20 +1046,24 @@ mod level2 {
     }
     
     impl PageEntry {
-        pub fn new(frame: Frame) -> Self {
-            let mut entry: u64 = 
-                (PageEntryBuilder {
-                    read_write: ReadWrite::Writeable,
-                    ..Default::default()
-                }).to_entry();
+        pub fn new(frame: Frame, flags: MappingFlags) -> Self {
+            let frame_number: usize = frame.into();
+            if frame_number & 0x1FF != 0 {
+                panic!("Frame isn't aligned for a 2mb page {:?}", frame);
+            }
+            let mut entry: u64 = PageEntryBuilder::from(flags).to_entry();
             entry |= 0x1 << 0;
             entry |= 0x1 << 7;
-            let frame_number: usize = frame.into();
-            entry |= (frame_number as u64 & 0x7FFFFFFF << 9) << 12;
+            entry |= (flags.pat as u64) << 12;
+            entry |= ((frame_number as u64) << 12) & TWO_MB_ADDRESS_MASK;
             PageEntry {
                 entry: entry,
             }
         }
+
+        pub fn frame(&self) -> Frame {
+            Frame::new(((self.entry & TWO_MB_ADDRESS_MASK) >> 12) as usize)
+        }
     }
 
     impl From<PageEntry> for GenericPageEntry {
@@ -532,8 +1076,9 @@ mod level2 {
 }
 
 mod level1 {
-    use ::mem::{Frame, Page};
-    use super::{PageEntryBuilder, ReadWrite, PageSize};
+    use ::mem::{Frame, Page, PhysicalAddress, VirtualAddress};
+    use super::{PageEntryBuilder, PageSize, MappingFlags, ADDRESS_MASK};
+    use super::{FrameSource, PhysicalMemory};
 
     pub struct PageMap {
         table: [GenericPageEntry; 0x200],
@@ -548,7 +1093,8 @@ mod level1 {
     }
 
     impl PageMap {
-        pub fn insert_page(&mut self, frame: Frame, page: Page, page_size: PageSize) {
+        pub fn insert_page<A, M>(&mut self, frame: Frame, page: Page, page_size: PageSize, flags: MappingFlags, _allocator: &mut A, _memory: &M)
+            where A: FrameSource, M: PhysicalMemory {
             let index = {
                 let virtual_address: ::mem::VirtualAddress = page.into();
                 let virtual_address: usize = virtual_address.into();
@@ -556,7 +1102,7 @@ mod level1 {
             };
             match (page_size, self.table[index].as_enum()) {
                 (PageSize::FourKb, PageEntryType::NotPresent(_)) => {
-                    self.table[index] = PageEntry::new(frame).into();
+                    self.table[index] = PageEntry::new(frame, flags).into();
                 },
                 (PageSize::FourKb, PageEntryType::PageEntry(_)) => {
                     panic!("This page is already mapped {:?} : {:?}", page, frame);
@@ -564,6 +1110,35 @@ mod level1 {
                 (_, _) => unreachable!(),
             };
         }
+
+        pub fn unmap_page<M: PhysicalMemory>(&mut self, page: Page, _memory: &M) -> Option<Frame> {
+            let index = {
+                let virtual_address: ::mem::VirtualAddress = page.into();
+                let virtual_address: usize = virtual_address.into();
+                (virtual_address >> 12) & 0x1FF
+            };
+            match self.table[index].as_enum() {
+                PageEntryType::NotPresent(_) => None,
+                PageEntryType::PageEntry(entry) => {
+                    self.table[index] = Default::default();
+                    Some(entry.frame())
+                },
+            }
+        }
+
+        pub fn translate<M: PhysicalMemory>(&self, address: VirtualAddress, _memory: &M) -> Option<(PhysicalAddress, PageSize, u64)> {
+            let virtual_address: usize = address.into();
+            let index = (virtual_address >> 12) & 0x1FF;
+            match self.table[index].as_enum() {
+                PageEntryType::NotPresent(_) => None,
+                PageEntryType::PageEntry(entry) => {
+                    let frame_address: PhysicalAddress = entry.frame().into();
+                    let frame_address: usize = frame_address.into();
+                    let offset = virtual_address & (PageSize::FourKb as usize - 1);
+                    Some((PhysicalAddress::new(frame_address + offset), PageSize::FourKb, entry.entry))
+                },
+            }
+        }
     }
 
     #[derive(Clone, Copy)]
@@ -610,19 +1185,20 @@ mod level1 {
     }
     
     impl PageEntry {
-        pub fn new(frame: Frame) -> Self {
-            let mut entry: u64 = 
-                (PageEntryBuilder {
-                    read_write: ReadWrite::Writeable,
-                    ..Default::default()
-                }).to_entry();
+        pub fn new(frame: Frame, flags: MappingFlags) -> Self {
+            let mut entry: u64 = PageEntryBuilder::from(flags).to_entry();
             entry |= 0x1 << 0;
+            entry |= (flags.pat as u64) << 7;
             let frame_number: usize = frame.into();
-            entry |= (frame_number as u64 & 0xFFFFF_FFFFF) << 12;
+            entry |= ((frame_number as u64) << 12) & ADDRESS_MASK;
             PageEntry {
                 entry: entry,
             }
         }
+
+        pub fn frame(&self) -> Frame {
+            Frame::new(((self.entry & ADDRESS_MASK) >> 12) as usize)
+        }
     }
 
     impl From<PageEntry> for GenericPageEntry {
@@ -634,12 +1210,108 @@ mod level1 {
     }
 }
 
+fn allocate_table_frame<A: FrameSource>(allocator: &mut A) -> ::mem::Frame {
+    allocator.allocate_frame().expect("Out of frames for page tables")
+}
+
+/// Borrows the table held in the frame at `address`
+unsafe fn table_at<'a, M: PhysicalMemory, T>(memory: &M, address: ::mem::PhysicalAddress) -> &'a mut T {
+    let mut virtual_address = memory.to_virtual(address);
+    &mut *(virtual_address.as_mut_ptr() as *mut T)
+}
+
+#[cfg(not(test))]
+fn flush_page(page: ::mem::Page) {
+    let virtual_address: ::mem::VirtualAddress = page.into();
+    unsafe {
+        ::x86::shared::tlb::flush(virtual_address.into());
+    }
+}
+
+/// Tables built by host tests are never loaded, and invlpg would fault
+#[cfg(test)]
+fn flush_page(_page: ::mem::Page) {
+}
+
+/// Sets EFER.NXE so that the no-execute bit in page entries is
+/// honoured instead of being a reserved bit
+unsafe fn enable_no_execute() {
+    use ::x86::shared::msr::{rdmsr, wrmsr, IA32_EFER};
+    let efer = rdmsr(IA32_EFER);
+    wrmsr(IA32_EFER, efer | (1 << 11));
+}
+
+/// Permission and caching bits of a mapping
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MappingFlags {
+    pub writable: bool,
+    pub user: bool,
+    pub write_through: bool,
+    pub cache_disable: bool,
+    /// Keep the TLB entry across CR3 loads. Only valid on leaf entries
+    pub global: bool,
+    /// Selects the upper half of the PAT together with the cache bits
+    pub pat: bool,
+    /// Requires EFER.NXE, which `PageTable::load` enables
+    pub no_execute: bool,
+}
+
+/// Supervisor only, writable and executable, which is how every
+/// mapping was made before flags could be chosen
+impl Default for MappingFlags {
+    fn default() -> Self {
+        MappingFlags {
+            writable: true,
+            user: false,
+            write_through: false,
+            cache_disable: false,
+            global: false,
+            pat: false,
+            no_execute: false,
+        }
+    }
+}
+
+impl MappingFlags {
+    fn from_entry(entry: u64, page_size: PageSize) -> Self {
+        let pat_bit = match page_size {
+            PageSize::FourKb => 7,
+            PageSize::TwoMb | PageSize::OneGb => 12,
+        };
+        MappingFlags {
+            writable: (entry >> 1) & 0x1 == 0x1,
+            user: (entry >> 2) & 0x1 == 0x1,
+            write_through: (entry >> 3) & 0x1 == 0x1,
+            cache_disable: (entry >> 4) & 0x1 == 0x1,
+            global: (entry >> 8) & 0x1 == 0x1,
+            pat: (entry >> pat_bit) & 0x1 == 0x1,
+            no_execute: (entry >> 63) & 0x1 == 0x1,
+        }
+    }
+}
+
+/// Bits an intermediate entry needs so that it doesn't restrict a
+/// mapping below it. The processor ANDs the read/write and
+/// user/supervisor bits of every level, so a table has to be writable
+/// or user accessible if any mapping under it is. No-execute and the
+/// caching bits are left clear, as they would apply to every mapping
+/// under the table.
+fn table_entry_bits(flags: MappingFlags) -> u64 {
+    (PageEntryBuilder {
+        read_write: if flags.writable { ReadWrite::Writeable } else { ReadWrite::ReadOnly },
+        user_supervisor: if flags.user { UserSupervisor::User } else { UserSupervisor::Supervisor },
+        ..Default::default()
+    }).to_entry()
+}
+
 struct PageEntryBuilder {
     read_write: ReadWrite,
     user_supervisor: UserSupervisor,
     page_level_write_through: bool,
     page_level_cache_disable: bool,
     accessed: bool,
+    global: bool,
+    execute_disable: bool,
 }
 
 impl Default for PageEntryBuilder {
@@ -650,6 +1322,22 @@ impl Default for PageEntryBuilder {
             page_level_write_through: false,
             page_level_cache_disable: false,
             accessed: false,
+            global: false,
+            execute_disable: false,
+        }
+    }
+}
+
+impl From<MappingFlags> for PageEntryBuilder {
+    fn from(flags: MappingFlags) -> Self {
+        PageEntryBuilder {
+            read_write: if flags.writable { ReadWrite::Writeable } else { ReadWrite::ReadOnly },
+            user_supervisor: if flags.user { UserSupervisor::User } else { UserSupervisor::Supervisor },
+            page_level_write_through: flags.write_through,
+            page_level_cache_disable: flags.cache_disable,
+            accessed: false,
+            global: flags.global,
+            execute_disable: flags.no_execute,
         }
     }
 }
@@ -662,6 +1350,8 @@ impl PageEntryBuilder {
         result |= (self.page_level_write_through as u64) << 3;
         result |= (self.page_level_cache_disable as u64) << 4;
         result |= (self.accessed as u64) << 5;
+        result |= (self.global as u64) << 8;
+        result |= (self.execute_disable as u64) << 63;
         result
     }
 }
@@ -676,4 +1366,407 @@ enum UserSupervisor {
     User = 1,
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{FrameSource, Mapping, MappingFlags, OffsetMapped, PageSize, PageTable, PhysicalMemory};
+    use ::mem::{Frame, Page, PhysicalAddress, VirtualAddress};
+    use super::dump::{Difference, MappingRun};
+    use ::std::cell::{Cell, RefCell};
+    use ::std::string::String;
+    use ::std::vec::Vec;
+
+    /// Physical memory made of ordinary host memory. Frame `n` is the
+    /// `n`th table in `frames`, and frames are handed out in order.
+    struct FakeMemory {
+        frames: Vec<[u64; 0x200]>,
+        next_frame: Cell<usize>,
+        freed: RefCell<Vec<Frame>>,
+    }
+
+    impl FakeMemory {
+        fn new(frame_count: usize) -> Self {
+            FakeMemory {
+                frames: vec![[0xDEAD_BEEF; 0x200]; frame_count],
+                next_frame: Cell::new(0),
+                freed: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn entry(&self, table: usize, index: usize) -> u64 {
+            self.frames[table][index]
+        }
+
+        fn allocated(&self) -> usize {
+            self.next_frame.get()
+        }
+
+        fn freed(&self) -> Vec<usize> {
+            let mut result: Vec<usize> = self.freed.borrow().iter()
+                .map(|&frame| frame.into())
+                .collect();
+            result.sort();
+            result
+        }
+    }
+
+    impl<'a> FrameSource for &'a FakeMemory {
+        fn allocate_frame(&mut self) -> Option<Frame> {
+            let frame = self.next_frame.get();
+            if frame == self.frames.len() {
+                return None;
+            }
+            self.next_frame.set(frame + 1);
+            Some(Frame::new(frame))
+        }
+
+        fn free_frame(&mut self, frame: Frame) {
+            self.freed.borrow_mut().push(frame);
+        }
+    }
+
+    impl<'a> PhysicalMemory for &'a FakeMemory {
+        fn to_virtual(&self, address: PhysicalAddress) -> VirtualAddress {
+            let address: usize = address.into();
+            VirtualAddress::new(self.frames.as_ptr() as usize + address)
+        }
+    }
+
+    #[test]
+    fn four_kb_walk() {
+        let memory = FakeMemory::new(8);
+        let mut table = PageTable::new_in(&memory, &memory);
+        let page = Page::new(0xFFFF_8000_0123_4000 >> 12);
+        table.insert_page(Frame::new(0x42), page, PageSize::FourKb, Default::default());
+
+        // PML4, PDPT, PD and PT
+        assert_eq!(memory.allocated(), 4);
+        assert_eq!(memory.entry(0, 0x100), 0x1003);
+        assert_eq!(memory.entry(1, 0), 0x2003);
+        assert_eq!(memory.entry(2, 0x9), 0x3003);
+        assert_eq!(memory.entry(3, 0x34), 0x4_2003);
+        assert_eq!(memory.entry(0, 0), 0);
+
+        let (address, page_size, flags) = table.translate(VirtualAddress::new(0xFFFF_8000_0123_4567)).unwrap();
+        assert_eq!(address, PhysicalAddress::new(0x4_2567));
+        assert_eq!(page_size, PageSize::FourKb);
+        assert_eq!(flags, Default::default());
+        assert!(table.translate(VirtualAddress::new(0xFFFF_8000_0123_5000)).is_none());
+
+        assert_eq!(table.unmap_page(page), Some(Frame::new(0x42)));
+        assert_eq!(table.unmap_page(page), None);
+        assert!(table.translate(VirtualAddress::new(0xFFFF_8000_0123_4567)).is_none());
+    }
+
+    #[test]
+    fn offset_mapped_tables() {
+        let memory = FakeMemory::new(8);
+        let mut table = PageTable::new_in(&memory, &memory);
+        table.insert_page(Frame::new(0x42), Page::new(0x10), PageSize::FourKb, Default::default());
+
+        let offset = VirtualAddress::new(memory.frames.as_ptr() as usize);
+        let mut table = unsafe { table.with_physical_memory(OffsetMapped::new(offset)) };
+        assert_eq!(table.translate(VirtualAddress::new(0x1_0123)).map(|(address, _, _)| address),
+            Some(PhysicalAddress::new(0x4_2123)));
+        table.insert_page(Frame::new(0x43), Page::new(0x11), PageSize::FourKb, Default::default());
+        assert_eq!(memory.entry(3, 0x11), 0x4_3003);
+        assert_eq!(memory.allocated(), 4);
+
+        ::std::mem::drop(table);
+        assert_eq!(memory.freed(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn tables_are_shared() {
+        let memory = FakeMemory::new(8);
+        let mut table = PageTable::new_in(&memory, &memory);
+        table.insert_page(Frame::new(0x10), Page::new(0x10), PageSize::FourKb, Default::default());
+        table.insert_page(Frame::new(0x11), Page::new(0x11), PageSize::FourKb, Default::default());
+        table.insert_page(Frame::new(0x200), Page::new(0x200), PageSize::TwoMb, Default::default());
+        assert_eq!(memory.allocated(), 4);
+    }
+
+    #[test]
+    fn intermediate_entries_allow_mapping() {
+        let memory = FakeMemory::new(8);
+        let mut table = PageTable::new_in(&memory, &memory);
+        let read_only = MappingFlags {
+            writable: false,
+            no_execute: true,
+            ..Default::default()
+        };
+        table.insert_page(Frame::new(0x10), Page::new(0x10), PageSize::FourKb, read_only);
+        assert_eq!(memory.entry(0, 0), 0x1001);
+
+        let user = MappingFlags {
+            user: true,
+            ..Default::default()
+        };
+        table.insert_page(Frame::new(0x11), Page::new(0x11), PageSize::FourKb, user);
+        assert_eq!(memory.entry(0, 0), 0x1007);
+        assert_eq!(memory.entry(3, 0x10), (1 << 63) | 0x1_0001);
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_table_frames() {
+        let memory = FakeMemory::new(2);
+        let mut table = PageTable::new_in(&memory, &memory);
+        table.insert_page(Frame::new(0x10), Page::new(0x10), PageSize::FourKb, Default::default());
+    }
+
+    #[test]
+    fn mappings_in_order() {
+        let memory = FakeMemory::new(8);
+        let mut table = PageTable::new_in(&memory, &memory);
+        let read_only = MappingFlags {
+            writable: false,
+            ..Default::default()
+        };
+        table.insert_page(Frame::new(0x42), Page::new(0xFFFF_8000_0000_1000 >> 12), PageSize::FourKb, read_only);
+        table.insert_page(Frame::new(0x400), Page::new(0x200), PageSize::TwoMb, Default::default());
+        table.insert_page(Frame::new(0x40000), Page::new(0x40000), PageSize::OneGb, Default::default());
+
+        let mappings: Vec<Mapping> = table.mappings().collect();
+        assert_eq!(mappings, vec![
+            Mapping {
+                virtual_address: VirtualAddress::new(0x20_0000),
+                physical_address: PhysicalAddress::new(0x40_0000),
+                page_size: PageSize::TwoMb,
+                flags: Default::default(),
+            },
+            Mapping {
+                virtual_address: VirtualAddress::new(0x4000_0000),
+                physical_address: PhysicalAddress::new(0x4000_0000),
+                page_size: PageSize::OneGb,
+                flags: Default::default(),
+            },
+            Mapping {
+                virtual_address: VirtualAddress::new(0xFFFF_8000_0000_1000),
+                physical_address: PhysicalAddress::new(0x4_2000),
+                page_size: PageSize::FourKb,
+                flags: read_only,
+            },
+        ]);
+    }
+
+    #[test]
+    fn runs_coalesce() {
+        let memory = FakeMemory::new(4);
+        let mut table = PageTable::new_in(&memory, &memory);
+        for offset in 0..4 {
+            table.insert_page(Frame::new(0x100 + offset), Page::new(0x10 + offset), PageSize::FourKb, Default::default());
+        }
+        table.insert_page(Frame::new(0x200), Page::new(0x14), PageSize::FourKb, Default::default());
+        let read_only = MappingFlags {
+            writable: false,
+            ..Default::default()
+        };
+        table.insert_page(Frame::new(0x201), Page::new(0x15), PageSize::FourKb, read_only);
+
+        let runs: Vec<MappingRun> = table.mapping_runs().collect();
+        assert_eq!(runs.len(), 3);
+        assert_eq!(runs[0].virtual_start, VirtualAddress::new(0x1_0000));
+        assert_eq!(runs[0].physical_start, PhysicalAddress::new(0x10_0000));
+        assert_eq!(runs[0].length, 0x4000);
+        assert_eq!(runs[1].virtual_start, VirtualAddress::new(0x1_4000));
+        assert_eq!(runs[1].length, 0x1000);
+        assert_eq!(runs[2].physical_end(), PhysicalAddress::new(0x20_2000));
+        assert_eq!(runs[2].flags, read_only);
+
+        let mut output = String::new();
+        table.write_mappings(&mut output).unwrap();
+        assert_eq!(output.lines().next(),
+            Some("0000000000010000-0000000000014000 -> 000000100000-000000104000 4K rwxs"));
+    }
+
+    #[test]
+    fn differences_between_tables() {
+        let first_memory = FakeMemory::new(4);
+        let second_memory = FakeMemory::new(4);
+        let mut first = PageTable::new_in(&first_memory, &first_memory);
+        let mut second = PageTable::new_in(&second_memory, &second_memory);
+        for &page in &[0x10, 0x11, 0x20] {
+            first.insert_page(Frame::new(page), Page::new(page), PageSize::FourKb, Default::default());
+        }
+        for &page in &[0x10, 0x12, 0x20] {
+            second.insert_page(Frame::new(page), Page::new(page), PageSize::FourKb, Default::default());
+        }
+
+        let differences: Vec<Difference> = first.differences(&second).collect();
+        assert_eq!(differences.len(), 3);
+        match differences[0] {
+            Difference::OnlyInFirst(run) => assert_eq!(run.length, 0x2000),
+            _ => panic!("expected the first table's run"),
+        }
+        match differences[2] {
+            Difference::OnlyInSecond(run) => assert_eq!(run.virtual_start, VirtualAddress::new(0x1_2000)),
+            _ => panic!("expected the second table's run"),
+        }
+
+        let mut output = String::new();
+        first.write_differences(&second, &mut output).unwrap();
+        assert_eq!(output.lines().count(), 3);
+        assert!(output.starts_with("- 0000000000010000-0000000000012000"));
+    }
+
+    #[test]
+    fn address_space_shares_kernel_half() {
+        let memory = FakeMemory::new(10);
+        let mut kernel_table = PageTable::new_in(&memory, &memory);
+        kernel_table.insert_page(Frame::new(0x200), Page::new(0xFFFF_8000_0000_0000 >> 12), PageSize::TwoMb, Default::default());
+        kernel_table.insert_page(Frame::new(0x42), Page::new(0x80_0000_0000 >> 12), PageSize::FourKb, Default::default());
+        assert_eq!(memory.allocated(), 6);
+
+        {
+            let mut user_table = PageTable::new_address_space_from(&kernel_table);
+            user_table.insert_page(Frame::new(0x43), Page::new(0x400), PageSize::FourKb, Default::default());
+            assert!(user_table.translate(VirtualAddress::new(0xFFFF_8000_0000_0000)).is_some());
+            assert!(user_table.translate(VirtualAddress::new(0x80_0000_0000)).is_some());
+            assert!(user_table.translate(VirtualAddress::new(0x40_0000)).is_some());
+            assert!(kernel_table.translate(VirtualAddress::new(0x40_0000)).is_none());
+        }
 
+        // Only the user table's PML4 and the tables for 0x400000
+        assert_eq!(memory.freed(), vec![6, 7, 8, 9]);
+        assert!(kernel_table.translate(VirtualAddress::new(0x80_0000_0000)).is_some());
+    }
+
+    #[test]
+    fn drop_frees_leaf_frames() {
+        let memory = FakeMemory::new(4);
+        {
+            let mut table = PageTable::new_in(&memory, &memory);
+            table.set_free_leaf_frames(true);
+            table.insert_page(Frame::new(0x200), Page::new(0x200), PageSize::TwoMb, Default::default());
+        }
+        let mut expected: Vec<usize> = (0..3).collect();
+        expected.extend(0x200..0x400);
+        assert_eq!(memory.freed(), expected);
+    }
+
+    #[test]
+    fn adopted_tables_are_not_freed() {
+        let memory = FakeMemory::new(4);
+        let frame = {
+            let mut table = PageTable::new_in(&memory, &memory);
+            table.insert_page(Frame::new(0x42), Page::new(0x10), PageSize::FourKb, Default::default());
+            let frame = table.pml4_frame();
+            ::std::mem::forget(table);
+            frame
+        };
+        {
+            let table = unsafe { PageTable::from_frame(frame, &memory, &memory) };
+            assert!(table.translate(VirtualAddress::new(0x1_0000)).is_some());
+        }
+        assert!(memory.freed().is_empty());
+    }
+
+    #[test]
+    fn one_gb_entry_encoding() {
+        let memory = FakeMemory::new(4);
+        let mut table = PageTable::new_in(&memory, &memory);
+        table.insert_page(Frame::new(0x5 << 18), Page::new(0x2 << 18), PageSize::OneGb, Default::default());
+        assert_eq!(memory.entry(1, 2), 0x1_4000_0083);
+
+        let (address, page_size, flags) = table.translate(VirtualAddress::new(0x8123_4567)).unwrap();
+        assert_eq!(address, PhysicalAddress::new(0x1_4123_4567));
+        assert_eq!(page_size, PageSize::OneGb);
+        assert_eq!(flags, Default::default());
+    }
+
+    #[test]
+    fn two_mb_entry_encoding() {
+        let memory = FakeMemory::new(4);
+        let mut table = PageTable::new_in(&memory, &memory);
+        table.insert_page(Frame::new(0x1234 << 9), Page::new(0x3 << 9), PageSize::TwoMb, Default::default());
+        assert_eq!(memory.entry(2, 3), 0x2_4680_0083);
+
+        let (address, page_size, _) = table.translate(VirtualAddress::new(0x60_1234)).unwrap();
+        assert_eq!(address, PhysicalAddress::new(0x2_4680_1234));
+        assert_eq!(page_size, PageSize::TwoMb);
+
+        assert_eq!(table.unmap_page(Page::new(0x3 << 9)), Some(Frame::new(0x1234 << 9)));
+        assert_eq!(memory.entry(2, 3), 0);
+        assert!(table.translate(VirtualAddress::new(0x60_1234)).is_none());
+    }
+
+    #[test]
+    fn unmap_inside_huge_pages() {
+        let memory = FakeMemory::new(6);
+        let mut table = PageTable::new_in(&memory, &memory);
+        let read_only = MappingFlags {
+            writable: false,
+            ..Default::default()
+        };
+        table.insert_page(Frame::new(0x400), Page::new(0x200), PageSize::TwoMb, read_only);
+        assert_eq!(memory.allocated(), 3);
+
+        // The rest of the 2MB page stays mapped with 4KB pages
+        assert_eq!(table.unmap_page(Page::new(0x201)), Some(Frame::new(0x401)));
+        assert_eq!(memory.allocated(), 4);
+        assert!(table.translate(VirtualAddress::new(0x20_1000)).is_none());
+        assert_eq!(table.translate(VirtualAddress::new(0x20_2345)),
+            Some((PhysicalAddress::new(0x40_2345), PageSize::FourKb, read_only)));
+        assert_eq!(table.mappings().count(), 0x1FF);
+
+        // A 1GB page is split down to the 2MB page holding `page`
+        table.insert_page(Frame::new(0x40000), Page::new(0x40000), PageSize::OneGb, Default::default());
+        assert_eq!(table.unmap_page(Page::new(0x40200)), Some(Frame::new(0x40200)));
+        assert_eq!(memory.allocated(), 6);
+        assert!(table.translate(VirtualAddress::new(0x4020_0000)).is_none());
+        assert_eq!(table.translate(VirtualAddress::new(0x4020_1000)).map(|(_, page_size, _)| page_size),
+            Some(PageSize::FourKb));
+        assert_eq!(table.translate(VirtualAddress::new(0x7FFF_FFFF)).map(|(address, page_size, _)| (address, page_size)),
+            Some((PhysicalAddress::new(0x7FFF_FFFF), PageSize::TwoMb)));
+
+        // No frames left for another table
+        assert_eq!(table.unmap_page(Page::new(0x40401)), None);
+        assert!(table.translate(VirtualAddress::new(0x4040_1000)).is_some());
+    }
+
+    #[test]
+    fn huge_page_flags() {
+        let flags = MappingFlags {
+            writable: false,
+            global: true,
+            pat: true,
+            no_execute: true,
+            ..Default::default()
+        };
+        let memory = FakeMemory::new(4);
+        let mut table = PageTable::new_in(&memory, &memory);
+        table.insert_page(Frame::new(0x200), Page::new(0), PageSize::TwoMb, flags);
+        assert_eq!(memory.entry(2, 0), (1 << 63) | 0x20_0000 | 0x1000 | 0x100 | 0x80 | 0x1);
+        assert_eq!(MappingFlags::from_entry(memory.entry(2, 0), PageSize::TwoMb), flags);
+    }
+
+    #[test]
+    fn four_kb_pat_bit() {
+        let flags = MappingFlags {
+            pat: true,
+            cache_disable: true,
+            ..Default::default()
+        };
+        let memory = FakeMemory::new(4);
+        let mut table = PageTable::new_in(&memory, &memory);
+        table.insert_page(Frame::new(0xABCDE), Page::new(0x7), PageSize::FourKb, flags);
+        assert_eq!(memory.entry(3, 7), 0xABCD_E000 | 0x80 | 0x10 | 0x2 | 0x1);
+        assert_eq!(MappingFlags::from_entry(memory.entry(3, 7), PageSize::FourKb), flags);
+    }
+
+    #[test]
+    #[should_panic]
+    fn misaligned_two_mb_frame() {
+        let memory = FakeMemory::new(4);
+        let mut table = PageTable::new_in(&memory, &memory);
+        table.insert_page(Frame::new(0x201), Page::new(0), PageSize::TwoMb, Default::default());
+    }
+
+    #[test]
+    #[should_panic]
+    fn misaligned_one_gb_page() {
+        let memory = FakeMemory::new(4);
+        let mut table = PageTable::new_in(&memory, &memory);
+        table.insert_page(Frame::new(0), Page::new(0x200), PageSize::OneGb, Default::default());
+    }
+}
@@ -7,16 +7,65 @@ extern crate spin;
 
 use x86::io::{inb, outb};
 
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use spin::Mutex;
 
 pub struct SerialWriter {
     mode: SerialMode,
+    baud_rate: u32,
+    echo: Option<fn(&str)>,
 }
 
 pub static SERIAL_WRITER: Mutex<SerialWriter> = Mutex::new(SerialWriter {
     mode: SerialMode::UnInit,
+    baud_rate: DEFAULT_BAUD_RATE,
+    echo: None,
 });
 
+/// How much gets printed. Each level includes the ones before it.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    /// Memory maps, page table dumps and the like
+    Debug,
+}
+
+impl LogLevel {
+    /// The level spelled `name`, as in a boot configuration
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        match name {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(LogLevel::Info as usize);
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+/// Whether output at `level` is printed
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as usize <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ({
@@ -34,6 +83,23 @@ macro_rules! println {
     ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
 }
 
+/// `println!` that only prints at `LogLevel::Debug`
+#[macro_export]
+macro_rules! debugln {
+    ($($arg:tt)*) => ({
+        if $crate::log_enabled($crate::LogLevel::Debug) {
+            println!($($arg)*);
+        }
+    });
+}
+
+/// Bits per second the port starts out at
+pub const DEFAULT_BAUD_RATE: u32 = 9600;
+
+/// A rate the UART can't be set to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UnsupportedBaudRate(pub u32);
+
 /// I/O port base address for the first serial port.
 const IO_BASE: u16 = 0x3f8;
 
@@ -86,6 +152,9 @@ use core::fmt::Write;
 impl Write for SerialWriter {
     fn write_str(&mut self, string:&str) -> ::core::fmt::Result {
         self.print_str(string);
+        if let Some(echo) = self.echo {
+            echo(string);
+        }
         Ok(())
     }
 }
@@ -94,6 +163,8 @@ impl SerialWriter {
     pub unsafe fn new() -> SerialWriter {
         let mut result = SerialWriter {
             mode: SerialMode::UnInit,
+            baud_rate: DEFAULT_BAUD_RATE,
+            echo: None,
         };
         result.init_poll();
         result
@@ -108,12 +179,36 @@ impl SerialWriter {
             outb(IER_REG, 0);                   /* Turn off all interrupts. */
             outb(FCR_REG, 0);                   /* Disable FIFO. */
         }
-        self.set_serial(9600);                       /* 9.6 kbps, N-8-1. */
+        let baud_rate = self.baud_rate;
+        self.set_serial(baud_rate);                  /* N-8-1. */
         //outb(MCR_REG, MCR_OUT2);                /* Required to enable interrupts. */
         //intq_init(&txq);
         self.mode = SerialMode::Poll;
     }
 
+    /// Switches the port to `bps` bits per second, which has to divide
+    /// the 16550A's 115200 Hz base rate
+    pub fn set_baud_rate(&mut self, bps: u32) -> Result<(), UnsupportedBaudRate> {
+        if bps < 300 || bps > 115200 || 115200 % bps != 0 {
+            return Err(UnsupportedBaudRate(bps));
+        }
+        self.baud_rate = bps;
+        if self.mode != SerialMode::UnInit {
+            self.set_serial(bps);
+        }
+        Ok(())
+    }
+
+    pub fn baud_rate(&self) -> u32 {
+        self.baud_rate
+    }
+
+    /// Also hands everything written to the port to `echo`, which is
+    /// called with the port locked and so can't print
+    pub fn set_echo(&mut self, echo: Option<fn(&str)>) {
+        self.echo = echo;
+    }
+
     /// Configures the serial port for BPS bits per second.
     fn set_serial(&self, bps:u32) {
         let base_rate:u32 = 1843200 / 16;         /* Base rate of 16550A, in Hz. */
@@ -171,6 +266,20 @@ impl SerialWriter {
         //intr_set_level(old_level);
     }
 
+    /// A byte received on the port, if one is waiting
+    pub fn try_getc(&mut self) -> Option<u8> {
+        if self.mode == SerialMode::UnInit {
+            self.init_poll();
+        }
+        unsafe {
+            if inb(LSR_REG) & LSR_DR != 0 {
+                Some(inb(RBR_REG))
+            } else {
+                None
+            }
+        }
+    }
+
     /// Polls the serial port until it's ready, and then transmits BYTE.
     fn putc_poll(&self, byte:u8) {
         //assert!(intr_get_level() == InterruptLevel::Off);
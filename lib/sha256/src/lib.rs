@@ -0,0 +1,245 @@
+//! SHA-256 (FIPS 180-4), for checking that the files the loader reads
+//! are the ones the build produced
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+use core::fmt;
+
+/// Bytes in a digest
+pub const DIGEST_SIZE: usize = 32;
+
+/// Bytes hashed at a time
+const BLOCK_SIZE: usize = 64;
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+    0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// A SHA-256 digest, which prints as lowercase hex
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Digest(pub [u8; DIGEST_SIZE]);
+
+impl Digest {
+    /// Reads a digest written as 64 hex digits, in either case
+    pub fn from_hex(text: &str) -> Option<Digest> {
+        let text = text.as_bytes();
+        if text.len() != 2 * DIGEST_SIZE {
+            return None;
+        }
+        let mut digest = [0; DIGEST_SIZE];
+        for (byte, pair) in digest.iter_mut().zip(text.chunks(2)) {
+            match (hex_value(pair[0]), hex_value(pair[1])) {
+                (Some(high), Some(low)) => *byte = high << 4 | low,
+                _ => return None,
+            }
+        }
+        Some(Digest(digest))
+    }
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'...b'9' => Some(digit - b'0'),
+        b'a'...b'f' => Some(digit - b'a' + 10),
+        b'A'...b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Digest({})", self)
+    }
+}
+
+/// A hash being computed over data given a piece at a time
+pub struct Sha256 {
+    state: [u32; 8],
+    /// Bytes of a partial block waiting for the rest of it
+    buffer: [u8; BLOCK_SIZE],
+    buffered: usize,
+    /// Bytes hashed so far
+    length: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 {
+            state: INITIAL_STATE,
+            buffer: [0; BLOCK_SIZE],
+            buffered: 0,
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+
+        if self.buffered > 0 {
+            let fits = core::cmp::min(data.len(), BLOCK_SIZE - self.buffered);
+            self.buffer[self.buffered..self.buffered + fits].copy_from_slice(&data[..fits]);
+            self.buffered += fits;
+            data = &data[fits..];
+            if self.buffered < BLOCK_SIZE {
+                return;
+            }
+            let block = self.buffer;
+            self.compress(&block);
+            self.buffered = 0;
+        }
+
+        while data.len() >= BLOCK_SIZE {
+            self.compress(&data[..BLOCK_SIZE]);
+            data = &data[BLOCK_SIZE..];
+        }
+
+        self.buffer[..data.len()].copy_from_slice(data);
+        self.buffered = data.len();
+    }
+
+    pub fn finish(mut self) -> Digest {
+        let bit_length = self.length.wrapping_mul(8);
+
+        // A one bit, zeros up to 8 bytes short of a block, then the
+        // length in bits
+        let mut padding = [0u8; BLOCK_SIZE + 8];
+        padding[0] = 0x80;
+        let zeros = (BLOCK_SIZE + BLOCK_SIZE - 8 - 1 - self.buffered) % BLOCK_SIZE;
+        for (index, byte) in padding[1 + zeros..1 + zeros + 8].iter_mut().enumerate() {
+            *byte = (bit_length >> (56 - 8 * index)) as u8;
+        }
+        let length = self.length;
+        self.update(&padding[..1 + zeros + 8]);
+        self.length = length;
+        assert_eq!(self.buffered, 0);
+
+        let mut digest = [0; DIGEST_SIZE];
+        for (bytes, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            bytes[0] = (word >> 24) as u8;
+            bytes[1] = (word >> 16) as u8;
+            bytes[2] = (word >> 8) as u8;
+            bytes[3] = *word as u8;
+        }
+        Digest(digest)
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut schedule = [0u32; 64];
+        for (word, bytes) in schedule.iter_mut().zip(block.chunks(4)) {
+            *word = (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 |
+                (bytes[2] as u32) << 8 | bytes[3] as u32;
+        }
+        for index in 16..64 {
+            let previous = schedule[index - 15];
+            let s0 = previous.rotate_right(7) ^ previous.rotate_right(18) ^ (previous >> 3);
+            let previous = schedule[index - 2];
+            let s1 = previous.rotate_right(17) ^ previous.rotate_right(19) ^ (previous >> 10);
+            schedule[index] = schedule[index - 16].wrapping_add(s0)
+                .wrapping_add(schedule[index - 7])
+                .wrapping_add(s1);
+        }
+
+        let mut a = self.state[0];
+        let mut b = self.state[1];
+        let mut c = self.state[2];
+        let mut d = self.state[3];
+        let mut e = self.state[4];
+        let mut f = self.state[5];
+        let mut g = self.state[6];
+        let mut h = self.state[7];
+        for index in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(ROUND_CONSTANTS[index])
+                .wrapping_add(schedule[index]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (word, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *word = word.wrapping_add(*value);
+        }
+    }
+}
+
+/// The digest of `data`
+pub fn digest(data: &[u8]) -> Digest {
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{digest, Digest, Sha256};
+
+    fn hex(data: &[u8]) -> ::std::string::String {
+        format!("{}", digest(data))
+    }
+
+    #[test]
+    fn known_digests() {
+        assert_eq!(hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+                   "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        let million = vec![b'a'; 1_000_000];
+        assert_eq!(hex(&million), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+
+    #[test]
+    fn pieces() {
+        let data: ::std::vec::Vec<u8> = (0..1000).map(|index| index as u8).collect();
+        for split in [0, 1, 55, 56, 63, 64, 65, 500, 999].iter() {
+            let mut hash = Sha256::new();
+            hash.update(&data[..*split]);
+            hash.update(&data[*split..]);
+            assert_eq!(hash.finish(), digest(&data));
+        }
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let text = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD";
+        assert_eq!(Digest::from_hex(text), Some(digest(b"abc")));
+        assert_eq!(Digest::from_hex(&text[1..]), None);
+        assert_eq!(Digest::from_hex(&text.replace("B", "G")), None);
+    }
+}
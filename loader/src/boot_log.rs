@@ -0,0 +1,47 @@
+use gnu_efi::api::protocol::File;
+use gnu_efi::api::protocol::file_protocol::OpenMode;
+
+/// Bytes of output kept for the log, anything printed after them is
+/// left out
+const BOOT_LOG_CAPACITY: usize = 16 * 1024;
+
+/// What the loader has printed so far
+struct BootLog {
+    bytes: [u8; BOOT_LOG_CAPACITY],
+    length: usize,
+}
+
+/// Only touched by `record`, which runs with the serial port locked
+static mut BOOT_LOG: BootLog = BootLog {
+    bytes: [0; BOOT_LOG_CAPACITY],
+    length: 0,
+};
+
+fn record(text: &str) {
+    unsafe {
+        let fits = ::core::cmp::min(text.len(), BOOT_LOG_CAPACITY - BOOT_LOG.length);
+        let length = BOOT_LOG.length;
+        BOOT_LOG.bytes[length..length + fits].copy_from_slice(&text.as_bytes()[..fits]);
+        BOOT_LOG.length += fits;
+    }
+}
+
+/// Starts keeping a copy of everything printed
+pub fn start() {
+    ::serial::SERIAL_WRITER.lock().set_echo(Some(record));
+}
+
+/// Stops keeping output and writes what was kept to `path`, replacing
+/// the file if it exists. Boot services have to still be up.
+pub fn write(root_directory: &mut File, path: &str) -> Result<(), ::gnu_efi::def::Status> {
+    ::serial::SERIAL_WRITER.lock().set_echo(None);
+    let text = unsafe { &BOOT_LOG.bytes[..BOOT_LOG.length] };
+
+    let mut file = root_directory.open(path, OpenMode::Create)?;
+    file.set_size(0)?;
+    let written = file.write(text)?;
+    if written != text.len() {
+        return Err(::gnu_efi::def::Status::VolumeFull);
+    }
+    file.flush()
+}
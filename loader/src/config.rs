@@ -0,0 +1,266 @@
+use core::fmt::Write;
+
+/// Read when `EFI\OS\BOOT.CFG` doesn't name a kernel
+pub const DEFAULT_KERNEL_PATH: &'static str = "EFI\\OS\\KERNEL.EFI";
+
+/// Where the loader's files live on the volume
+pub const OS_DIRECTORY: &'static str = "EFI\\OS";
+
+/// Where the optional boot configuration lives on the volume
+pub const CONFIG_PATH: &'static str = "EFI\\OS\\BOOT.CFG";
+
+/// Most `entry` lines read, any more are ignored
+pub const MAX_CONFIG_ENTRIES: usize = 8;
+
+/// Longest kernel command line the loader passes on
+pub const COMMAND_LINE_CAPACITY: usize = 1024;
+
+/// What `EFI\OS\BOOT.CFG` asks for. Anything it leaves out keeps the
+/// loader's default.
+#[derive(Debug)]
+pub struct BootConfig<'a> {
+    /// `kernel`, relative to the root of the volume
+    pub kernel_path: &'a str,
+    /// `cmdline`, passed to the kernel as it is
+    pub command_line: &'a str,
+    /// `log_level`, one of error, warn, info or debug
+    pub log_level: Option<::serial::LogLevel>,
+    /// `serial_baud`
+    pub serial_baud: Option<u32>,
+    /// `cpus`, how many CPUs the kernel starts, itself included
+    pub cpus: Option<usize>,
+    /// `boot_log`, a file on the volume the loader writes what it
+    /// printed to before starting the kernel
+    pub boot_log: Option<&'a str>,
+    /// `timeout`, seconds the boot menu waits before booting `kernel`
+    pub timeout: Option<usize>,
+    /// `entry` lines, each a kernel path for the boot menu optionally
+    /// followed by the command line to boot it with instead of `cmdline`
+    entries: [&'a str; MAX_CONFIG_ENTRIES],
+    entry_count: usize,
+    /// `module` lines, files read into memory for the kernel
+    modules: [&'a str; ::boot_info::MAX_MODULES],
+    module_count: usize,
+    /// `integrity`, warn or enforce, what to do when the kernel or a
+    /// module doesn't match `EFI\OS\MANIFEST`
+    pub integrity: Option<::manifest::Integrity>,
+    /// `aslr`, on or off, whether the kernel is loaded at a random
+    /// address. Without it the loader's `no_aslr` feature decides.
+    pub aslr: Option<bool>,
+}
+
+impl<'a> Default for BootConfig<'a> {
+    fn default() -> BootConfig<'a> {
+        BootConfig {
+            kernel_path: DEFAULT_KERNEL_PATH,
+            command_line: "",
+            log_level: None,
+            serial_baud: None,
+            cpus: None,
+            boot_log: None,
+            timeout: None,
+            entries: [""; MAX_CONFIG_ENTRIES],
+            entry_count: 0,
+            modules: [""; ::boot_info::MAX_MODULES],
+            module_count: 0,
+            integrity: None,
+            aslr: None,
+        }
+    }
+}
+
+impl<'a> BootConfig<'a> {
+    /// Reads a configuration file. Lines that don't make sense are
+    /// reported and skipped, a typo shouldn't keep the machine from
+    /// booting.
+    pub fn parse(text: &'a str) -> BootConfig<'a> {
+        let mut config = BootConfig::default();
+        for entry in ::options::config_entries(text) {
+            let (key, value) = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    println!("{}:{}: expected key = value", CONFIG_PATH, error.line);
+                    continue;
+                },
+            };
+            let valid = match key {
+                "kernel" => {
+                    config.kernel_path = value;
+                    true
+                },
+                "cmdline" => {
+                    config.command_line = value;
+                    true
+                },
+                "log_level" => {
+                    config.log_level = ::serial::LogLevel::from_name(value);
+                    config.log_level.is_some()
+                },
+                "serial_baud" => {
+                    config.serial_baud = value.parse().ok();
+                    config.serial_baud.is_some()
+                },
+                "cpus" => {
+                    config.cpus = value.parse().ok().and_then(|cpus| if cpus > 0 { Some(cpus) } else { None });
+                    config.cpus.is_some()
+                },
+                "boot_log" => {
+                    config.boot_log = Some(value);
+                    true
+                },
+                "timeout" => {
+                    config.timeout = value.parse().ok();
+                    config.timeout.is_some()
+                },
+                "entry" => {
+                    if config.entry_count == MAX_CONFIG_ENTRIES {
+                        println!("{}: more than {} entries, ignoring {}", CONFIG_PATH, MAX_CONFIG_ENTRIES, value);
+                    } else {
+                        config.entries[config.entry_count] = value;
+                        config.entry_count += 1;
+                    }
+                    true
+                },
+                "module" => {
+                    if value.len() > ::boot_info::MODULE_NAME_SIZE {
+                        println!("{}: module path {} is longer than {} bytes, ignoring it",
+                                 CONFIG_PATH, value, ::boot_info::MODULE_NAME_SIZE);
+                    } else if config.module_count == ::boot_info::MAX_MODULES {
+                        println!("{}: more than {} modules, ignoring {}", CONFIG_PATH, ::boot_info::MAX_MODULES, value);
+                    } else {
+                        config.modules[config.module_count] = value;
+                        config.module_count += 1;
+                    }
+                    true
+                },
+                "integrity" => {
+                    config.integrity = ::manifest::Integrity::from_name(value);
+                    config.integrity.is_some()
+                },
+                "aslr" => {
+                    config.aslr = match value {
+                        "on" => Some(true),
+                        "off" => Some(false),
+                        _ => None,
+                    };
+                    config.aslr.is_some()
+                },
+                _ => {
+                    println!("{}: unknown key {}", CONFIG_PATH, key);
+                    continue;
+                },
+            };
+            if !valid {
+                println!("{}: bad value {} for {}", CONFIG_PATH, value, key);
+            }
+        }
+        config
+    }
+
+    pub fn entries(&self) -> &[&'a str] {
+        &self.entries[..self.entry_count]
+    }
+
+    pub fn modules(&self) -> &[&'a str] {
+        &self.modules[..self.module_count]
+    }
+
+    /// The command line the kernel gets: `cmdline`, followed by the
+    /// options the kernel applies itself unless `cmdline` already sets
+    /// them
+    pub fn kernel_command_line(&self) -> CommandLineBuffer {
+        let mut buffer = CommandLineBuffer::new();
+        let command_line = ::options::CommandLine::new(self.command_line);
+        let mut result = write!(buffer, "{}", self.command_line);
+        if let Some(log_level) = self.log_level {
+            if command_line.get("log_level").is_none() {
+                result = result.and_then(|_| write!(buffer, " log_level={}", log_level.name()));
+            }
+        }
+        if let Some(serial_baud) = self.serial_baud {
+            if command_line.get("serial_baud").is_none() {
+                result = result.and_then(|_| write!(buffer, " serial_baud={}", serial_baud));
+            }
+        }
+        if let Some(cpus) = self.cpus {
+            if command_line.get("cpus").is_none() {
+                result = result.and_then(|_| write!(buffer, " cpus={}", cpus));
+            }
+        }
+        if result.is_err() {
+            println!("Kernel command line is longer than {} bytes, truncating it", COMMAND_LINE_CAPACITY);
+        }
+        buffer
+    }
+}
+
+/// A command line being put together, which is cut off at
+/// `COMMAND_LINE_CAPACITY` bytes
+pub struct CommandLineBuffer {
+    bytes: [u8; COMMAND_LINE_CAPACITY],
+    length: usize,
+}
+
+impl CommandLineBuffer {
+    pub fn new() -> CommandLineBuffer {
+        CommandLineBuffer {
+            bytes: [0; COMMAND_LINE_CAPACITY],
+            length: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.length]
+    }
+}
+
+impl Write for CommandLineBuffer {
+    /// Keeps what fits of `string`, failing if that isn't all of it
+    fn write_str(&mut self, string: &str) -> ::core::fmt::Result {
+        let fits = ::core::cmp::min(string.len(), COMMAND_LINE_CAPACITY - self.length);
+        self.bytes[self.length..self.length + fits].copy_from_slice(&string.as_bytes()[..fits]);
+        self.length += fits;
+        if fits == string.len() {
+            Ok(())
+        } else {
+            Err(::core::fmt::Error)
+        }
+    }
+}
+
+/// Whether two paths name the same file on a FAT volume, where case
+/// doesn't matter, with either kind of slash and with or without a
+/// leading one
+pub fn same_path(a: &str, b: &str) -> bool {
+    fn normalize(byte: u8) -> u8 {
+        match byte {
+            b'a'...b'z' => byte - b'a' + b'A',
+            b'/' => b'\\',
+            _ => byte,
+        }
+    }
+    let a = a.trim_left_matches(|c| c == '/' || c == '\\');
+    let b = b.trim_left_matches(|c| c == '/' || c == '\\');
+    a.len() == b.len() && a.bytes().zip(b.bytes()).all(|(a, b)| normalize(a) == normalize(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BootConfig, same_path};
+
+    #[test]
+    fn paths() {
+        assert!(same_path("EFI\\OS\\debug.efi", "EFI\\OS\\DEBUG.EFI"));
+        assert!(same_path("efi/os/kernel.efi", "\\EFI\\OS\\KERNEL.EFI"));
+        assert!(!same_path("EFI\\OS\\debug.efi", "EFI\\OS\\KERNEL.EFI"));
+        assert!(!same_path("EFI\\OS", "EFI\\OS\\KERNEL.EFI"));
+    }
+
+    #[test]
+    fn aslr() {
+        assert_eq!(BootConfig::parse("").aslr, None);
+        assert_eq!(BootConfig::parse("aslr = off").aslr, Some(false));
+        assert_eq!(BootConfig::parse("aslr = on").aslr, Some(true));
+        assert_eq!(BootConfig::parse("aslr = maybe").aslr, None);
+    }
+}
@@ -27,181 +27,430 @@ extern crate frame_allocator as falloc;
 
 extern crate elf;
 
+extern crate boot_info;
+
+extern crate options;
+
+extern crate sha256;
+
 //mod palloc;
 
-static mut INIT_RAM_PAGES: usize = 0;
+// Entropy for randomizing the kernel's layout
+mod random;
+
+// EFI\OS\BOOT.CFG
+mod config;
+
+// Copy of the loader's output, for EFI\OS\BOOT.CFG's boot_log
+mod boot_log;
+
+// Choosing between the kernels on the volume
+mod menu;
+
+// EFI\OS\MANIFEST, the digests the kernel and modules should have
+mod manifest;
+
+use gnu_efi::api::protocol::File;
+use gnu_efi::api::protocol::file_protocol::OpenMode;
+
+/// Pages of the kernel's boot stack
+const KERNEL_STACK_PAGES: usize = 16;
+
+/// Largest kernel file the loader reads
+const MAX_KERNEL_SIZE: usize = 64 << 20;
+
+/// Largest boot module the loader reads
+const MAX_MODULE_SIZE: usize = 512 << 20;
+
+/// Largest manifest the loader reads
+const MAX_MANIFEST_SIZE: usize = 64 << 10;
+
+/// Size of the region the kernel image and stack are placed in, the
+/// PML4 entry at `page_table::KERNEL_IMAGE_START`. The image goes in
+/// the lower half and the stack in the upper half.
+const KERNEL_IMAGE_REGION_SIZE: usize = 1 << 39;
+
+/// Size of the region from `mem::DIRECT_MAP_OFFSET` the direct map is
+/// placed in, 64 PML4 entries
+const DIRECT_MAP_REGION_SIZE: usize = 64 << 39;
+
+/// The kernel's entry point. Everything it is told is in the
+/// `BootInfo`, reached through the direct map.
+type KernelEntry = extern fn(&'static boot_info::BootInfo) -> !;
 
 struct StackData {
     elf_file: elf::File<'static>,
     page_table: page_table::PageTable,
-    system_table: &'static gnu_efi::api::SystemTable,
+    boot_info: boot_info::BootInfo,
+    command_line: config::CommandLineBuffer,
+    layout: KernelLayout,
 }
 
 static mut STACK_DATA_GLOBAL: Option<StackData> = None;
 
+struct KernelHandoff {
+    entry: KernelEntry,
+    boot_info: &'static boot_info::BootInfo,
+}
+
+static mut KERNEL_HANDOFF_GLOBAL: Option<KernelHandoff> = None;
+
+/// Why a file couldn't be read into memory
+#[derive(Clone, Copy, Debug)]
+enum ReadError {
+    /// The firmware can't say how big the file is
+    NoFileInfo(gnu_efi::def::Status),
+    /// Larger than the most the loader reads of that kind of file
+    TooLarge(usize),
+    OutOfMemory(gnu_efi::def::Status),
+    ReadFailed(gnu_efi::def::Status),
+    /// Fewer bytes could be read than the file has
+    ShortRead { read: usize, size: usize },
+}
+
+/// Why the loader won't boot the kernel
+#[derive(Clone, Copy, Debug)]
+enum KernelError {
+    /// No volume has the file the boot configuration names
+    NotFound,
+    Read(ReadError),
+    Empty,
+    BadElf(elf::ElfError),
+    /// The kernel expects another version of the BootInfo
+    BootInfoVersion(u32),
+    /// The kernel doesn't say which BootInfo version it expects
+    NoBootInfoVersion,
+    DigestMismatch(DigestMismatch),
+}
+
+/// A file's SHA-256 isn't the one `manifest::MANIFEST_PATH` lists
+#[derive(Clone, Copy, Debug)]
+struct DigestMismatch {
+    expected: sha256::Digest,
+    actual: sha256::Digest,
+}
+
+/// Where the kernel's image, stack and direct map go this boot
+#[derive(Clone, Copy, Debug)]
+struct KernelLayout {
+    image_base: usize,
+    stack_top: usize,
+    direct_map_offset: usize,
+}
+
+impl KernelLayout {
+    /// Places each part at a random offset in its region, or at the
+    /// start of it when `randomize` is off
+    fn new(elf_file: &elf::File, memory_map: &gnu_efi::def::MemoryDescriptors, randomize: bool) -> Self {
+        let offset = |limit: usize, alignment: usize| {
+            if randomize { random::random_below(limit, alignment) } else { 0 }
+        };
+        let half_region = KERNEL_IMAGE_REGION_SIZE / 2;
+        // The page below the stack stays unmapped as a guard
+        let stack_size = (KERNEL_STACK_PAGES + 1) * 0x1000;
+        let direct_map_size = mem::PhysicalAddress::new(physical_memory_end(memory_map)).align_up(0x4000_0000);
+        let direct_map_size: usize = direct_map_size.into();
+
+        KernelLayout {
+            image_base: page_table::KERNEL_IMAGE_START +
+                offset(half_region - image_size(elf_file), 0x20_0000),
+            stack_top: page_table::KERNEL_IMAGE_START + half_region +
+                offset(half_region - stack_size, 0x1000) + stack_size,
+            direct_map_offset: mem::DIRECT_MAP_OFFSET +
+                offset(DIRECT_MAP_REGION_SIZE - direct_map_size, 0x4000_0000),
+        }
+    }
+}
+
 /// This is the entry point for the rust language part of the
 /// OS. At this point all UEFI code can still be run, and
 /// we haven't yet exited boot services
 #[no_mangle]
 pub extern fn rust_main(image_handle:gnu_efi::def::Handle,
                         system_table:&mut gnu_efi::api::SystemTable) -> ! {
+    boot_log::start();
+
     // Get all handles supporting simple_file_protocol
     let handles = system_table.boot_services.retrieve_handles_with_protocol::<gnu_efi::api::protocol::SimpleFileSystemProtocol>();
 
-    let size = 512000;
-    if let Ok(mut buffer) = system_table.boot_services.allocate_pages((size + 0x200) / 0x200) {
-        // Retrieve the kernel efi file
-        let file = handles.unwrap().iter().filter_map(|handle| -> Option<&mut gnu_efi::api::protocol::SimpleFileSystemProtocol> {
-            // Retrieve the protocol based off of the handle, filtering
-            // when the protocol doesn't exist
-            system_table.boot_services.retrieve_protocol_from_handle(handle).ok()
-        }).filter_map(|protocol| {
-            // Open each found volume
-            protocol.open_volume().ok()
-        }).filter_map(|root_directory| {
-            // Try to navigate to the kernel efi for each found volume
-            root_directory.open("EFI\\OS\\KERNEL.EFI").ok()
-        }).next();
-
-        // Read the efi file into memory, and parse it into an elf
-        // file structure
-        let elf_kernel = file.and_then(|file| {
-            // Read the efi file into memory
-            (*file).read(size, buffer.get_mut_pointer()).ok().map(|file_buffer| {
-                // Initialize the elf_structure
-                elf::File::from_buffer(file_buffer)
-            })
-        });
-
-        // Allocate the page for the new stack
-        let mut new_stack_page = system_table.boot_services.allocate_pages(10).unwrap();
-        //let mut new_gdt_page = system_table.boot_services.allocate_pages(1).unwrap();
-
-        // Use efilib to get memory map, involves allocating from UEFI
-        // because we don't have control of all memory yet
-        let (memory_map, map_key) = gnu_efi::efilib::lib_memory_map();
-
-        // Exit boot services. At this point the rust kernel
-        // can do whatever it wants as long as it doesn't kill
-        // the runtime services code
-        system_table.boot_services.exit_boot_services(
-            &image_handle,
-            map_key);
+    // Retrieve the kernel efi file
+    let volume = handles.unwrap().iter().filter_map(|handle| -> Option<&mut gnu_efi::api::protocol::SimpleFileSystemProtocol> {
+        // Retrieve the protocol based off of the handle, filtering
+        // when the protocol doesn't exist
+        system_table.boot_services.retrieve_protocol_from_handle(handle).ok()
+    }).filter_map(|protocol| {
+        // Open each found volume
+        protocol.open_volume().ok()
+    }).filter_map(|mut root_directory| {
+        // Look for the kernels the volume's boot configuration
+        // names, and any others next to them
+        let config = read_config(&system_table.boot_services, &mut root_directory);
+        let entries = menu::find_entries(&mut root_directory, &config);
+        if entries.is_empty() {
+            None
+        } else {
+            Some((root_directory, config, entries))
+        }
+    }).next();
+
+    let (mut root_directory, mut config, entries) = match volume {
+        Some((root_directory, config, entries)) => (Some(root_directory), config, entries),
+        None => {
+            println!("No volume has a kernel to boot");
+            (None, config::BootConfig::default(), menu::BootEntries::new())
+        },
+    };
+    apply_config(&config);
+    debugln!("{:?}", config);
+    if serial::log_enabled(serial::LogLevel::Debug) {
+        if let Some(ref mut root_directory) = root_directory {
+            print_directory(root_directory, config::OS_DIRECTORY);
+        }
+    }
+
+    let entry = menu::choose(system_table, &entries, config.timeout.unwrap_or(menu::DEFAULT_TIMEOUT));
+    let kernel_path = entry.map(|entry| entry.path()).unwrap_or(config.kernel_path);
+    if let Some(command_line) = entry.and_then(|entry| entry.command_line) {
+        config.command_line = command_line;
+    }
+    println!("Booting {} with command line \"{}\"", kernel_path, config.command_line);
+    let command_line = config.kernel_command_line();
+
+    // Without a manifest the files are only hashed, for the kernel to
+    // log. With one, a file that doesn't match it is refused unless the
+    // configuration asks for just a warning.
+    let digests = root_directory.as_mut().and_then(|root_directory| {
+        read_manifest(&system_table.boot_services, root_directory)
+    });
+    let integrity = config.integrity.unwrap_or(manifest::Integrity::Enforce);
+    let randomize = config.aslr.unwrap_or(cfg!(not(feature = "no_aslr")));
+
+    // Read the efi file into memory, and parse it into an elf
+    // file structure
+    let file = match (entry, root_directory.as_mut()) {
+        (Some(_), Some(root_directory)) => root_directory.open(kernel_path, OpenMode::Read).ok(),
+        _ => None,
+    };
+    let kernel_file = match file {
+        Some(mut file) => read_kernel(&system_table.boot_services, &mut file),
+        None => Err(KernelError::NotFound),
+    }.and_then(|data| {
+        verify(digests.as_ref(), integrity, kernel_path, data)
+            .map(|digest| (data, digest))
+            .map_err(KernelError::DigestMismatch)
+    });
+    let mut elf_kernel = match kernel_file.and_then(|(data, _)| parse_kernel(data)) {
+        Ok(elf_file) => Some(elf_file),
+        Err(error) => {
+            println!("Can't boot {}: {:?}", kernel_path, error);
+            None
+        },
+    };
+
+    // Everything the kernel is told about the firmware has to be
+    // gathered while boot services are still up
+    let mut boot_info = boot_info::BootInfo::new();
+    if let Ok((_, digest)) = kernel_file {
+        boot_info.kernel_digest = digest.0;
+    }
+    if let Some(framebuffer) = find_framebuffer(&system_table.boot_services) {
+        boot_info.framebuffer = framebuffer;
+    }
+    if let Some(rsdp) = system_table.get_vendor_table(&gnu_efi::api::types::ACPI_20_TABLE_GUID) {
+        boot_info.rsdp = rsdp.handle as u64;
+    }
+    random::seed_from_firmware(&system_table.boot_services);
+
+    // The volume can't be read, written or even closed once boot
+    // services are gone
+    if let Some(mut root_directory) = root_directory {
+        if elf_kernel.is_some() {
+            let loaded = load_modules(&system_table.boot_services, &mut root_directory, &config,
+                                      digests.as_ref(), integrity, &mut boot_info);
+            if let Err(error) = loaded {
+                println!("Can't boot {}, a module doesn't match {}: {:?}",
+                         kernel_path, manifest::MANIFEST_PATH, error);
+                elf_kernel = None;
+            }
+        }
+        if let Some(path) = config.boot_log {
+            if let Err(status) = boot_log::write(&mut root_directory, path) {
+                println!("Can't write the boot log to {}: {:?}", path, status);
+            }
+        }
+    }
 
+    // Allocate the page for the new stack
+    let mut new_stack_page = system_table.boot_services.allocate_pages(10).unwrap();
+    //let mut new_gdt_page = system_table.boot_services.allocate_pages(1).unwrap();
+
+    // Use efilib to get memory map, involves allocating from UEFI
+    // because we don't have control of all memory yet
+    let (memory_map, map_key) = gnu_efi::efilib::lib_memory_map();
+
+    // Exit boot services. At this point the rust kernel
+    // can do whatever it wants as long as it doesn't kill
+    // the runtime services code
+    system_table.boot_services.exit_boot_services(
+        &image_handle,
+        map_key);
+
+    if serial::log_enabled(serial::LogLevel::Debug) {
         print_memory_map(&memory_map);
+    }
+    boot_info.system_table = system_table as *const gnu_efi::api::SystemTable as u64;
+    boot_info.memory_map = boot_info::MemoryMap {
+        address: memory_map.as_ptr() as u64,
+        descriptor_count: memory_map.len() as u64,
+        descriptor_size: memory_map.descriptor_size() as u64,
+    };
 
-        if let Some(elf_file) = elf_kernel {
-            println!("{:?}", elf_file.file_header());
-            // Initialize page table
-            let mut page_table = unsafe {
-                page_table::PageTable::new(
-                    falloc::FRAME_ALLOCATOR.get_frame())
-            };
+    // Build the frame allocator from the final memory map, keeping
+    // the LoaderData buffers we are still using out of the free set.
+    // Boot services memory isn't in it, we are still on the firmware's
+    // page tables and stack, so the kernel reclaims it later.
+    let frame_allocator = falloc::FrameAllocator::new(&memory_map).and_then(|mut frame_allocator| {
+        if let Ok((kernel_file, _)) = kernel_file {
+            frame_allocator.reserve_range(
+                mem::PhysicalAddress::new(kernel_file.as_ptr() as usize),
+                kernel_file.len())?;
+        }
+        for module in boot_info.modules().iter().filter(|module| module.size > 0) {
+            frame_allocator.reserve_range(
+                mem::PhysicalAddress::new(module.address as usize),
+                module.size as usize)?;
+        }
+        frame_allocator.reserve_range(
+            mem::PhysicalAddress::new(new_stack_page.as_ptr() as usize),
+            10 * 0x1000)?;
+        frame_allocator.reserve_range(
+            mem::PhysicalAddress::new(memory_map.as_ptr() as usize),
+            memory_map.buffer_size())?;
+        Ok(frame_allocator)
+    });
+    match frame_allocator {
+        Ok(frame_allocator) => unsafe {
+            println!("{} free frames", frame_allocator.free_frame_count());
+            falloc::FRAME_ALLOCATOR = frame_allocator;
+        },
+        Err(error) => {
+            println!("Can't build the frame allocator from the memory map: {:?}", error);
+            elf_kernel = None;
+        },
+    }
 
-            {
-                // Create mapping for existing code
-                for memory_descriptor in &memory_map {
-                    use ::gnu_efi::def::MemoryType;
-                    let keep = match memory_descriptor.region_type {
-                        MemoryType::LoaderCode => true,
-                        MemoryType::LoaderData => true,
-                        MemoryType::RuntimeServicesCode => true,
-                        MemoryType::RuntimeServicesData => true,
-                        MemoryType::ACPIMemoryNVS => true,
-                        MemoryType::ACPIReclaimMemory => true,
-                        MemoryType::PalCode => false,
-                        _ => false,
-                    };
-
-                    if keep {
-                        // Add pages for each page
-                        let frame_start: mem::Frame = memory_descriptor.physical_start.into();
-                        let frame_number: usize = frame_start.into();
-                        let page_start: mem::Page = mem::Page::new(frame_number);
-                        for offset in 0isize..memory_descriptor.number_of_pages as isize {
-                            let new_page = page_start + mem::PageOffset::new(offset);
-                            let new_frame = frame_start + mem::FrameOffset::new(offset);
-
-                            page_table.insert_page(new_frame, new_page, page_table::PageSize::FourKb);
-                        }
+    if let Some(elf_file) = elf_kernel {
+        debugln!("{:?}", elf_file.file_header());
+        if let Some((symbol, offset)) = elf_file.symbolize(elf_file.file_header().entry) {
+            debugln!("Kernel entry is {}+{:#x}", symbol.name, offset);
+        }
+        // Initialize page table
+        let mut page_table = unsafe {
+            page_table::PageTable::new(
+                falloc::FRAME_ALLOCATOR.get_frame(falloc::FrameUsage::PageTable).unwrap())
+        };
+
+        {
+            // Create mapping for existing code
+            for memory_descriptor in &memory_map {
+                use ::gnu_efi::def::MemoryType;
+                let keep = match memory_descriptor.region_type {
+                    MemoryType::LoaderCode => true,
+                    MemoryType::LoaderData => true,
+                    MemoryType::RuntimeServicesCode => true,
+                    MemoryType::RuntimeServicesData => true,
+                    MemoryType::ACPIMemoryNVS => true,
+                    MemoryType::ACPIReclaimMemory => true,
+                    MemoryType::PalCode => false,
+                    _ => false,
+                };
+
+                if keep {
+                    // Identity map each page
+                    let frames = mem::FrameRange::starting_at(
+                        memory_descriptor.physical_start.into(),
+                        memory_descriptor.number_of_pages as usize);
+                    for frame in frames {
+                        let page = mem::Page::new(frame.into());
+                        page_table.insert_page(frame, page, page_table::PageSize::FourKb, Default::default());
                     }
                 }
             }
+        }
 
-            // Add a mapping for the first init_ram_pages pages
-            //
-            unsafe {
-                INIT_RAM_PAGES = 0x1000;
-
-                let start_page: usize = 0;
-                for offset in 1..INIT_RAM_PAGES {
-                    page_table.insert_page(
-                        mem::Frame::new(start_page + offset),
-                        mem::Page::new(start_page + offset),
-                        page_table::PageSize::FourKb);
-                }
-            }
+        // Identity map low memory. Page 0 stays unmapped to catch null
+        // pointers.
+        identity_map(&mut page_table, 1, page_table::LOW_IDENTITY_PAGES - 1);
 
-            /*
-            // Initialize the GDT
-            unsafe {
-                use x86::shared::segmentation;
-                use x86::shared::segmentation::{SegmentDescriptor, Type};
-                use x86::shared::segmentation::{CODE_READ, DATA_WRITE};
-                use x86::shared::PrivilegeLevel;
-                use x86::shared::dtables::DescriptorTablePointer;
-                let segment_descriptors: &mut [SegmentDescriptor] = core::slice::from_raw_parts_mut(new_gdt_page.as_mut_ptr() as *mut SegmentDescriptor, 512);
-                segment_descriptors[0] = SegmentDescriptor::NULL;
-                segment_descriptors[1] = SegmentDescriptor::new(0, 0, Type::Code(CODE_READ), false, PrivilegeLevel::Ring0);
-                segment_descriptors[2] = SegmentDescriptor::new(0, 0, Type::Data(DATA_WRITE), false, PrivilegeLevel::Ring0);
-                segment_descriptors[3] = SegmentDescriptor::new(0, 0, Type::Code(CODE_READ), false, PrivilegeLevel::Ring3);
-                segment_descriptors[4] = SegmentDescriptor::new(0, 0, Type::Data(DATA_WRITE), false, PrivilegeLevel::Ring3);
-                let gdt: DescriptorTablePointer<SegmentDescriptor> = DescriptorTablePointer::new_gdtp(segment_descriptors);
-
-                //x86::shared::dtables::lgdt(&gdt);
-
-                /*#[repr(Packed)]
-                struct LJmp {
-                    selector: u16,
-                    offset: u64,
-                };
+        // Pick where the kernel goes, and map all of RAM into the
+        // higher half at the chosen offset
+        let layout = KernelLayout::new(&elf_file, &memory_map, randomize);
+        unsafe {
+            mem::set_direct_map_offset(layout.direct_map_offset);
+        }
+        direct_map(&mut page_table, &memory_map);
 
-                let LJmp = 
-
-                asm!("\
-                        movw $$8, ax
-                        ljmpq ax, next_instruction
-                        next_instruction: nop
-                        ");*/
-
-                //let data_selector = segmentation::SegmentSelector::new(2, PrivilegeLevel::Ring0);
-                //segmentation::load_ss(data_selector);
-            }*/
-
-            // Initialize a new stack
-            unsafe {
-                println!("saving stack variables to globals");
-                // Save current stack variables to globals
-                let stack_data = StackData {
-                    elf_file: ::core::mem::transmute(elf_file),
-                    page_table: page_table,
-                    system_table: ::core::mem::transmute(system_table),
-                };
+        /*
+        // Initialize the GDT
+        unsafe {
+            use x86::shared::segmentation;
+            use x86::shared::segmentation::{SegmentDescriptor, Type};
+            use x86::shared::segmentation::{CODE_READ, DATA_WRITE};
+            use x86::shared::PrivilegeLevel;
+            use x86::shared::dtables::DescriptorTablePointer;
+            let segment_descriptors: &mut [SegmentDescriptor] = core::slice::from_raw_parts_mut(new_gdt_page.as_mut_ptr() as *mut SegmentDescriptor, 512);
+            segment_descriptors[0] = SegmentDescriptor::NULL;
+            segment_descriptors[1] = SegmentDescriptor::new(0, 0, Type::Code(CODE_READ), false, PrivilegeLevel::Ring0);
+            segment_descriptors[2] = SegmentDescriptor::new(0, 0, Type::Data(DATA_WRITE), false, PrivilegeLevel::Ring0);
+            segment_descriptors[3] = SegmentDescriptor::new(0, 0, Type::Code(CODE_READ), false, PrivilegeLevel::Ring3);
+            segment_descriptors[4] = SegmentDescriptor::new(0, 0, Type::Data(DATA_WRITE), false, PrivilegeLevel::Ring3);
+            let gdt: DescriptorTablePointer<SegmentDescriptor> = DescriptorTablePointer::new_gdtp(segment_descriptors);
+
+            //x86::shared::dtables::lgdt(&gdt);
 
-                ::core::mem::replace(&mut STACK_DATA_GLOBAL, Some(stack_data));
+            /*#[repr(Packed)]
+            struct LJmp {
+                selector: u16,
+                offset: u64,
+            };
 
-                // Set stack to be a new ebp/esp
-                let stack_address: *mut u8 = new_stack_page.as_mut_ptr().offset(0x9_000);
-                asm!("mov $0, %rsp" :: "r" (stack_address as usize) : "memory");
-                asm!("push $$0");
-                asm!("push $$0");
-                asm!("mov %rsp, %rbp");
+            let LJmp = 
 
-                // Call into the new_stack function to reset local variables
-                let StackData { elf_file, page_table, system_table } =
-                    ::core::mem::replace(&mut STACK_DATA_GLOBAL, None).unwrap();
-                new_stack(elf_file, page_table, system_table);
-            }
+            asm!("\
+                    movw $$8, ax
+                    ljmpq ax, next_instruction
+                    next_instruction: nop
+                    ");*/
+
+            //let data_selector = segmentation::SegmentSelector::new(2, PrivilegeLevel::Ring0);
+            //segmentation::load_ss(data_selector);
+        }*/
+
+        // Initialize a new stack
+        unsafe {
+            println!("saving stack variables to globals");
+            // Save current stack variables to globals
+            let stack_data = StackData {
+                elf_file: ::core::mem::transmute(elf_file),
+                page_table: page_table,
+                boot_info: boot_info,
+                command_line: command_line,
+                layout: layout,
+            };
+
+            ::core::mem::replace(&mut STACK_DATA_GLOBAL, Some(stack_data));
+
+            // Set stack to be a new ebp/esp
+            let stack_address: *mut u8 = new_stack_page.as_mut_ptr().offset(0x9_000);
+            asm!("mov $0, %rsp" :: "r" (stack_address as usize) : "memory");
+            asm!("push $$0");
+            asm!("push $$0");
+            asm!("mov %rsp, %rbp");
+
+            // Call into the new_stack function to reset local variables
+            let StackData { elf_file, page_table, boot_info, command_line, layout } =
+                ::core::mem::replace(&mut STACK_DATA_GLOBAL, None).unwrap();
+            new_stack(elf_file, page_table, boot_info, command_line, layout);
         }
     }
 
@@ -212,68 +461,617 @@ pub extern fn rust_main(image_handle:gnu_efi::def::Handle,
         core::ptr::null());
 }
 
-fn new_stack(elf_file: elf::File, mut page_table: page_table::PageTable, system_table: &::gnu_efi::api::SystemTable) -> ! {
+fn new_stack(elf_file: elf::File, mut page_table: page_table::PageTable, mut boot_info: boot_info::BootInfo,
+             command_line: config::CommandLineBuffer, layout: KernelLayout) -> ! {
     // Load page tables
     page_table.load();
 
-    // For section in section_headers
-    for section_header in elf_file.section_headers() {
-        println!("{:?}", section_header);
-        match section_header.section_type {
-            SectionType::NoBits | SectionType::ProgramBits
-                    if ((section_header.virtual_address as usize) >=
-                        0x8000000000) => {
-                let size_bytes = mem::VirtualAddressOffset::new(section_header.size as isize);
-                let num_pages: mem::PageOffset = size_bytes.into();
-                let num_pages_isize: isize = num_pages.into();
-                let start_page: mem::Page = {
-                    let start_virtual_address = mem::VirtualAddress::new(section_header.virtual_address as usize);
-                    start_virtual_address.into()
-                };
-                let start_frame: mem::Frame = unsafe { falloc::FRAME_ALLOCATOR.get_multiple_frames(num_pages_isize as usize) };
+    let slide = kernel_slide(&elf_file, layout.image_base);
+    let kernel_base = link_base(&elf_file).wrapping_add(slide);
+    println!("Loading kernel at {:#x} with slide {:#x}, stack top at {:#x}, direct map at {:#x}",
+        kernel_base, slide, layout.stack_top, layout.direct_map_offset);
+
+    let image_frame = load_segments(&elf_file, &mut page_table, slide);
+    relocate(&elf_file, &page_table, slide);
+    load_trampoline(&elf_file);
+    let stack_frame = map_kernel_stack(&mut page_table, layout.stack_top);
+
+    if serial::log_enabled(serial::LogLevel::Debug) {
+        println!("Page table at handoff:");
+        page_table.print_mappings();
+    }
+
+    let image_address: mem::PhysicalAddress = image_frame.into();
+    let image_address: usize = image_address.into();
+    let stack_address: mem::PhysicalAddress = stack_frame.into();
+    let stack_address: usize = stack_address.into();
+    let pml4_address: mem::PhysicalAddress = page_table.pml4_frame().into();
+    let pml4_address: usize = pml4_address.into();
+    boot_info.kernel = boot_info::KernelLayout {
+        virtual_base: kernel_base as u64,
+        physical_base: image_address as u64,
+        size: image_pages(&elf_file) as u64 * 0x1000,
+        slide: slide as u64,
+        stack_top: layout.stack_top as u64,
+        stack_size: KERNEL_STACK_PAGES as u64 * 0x1000,
+        stack_physical_base: stack_address as u64,
+        direct_map_offset: layout.direct_map_offset as u64,
+    };
+    boot_info.page_table = pml4_address as u64;
+    // The loader runs identity mapped, so this is also its physical
+    // address. The kernel takes the allocator over from here.
+    boot_info.frame_allocator = unsafe { &falloc::FRAME_ALLOCATOR as *const falloc::FrameAllocator as u64 };
+    let boot_info = write_boot_info(boot_info, command_line.as_bytes());
+
+    unsafe {
+        let entry: KernelEntry = core::mem::transmute(elf_file.file_header().entry.wrapping_add(slide));
+
+        // Switch to the kernel's stack the same way we switched to
+        // this one, through a global
+        let handoff = KernelHandoff {
+            entry: entry,
+            boot_info: boot_info,
+        };
+        ::core::mem::replace(&mut KERNEL_HANDOFF_GLOBAL, Some(handoff));
 
-                for offset in 0..num_pages_isize {
-                    let page = start_page + mem::PageOffset::new(offset);
-                    let frame = start_frame + mem::FrameOffset::new(offset);
+        asm!("mov $0, %rsp" :: "r" (layout.stack_top) : "memory");
+        asm!("push $$0");
+        asm!("push $$0");
+        asm!("mov %rsp, %rbp");
 
-                    println!("map page {:?} into frame {:?}", page, frame);
+        let KernelHandoff { entry, boot_info } =
+            ::core::mem::replace(&mut KERNEL_HANDOFF_GLOBAL, None).unwrap();
+        run_kernel(entry, boot_info);
+    }
+}
 
-                    page_table.insert_page(frame, page, page_table::PageSize::FourKb);
+/// Jumps to the kernel. Kept out of line as gdb.py breaks on it.
+fn run_kernel(entry: KernelEntry, boot_info: &'static boot_info::BootInfo) -> ! {
+    entry(boot_info);
+}
+
+/// Reads a whole file of at most `max_size` bytes into LoaderData
+/// pages allocated for exactly its size, which are never freed
+fn read_file(boot_services: &gnu_efi::api::BootServices,
+             file: &mut File, max_size: usize) -> Result<&'static [u8], ReadError> {
+    let size = file.get_info().map_err(ReadError::NoFileInfo)?.file_size as usize;
+    if size == 0 {
+        return Ok(&[]);
+    }
+    if size > max_size {
+        return Err(ReadError::TooLarge(size));
+    }
+
+    let pages = (size + 0xFFF) / 0x1000;
+    let (buffer, _) = boot_services.allocate_pages(pages).map_err(ReadError::OutOfMemory)?.into_raw_parts();
+    let data = unsafe { core::slice::from_raw_parts_mut(buffer, size) };
+    let read = file.read(data).map_err(ReadError::ReadFailed)?;
+    if read != size {
+        return Err(ReadError::ShortRead { read: read, size: size });
+    }
+    Ok(data)
+}
+
+fn read_kernel(boot_services: &gnu_efi::api::BootServices,
+               file: &mut File) -> Result<&'static [u8], KernelError> {
+    let data = read_file(boot_services, file, MAX_KERNEL_SIZE).map_err(KernelError::Read)?;
+    if data.is_empty() {
+        return Err(KernelError::Empty);
+    }
+    Ok(data)
+}
+
+/// Reads the boot configuration's modules for the kernel. One that
+/// can't be read is left out, the kernel may manage without it, but one
+/// that doesn't match the manifest stops the boot if `integrity` says
+/// so.
+fn load_modules(boot_services: &gnu_efi::api::BootServices, root_directory: &mut File,
+                config: &config::BootConfig, digests: Option<&manifest::Manifest>,
+                integrity: manifest::Integrity,
+                boot_info: &mut boot_info::BootInfo) -> Result<(), DigestMismatch> {
+    for &path in config.modules() {
+        let data = match root_directory.open(path, OpenMode::Read) {
+            Ok(mut file) => read_file(boot_services, &mut file, MAX_MODULE_SIZE),
+            Err(status) => {
+                println!("Can't open module {}: {:?}", path, status);
+                continue;
+            },
+        };
+        match data {
+            Ok(data) => {
+                debugln!("Module {} is {} bytes at {:p}", path, data.len(), data.as_ptr());
+                let digest = verify(digests, integrity, path, data)?;
+                match boot_info::Module::new(data.as_ptr() as u64, data.len() as u64, path, digest.0) {
+                    Some(module) => {
+                        if boot_info.add_module(module).is_err() {
+                            println!("No room to pass on module {}", path);
+                        }
+                    },
+                    None => println!("Module path {} is longer than {} bytes, skipping it",
+                                     path, boot_info::MODULE_NAME_SIZE),
                 }
             },
-            _ => {},
+            Err(error) => println!("Can't load module {}: {:?}", path, error),
         }
-        use ::elf::SectionType;
-        // Copy section to destination
-        unsafe {
-            match section_header.section_type {
-                SectionType::NoBits =>
-                    { rlibc::memset(section_header.addr_ptr(), 0, section_header.size); },
-                SectionType::ProgramBits =>
-                    { rlibc::memcpy(section_header.addr_ptr(), section_header.offset_buffer(&elf_file).as_ptr(), section_header.size); },
-                _ => {},
+    }
+    Ok(())
+}
+
+/// Hashes a file the loader read, comparing it to the manifest if the
+/// volume has one. Gives the digest, unless the file doesn't match and
+/// `integrity` says not to use it.
+fn verify(digests: Option<&manifest::Manifest>, integrity: manifest::Integrity,
+          path: &str, data: &[u8]) -> Result<sha256::Digest, DigestMismatch> {
+    let digest = sha256::digest(data);
+    let check = match digests {
+        Some(digests) => digests.check(path, &digest),
+        None => {
+            debugln!("{} has SHA-256 {}", path, digest);
+            return Ok(digest);
+        },
+    };
+    match check {
+        manifest::Check::Match => debugln!("{} matches {}", path, manifest::MANIFEST_PATH),
+        manifest::Check::Unlisted => {
+            println!("{} isn't in {}, its SHA-256 is {}", path, manifest::MANIFEST_PATH, digest);
+        },
+        manifest::Check::Mismatch(expected) => {
+            println!("{} doesn't match {}, its SHA-256 is {} instead of {}",
+                     path, manifest::MANIFEST_PATH, digest, expected);
+            if integrity == manifest::Integrity::Enforce {
+                return Err(DigestMismatch {
+                    expected: expected,
+                    actual: digest,
+                });
+            }
+        },
+    }
+    Ok(digest)
+}
+
+/// Parses the kernel, refusing kernels that would misread the BootInfo
+fn parse_kernel(data: &'static [u8]) -> Result<elf::File<'static>, KernelError> {
+    let elf_file = elf::File::from_buffer(data).map_err(KernelError::BadElf)?;
+    match kernel_boot_info_version(&elf_file) {
+        Some(boot_info::VERSION) => Ok(elf_file),
+        Some(version) => Err(KernelError::BootInfoVersion(version)),
+        None => Err(KernelError::NoBootInfoVersion),
+    }
+}
+
+/// The `BootInfo` version the kernel declares it understands
+fn kernel_boot_info_version(elf_file: &elf::File) -> Option<u32> {
+    elf_file.find_symbol_by_name(boot_info::VERSION_SYMBOL)
+        .and_then(|symbol| elf_file.symbol_data(&symbol))
+        .and_then(|data| {
+            if data.len() == 4 {
+                Some(data.iter().rev().fold(0, |value, &byte| value << 8 | byte as u32))
+            } else {
+                None
+            }
+        })
+}
+
+/// The linear framebuffer of the first graphics output, if any has one
+fn find_framebuffer(boot_services: &gnu_efi::api::BootServices) -> Option<boot_info::Framebuffer> {
+    use gnu_efi::api::protocol::GraphicsOutputProtocol;
+    use gnu_efi::api::protocol::graphics_output_protocol::PixelFormat;
+
+    let handles = match boot_services.retrieve_handles_with_protocol::<GraphicsOutputProtocol>() {
+        Ok(handles) => handles,
+        Err(_) => return None,
+    };
+    let graphics_output = handles.iter().filter_map(|handle| -> Option<&mut GraphicsOutputProtocol> {
+        boot_services.retrieve_protocol_from_handle(handle).ok()
+    }).next();
+
+    graphics_output.and_then(|graphics_output| {
+        let mode = graphics_output.mode_information();
+        let pixel_format = match mode.pixel_format {
+            PixelFormat::RedGreenBlueReserved8BitPerColor => boot_info::PixelFormat::Rgb,
+            PixelFormat::BlueGreenRedReserved8BitPerColor => boot_info::PixelFormat::Bgr,
+            PixelFormat::BitMask => boot_info::PixelFormat::Unknown,
+            PixelFormat::BltOnly => return None,
+        };
+        let address: usize = graphics_output.frame_buffer_base().into();
+        Some(boot_info::Framebuffer {
+            address: address as u64,
+            size: graphics_output.frame_buffer_size() as u64,
+            width: mode.horizontal_resolution,
+            height: mode.vertical_resolution,
+            stride: mode.pixels_per_scan_line,
+            pixel_format: pixel_format,
+        })
+    })
+}
+
+/// Copies `boot_info` into a frame of its own, where the kernel reaches
+/// it through the direct map, with the command line right after it
+fn write_boot_info(mut boot_info: boot_info::BootInfo, command_line: &[u8]) -> &'static boot_info::BootInfo {
+    let size = core::mem::size_of::<boot_info::BootInfo>();
+    assert!(size + config::COMMAND_LINE_CAPACITY <= 0x1000);
+    let frame = unsafe {
+        falloc::FRAME_ALLOCATOR.get_frame(falloc::FrameUsage::Other)
+    }.unwrap();
+    let frame_address: mem::PhysicalAddress = frame.into();
+    let frame_address: usize = frame_address.into();
+    boot_info.command_line = boot_info::CommandLine {
+        address: (frame_address + size) as u64,
+        length: command_line.len() as u64,
+    };
+
+    let mut destination = mem::PhysicalAddress::new(frame_address).to_direct_map();
+    unsafe {
+        let pointer = destination.as_mut_ptr() as *mut boot_info::BootInfo;
+        core::ptr::write(pointer, boot_info);
+        rlibc::memcpy((pointer as *mut u8).offset(size as isize), command_line.as_ptr(), command_line.len());
+        &*pointer
+    }
+}
+
+/// Reads `config::CONFIG_PATH` from the volume, or gives the defaults
+/// if it has none. The pages the file is read into are never freed.
+fn read_config(boot_services: &gnu_efi::api::BootServices,
+               root_directory: &mut File) -> config::BootConfig<'static> {
+    let mut file = match root_directory.open(config::CONFIG_PATH, OpenMode::Read) {
+        Ok(file) => file,
+        Err(_) => return config::BootConfig::default(),
+    };
+    let size = match file.get_info() {
+        Ok(info) => info.file_size as usize,
+        Err(status) => {
+            println!("Can't read {}: {:?}", config::CONFIG_PATH, status);
+            return config::BootConfig::default();
+        },
+    };
+    if size == 0 {
+        return config::BootConfig::default();
+    }
+    let buffer = match boot_services.allocate_pages((size + 0xFFF) / 0x1000) {
+        Ok(buffer) => buffer.into_raw_parts().0,
+        Err(status) => {
+            println!("No memory to read {}: {:?}", config::CONFIG_PATH, status);
+            return config::BootConfig::default();
+        },
+    };
+    let text = file.read(unsafe { core::slice::from_raw_parts_mut(buffer, size) }).ok().and_then(|read| {
+        let bytes: &'static [u8] = unsafe { core::slice::from_raw_parts(buffer, read) };
+        core::str::from_utf8(bytes).ok()
+    });
+    match text {
+        Some(text) => config::BootConfig::parse(text),
+        None => {
+            println!("Can't read {}, using the defaults", config::CONFIG_PATH);
+            config::BootConfig::default()
+        },
+    }
+}
+
+/// Reads `manifest::MANIFEST_PATH` from the volume, if it has one. The
+/// pages the file is read into are never freed.
+fn read_manifest(boot_services: &gnu_efi::api::BootServices,
+                 root_directory: &mut File) -> Option<manifest::Manifest<'static>> {
+    let data = match root_directory.open(manifest::MANIFEST_PATH, OpenMode::Read) {
+        Ok(mut file) => read_file(boot_services, &mut file, MAX_MANIFEST_SIZE),
+        Err(_) => return None,
+    };
+    match data.map(core::str::from_utf8) {
+        Ok(Ok(text)) => Some(manifest::Manifest::parse(text)),
+        Ok(Err(_)) => {
+            println!("{} isn't text, not checking the kernel against it", manifest::MANIFEST_PATH);
+            None
+        },
+        Err(error) => {
+            println!("Can't read {}: {:?}", manifest::MANIFEST_PATH, error);
+            None
+        },
+    }
+}
+
+/// Lists `path` on the volume
+fn print_directory(root_directory: &mut File, path: &str) {
+    let mut directory = match root_directory.open(path, OpenMode::Read) {
+        Ok(directory) => directory,
+        Err(status) => {
+            println!("Can't open {}: {:?}", path, status);
+            return;
+        },
+    };
+    let entries = match directory.entries() {
+        Ok(entries) => entries,
+        Err(status) => {
+            println!("Can't list {}: {:?}", path, status);
+            return;
+        },
+    };
+    println!("{}:", path);
+    for entry in entries {
+        match entry {
+            Ok(ref info) if info.is_directory() => println!("    {}\\", info.name()),
+            Ok(info) => println!("    {} {} bytes", info.name(), info.file_size),
+            Err(status) => println!("    Can't read the next entry: {:?}", status),
+        }
+    }
+}
+
+/// Applies the parts of the configuration the loader itself acts on
+fn apply_config(config: &config::BootConfig) {
+    if let Some(log_level) = config.log_level {
+        serial::set_log_level(log_level);
+    }
+    if let Some(serial_baud) = config.serial_baud {
+        // Not in the if let, the port would still be locked when
+        // printing the error
+        let result = serial::SERIAL_WRITER.lock().set_baud_rate(serial_baud);
+        if let Err(error) = result {
+            println!("Can't set the serial port to {:?}", error);
+        }
+    }
+}
+
+/// Size of the span of memory the kernel image is linked into
+fn image_size(elf_file: &elf::File) -> usize {
+    let end = elf_file.program_headers()
+        .filter(|program_header| program_header.segment_type() == elf::SegmentType::Load)
+        .map(|program_header| program_header.virtual_address + program_header.memory_size)
+        .max()
+        .unwrap_or(0);
+    end - link_base(elf_file)
+}
+
+/// Pages the kernel image takes up once loaded
+fn image_pages(elf_file: &elf::File) -> usize {
+    (image_size(elf_file) + 0xFFF) / 0x1000
+}
+
+/// Maps `KERNEL_STACK_PAGES` contiguous fresh frames below `stack_top`,
+/// returning the first of them
+fn map_kernel_stack(page_table: &mut page_table::PageTable, stack_top: usize) -> mem::Frame {
+    let flags = page_table::MappingFlags {
+        no_execute: true,
+        ..Default::default()
+    };
+    let stack_frame = unsafe {
+        falloc::FRAME_ALLOCATOR.get_multiple_frames(KERNEL_STACK_PAGES, falloc::FrameUsage::Stack)
+    }.unwrap();
+    let frames = mem::FrameRange::starting_at(stack_frame, KERNEL_STACK_PAGES);
+    let pages = mem::PageRange::new(
+        mem::VirtualAddress::new(stack_top - KERNEL_STACK_PAGES * 0x1000).into(),
+        mem::VirtualAddress::new(stack_top).into());
+    for (frame, page) in frames.zip(pages) {
+        page_table.insert_page(frame, page, page_table::PageSize::FourKb, flags);
+    }
+    stack_frame
+}
+
+/// Lowest address the kernel image is linked at
+fn link_base(elf_file: &elf::File) -> usize {
+    elf_file.program_headers()
+        .filter(|program_header| program_header.segment_type() == elf::SegmentType::Load)
+        .map(|program_header| program_header.virtual_address)
+        .min()
+        .unwrap_or(0) & !0xFFF
+}
+
+/// How far the kernel image is moved from where it was linked.
+/// Executables are loaded where they were linked, position independent
+/// kernels at `load_base`.
+fn kernel_slide(elf_file: &elf::File, load_base: usize) -> usize {
+    match elf_file.file_header().elf_type {
+        elf::ElfType::Dynamic => load_base.wrapping_sub(link_base(elf_file)),
+        _ => 0,
+    }
+}
+
+/// Loads every PT_LOAD segment of the kernel, moved by `slide`, into
+/// one physically contiguous run of fresh frames, each page mapped with
+/// its segment's permissions. Returns the first frame. The bytes are
+/// written through the direct map, so read only segments never need a
+/// writable mapping.
+fn load_segments(elf_file: &elf::File, page_table: &mut page_table::PageTable, slide: usize) -> mem::Frame {
+    let image_base = link_base(elf_file).wrapping_add(slide);
+    let image_pages = image_pages(elf_file);
+    let image_frame = unsafe {
+        falloc::FRAME_ALLOCATOR.get_multiple_frames(image_pages, falloc::FrameUsage::KernelImage)
+    }.unwrap();
+    let image_address: mem::PhysicalAddress = image_frame.into();
+    let mut image = image_address.to_direct_map();
+    let image = image.as_mut_ptr() as *mut u8;
+    // Whatever no segment's file bytes cover, bss included, is zero
+    unsafe {
+        rlibc::memset(image, 0, image_pages * 0x1000);
+    }
+
+    for program_header in elf_file.program_headers() {
+        if program_header.segment_type() != elf::SegmentType::Load {
+            continue;
+        }
+        debugln!("{:?}", program_header);
+
+        let flags = page_table::MappingFlags {
+            writable: program_header.is_writable(),
+            no_execute: !program_header.is_executable(),
+            ..Default::default()
+        };
+        let data = program_header.file_buffer(elf_file);
+        let segment_start = program_header.virtual_address.wrapping_add(slide);
+        let segment_end = segment_start + program_header.memory_size;
+        let pages = mem::PageRange::new(
+            mem::VirtualAddress::new(segment_start).align_down(0x1000).into(),
+            mem::VirtualAddress::new(segment_end).align_up(0x1000).into());
+
+        for page in pages {
+            // A page shared with the previous segment keeps that
+            // segment's flags
+            if page_table.translate(page.into()).is_none() {
+                let page_address: mem::VirtualAddress = page.into();
+                let page_address: usize = page_address.into();
+                let image_frame: usize = image_frame.into();
+                let frame = mem::Frame::new(image_frame + (page_address - image_base) / 0x1000);
+                page_table.insert_page(frame, page, page_table::PageSize::FourKb, flags);
             }
         }
+
+        unsafe {
+            rlibc::memcpy(
+                image.offset((segment_start - image_base) as isize),
+                data.as_ptr(),
+                data.len());
+        }
+    }
+
+    image_frame
+}
+
+/// Applies the kernel's relocations for an image moved by `slide`
+fn relocate(elf_file: &elf::File, page_table: &page_table::PageTable, slide: usize) {
+    use elf::RelocationType;
+
+    for relocation in elf_file.all_relocations() {
+        let value = match relocation.relocation_type {
+            RelocationType::None => continue,
+            RelocationType::Relative => (relocation.addend as usize).wrapping_add(slide),
+            RelocationType::Direct64 =>
+                symbol_address(&relocation, slide).wrapping_add(relocation.addend as usize),
+            RelocationType::GlobalData => symbol_address(&relocation, slide),
+            other => panic!("Unsupported kernel relocation {:?} at {:#x}", other, relocation.offset),
+        };
+        write_to_image(page_table, relocation.offset.wrapping_add(slide), value as u64);
     }
+}
 
+/// Where the symbol of a relocation ended up. There is no dynamic
+/// linker, so only undefined weak symbols may be left unresolved.
+fn symbol_address(relocation: &elf::Relocation, slide: usize) -> usize {
+    let symbol = relocation.symbol.expect("Kernel relocation without a symbol");
+    if symbol.is_absolute() {
+        symbol.value
+    } else if !symbol.is_undefined() {
+        symbol.value.wrapping_add(slide)
+    } else if symbol.binding == elf::SymbolBinding::Weak {
+        0
+    } else {
+        panic!("Kernel refers to undefined symbol {}", symbol.name);
+    }
+}
+
+/// Writes `value` into the loaded kernel image at `address`, through
+/// the direct map so the image's own permissions don't matter
+fn write_to_image(page_table: &page_table::PageTable, address: usize, value: u64) {
+    assert!(address % 0x1000 <= 0x1000 - 8, "Kernel relocation at {:#x} straddles a page", address);
+    let (physical_address, _, _) = page_table.translate(mem::VirtualAddress::new(address))
+        .expect("Kernel relocation outside the image");
     unsafe {
-        let entry: extern fn(system_table:&gnu_efi::api::SystemTable, falloc::FrameAllocator, page_table::PageTable) -> ! =
-            core::mem::transmute(elf_file.file_header().entry_ptr());
+        core::ptr::write_unaligned(physical_address.to_direct_map().as_mut_ptr() as *mut u64, value);
+    }
+}
 
-        run_kernel(entry, system_table, page_table);
+/// Copies the AP trampoline to the low physical address it is linked
+/// at. It isn't in any segment, so it doesn't move with the image.
+fn load_trampoline(elf_file: &elf::File) {
+    if let Some(trampoline) = elf_file.section_by_name(".trampoline") {
+        let data = trampoline.offset_buffer(elf_file);
+        let mut destination = mem::PhysicalAddress::new(trampoline.virtual_address).to_direct_map();
+        unsafe {
+            rlibc::memcpy(destination.as_mut_ptr() as *mut u8, data.as_ptr(), data.len());
+        }
     }
 }
 
-fn run_kernel(entry: extern fn(system_table:&gnu_efi::api::SystemTable, falloc::FrameAllocator, page_table::PageTable) -> !, system_table:&gnu_efi::api::SystemTable, page_table:page_table::PageTable) -> ! {
-    // Jump to entry
-    let frame_allocator = unsafe {
-        core::mem::replace(
-            &mut falloc::FRAME_ALLOCATOR,
-            core::mem::uninitialized() )
+/// Identity maps `num_pages` pages starting at `start_page`
+fn identity_map(page_table: &mut page_table::PageTable, start_page: usize, num_pages: usize) {
+    map_range(page_table, start_page, start_page, num_pages, Default::default());
+}
+
+/// Maps every RAM region of the memory map at `mem::direct_map_offset()`.
+/// The direct map is never executed from.
+fn direct_map(page_table: &mut page_table::PageTable, memory_map: &gnu_efi::def::MemoryDescriptors) {
+    let flags = page_table::MappingFlags {
+        no_execute: true,
+        ..Default::default()
     };
-    entry(system_table,
-          frame_allocator,
-          page_table);
+    for memory_descriptor in memory_map {
+        if is_ram(memory_descriptor.region_type) {
+            let start_frame: mem::Frame = memory_descriptor.physical_start.into();
+            let start_frame: usize = start_frame.into();
+            let start_page: mem::Page = memory_descriptor.physical_start.to_direct_map().into();
+            map_range(
+                page_table,
+                start_frame,
+                start_page.into(),
+                memory_descriptor.number_of_pages as usize,
+                flags);
+        }
+    }
+}
+
+/// Whether a region of this type is RAM, as opposed to MMIO or holes
+fn is_ram(region_type: gnu_efi::def::MemoryType) -> bool {
+    use ::gnu_efi::def::MemoryType;
+    match region_type {
+        MemoryType::LoaderCode |
+        MemoryType::LoaderData |
+        MemoryType::BootServicesCode |
+        MemoryType::BootServicesData |
+        MemoryType::RuntimeServicesCode |
+        MemoryType::RuntimeServicesData |
+        MemoryType::ConventionalMemory |
+        MemoryType::ACPIReclaimMemory |
+        MemoryType::ACPIMemoryNVS => true,
+        _ => false,
+    }
+}
+
+/// End of the highest RAM region
+fn physical_memory_end(memory_map: &gnu_efi::def::MemoryDescriptors) -> usize {
+    memory_map.into_iter()
+        .filter(|memory_descriptor| is_ram(memory_descriptor.region_type))
+        .map(|memory_descriptor| {
+            let start: usize = memory_descriptor.physical_start.into();
+            start + memory_descriptor.number_of_pages as usize * 0x1000
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Maps `num_pages` pages starting at `start_page` to the frames
+/// starting at `start_frame`. Chunks where both are 2MB aligned and
+/// nothing else has been mapped yet use a single 2MB page, everything
+/// else is mapped page by page, skipping pages that are already
+/// mapped.
+fn map_range(page_table: &mut page_table::PageTable, start_frame: usize, start_page: usize, num_pages: usize, flags: page_table::MappingFlags) {
+    const PAGES_PER_TWO_MB: usize = 0x200;
+
+    let mut offset = 0;
+    while offset < num_pages {
+        let frame_number = start_frame + offset;
+        let page_number = start_page + offset;
+        let chunk_free = frame_number % PAGES_PER_TWO_MB == 0 &&
+            page_number % PAGES_PER_TWO_MB == 0 &&
+            offset + PAGES_PER_TWO_MB <= num_pages &&
+            (page_number..page_number + PAGES_PER_TWO_MB).all(|page_number| {
+                page_table.translate(mem::Page::new(page_number).into()).is_none()
+            });
+
+        if chunk_free {
+            page_table.insert_page(
+                mem::Frame::new(frame_number),
+                mem::Page::new(page_number),
+                page_table::PageSize::TwoMb,
+                flags);
+            offset += PAGES_PER_TWO_MB;
+        } else {
+            let page = mem::Page::new(page_number);
+            if page_table.translate(page.into()).is_none() {
+                page_table.insert_page(
+                    mem::Frame::new(frame_number),
+                    page,
+                    page_table::PageSize::FourKb,
+                    flags);
+            }
+            offset += 1;
+        }
+    }
 }
 
 fn print_memory_map(memory_map: &gnu_efi::def::MemoryDescriptors) {
@@ -0,0 +1,137 @@
+use sha256::Digest;
+
+use config::same_path;
+
+/// Where the optional list of expected digests lives on the volume
+pub const MANIFEST_PATH: &'static str = "EFI\\OS\\MANIFEST";
+
+/// What the loader does when a file doesn't match the manifest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Integrity {
+    /// Says so and boots anyway
+    Warn,
+    /// Refuses to boot
+    Enforce,
+}
+
+impl Integrity {
+    pub fn from_name(name: &str) -> Option<Integrity> {
+        match name {
+            "warn" => Some(Integrity::Warn),
+            "enforce" => Some(Integrity::Enforce),
+            _ => None,
+        }
+    }
+}
+
+/// How a file compares to the manifest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    Match,
+    /// Listed with another digest, which is given
+    Mismatch(Digest),
+    /// Not listed at all
+    Unlisted,
+}
+
+/// The digests the files on the volume should have, in the format
+/// `sha256sum` writes: a hex digest, a space, then a path, which may be
+/// marked binary with a `*`. Blank lines and `#` comments are skipped.
+#[derive(Clone, Copy, Debug)]
+pub struct Manifest<'a> {
+    text: &'a str,
+}
+
+impl<'a> Manifest<'a> {
+    /// Reads a manifest, reporting the lines that can't be used
+    pub fn parse(text: &'a str) -> Manifest<'a> {
+        let manifest = Manifest { text: text };
+        for (index, line) in text.lines().enumerate() {
+            if is_entry(line) && parse_line(line).is_none() {
+                println!("{}:{}: expected a SHA-256 digest and a path", MANIFEST_PATH, index + 1);
+            }
+        }
+        manifest
+    }
+
+    /// Compares the digest of the file at `path` to the manifest's.
+    /// Paths are compared without regard to case or the kind of slash.
+    /// A line for exactly `path` wins, otherwise one with the same file
+    /// name is used, so a manifest written by running `sha256sum` on
+    /// the build's outputs works.
+    pub fn check(&self, path: &str, digest: &Digest) -> Check {
+        let exact = self.find(|name| same_path(name, path));
+        let expected = exact.or_else(|| self.find(|name| same_path(file_name(name), file_name(path))));
+        match expected {
+            Some(ref expected) if expected == digest => Check::Match,
+            Some(expected) => Check::Mismatch(expected),
+            None => Check::Unlisted,
+        }
+    }
+
+    /// The digest on the first line whose path satisfies `matches`
+    fn find<F: Fn(&str) -> bool>(&self, matches: F) -> Option<Digest> {
+        self.text.lines()
+            .filter_map(parse_line)
+            .find(|&(_, name)| matches(name))
+            .map(|(digest, _)| digest)
+    }
+}
+
+/// Whether a manifest line is more than a blank line or comment
+fn is_entry(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && !line.starts_with('#')
+}
+
+/// The digest and path on a manifest line, `None` for a line that
+/// isn't one
+fn parse_line(line: &str) -> Option<(Digest, &str)> {
+    if !is_entry(line) {
+        return None;
+    }
+    let line = line.trim();
+    let split = line.find(char::is_whitespace).unwrap_or(line.len());
+    let (digest, name) = line.split_at(split);
+    let name = name.trim_left();
+    let name = if name.starts_with('*') { &name[1..] } else { name };
+    match Digest::from_hex(digest) {
+        Some(digest) if !name.is_empty() => Some((digest, name)),
+        _ => None,
+    }
+}
+
+/// The last component of `path`
+fn file_name(path: &str) -> &str {
+    path.rsplit(|c| c == '/' || c == '\\').next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, Check};
+    use sha256::digest;
+
+    #[test]
+    fn check() {
+        let text = "\
+# written by make
+ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  target/release/kernel.efi
+e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 *EFI/OS/INITRD.TAR
+not a digest  EFI/OS/OTHER
+";
+        let manifest = Manifest::parse(text);
+        assert_eq!(manifest.check("EFI\\OS\\KERNEL.EFI", &digest(b"abc")), Check::Match);
+        assert_eq!(manifest.check("EFI\\OS\\KERNEL.EFI", &digest(b"abd")), Check::Mismatch(digest(b"abc")));
+        assert_eq!(manifest.check("\\efi\\os\\initrd.tar", &digest(b"")), Check::Match);
+        assert_eq!(manifest.check("EFI\\OS\\OTHER", &digest(b"")), Check::Unlisted);
+        assert_eq!(manifest.check("EFI\\OS\\KERNEL2.EFI", &digest(b"abc")), Check::Unlisted);
+
+        // A full path is preferred to a file name
+        let manifest = Manifest::parse("\
+e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  OLD/KERNEL.EFI
+ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  EFI\\OS\\KERNEL.EFI
+");
+        assert_eq!(manifest.check("EFI\\OS\\KERNEL.EFI", &digest(b"abc")), Check::Match);
+        assert_eq!(manifest.check("EFI\\OS\\NEW\\KERNEL.EFI", &digest(b"")), Check::Match);
+    }
+}
@@ -0,0 +1,347 @@
+use core::fmt::Write;
+
+use gnu_efi::api::SystemTable;
+use gnu_efi::api::protocol::{File, SimpleTextOutputProtocol};
+use gnu_efi::api::protocol::file_protocol::OpenMode;
+use gnu_efi::api::protocol::simple_text_input_protocol::{InputKey, SCAN_UP, SCAN_DOWN};
+
+use config;
+
+/// Most kernels the menu lists
+pub const MAX_ENTRIES: usize = 16;
+
+/// Seconds the menu waits when the configuration has no `timeout`
+pub const DEFAULT_TIMEOUT: usize = 3;
+
+/// Longest kernel path an entry holds
+const MAX_ENTRY_PATH: usize = 128;
+
+/// Microseconds between looking for key presses
+const POLL_INTERVAL: usize = 10_000;
+
+/// A kernel the menu offers
+#[derive(Copy)]
+pub struct BootEntry<'a> {
+    path: [u8; MAX_ENTRY_PATH],
+    path_length: usize,
+    /// Replaces the configuration's `cmdline` when this entry boots
+    pub command_line: Option<&'a str>,
+}
+
+impl<'a> Clone for BootEntry<'a> {
+    fn clone(&self) -> BootEntry<'a> {
+        *self
+    }
+}
+
+impl<'a> BootEntry<'a> {
+    fn new(command_line: Option<&'a str>) -> BootEntry<'a> {
+        BootEntry {
+            path: [0; MAX_ENTRY_PATH],
+            path_length: 0,
+            command_line: command_line,
+        }
+    }
+
+    /// The kernel, relative to the root of the volume
+    pub fn path(&self) -> &str {
+        // Only ever written whole strs
+        ::core::str::from_utf8(&self.path[..self.path_length]).unwrap_or("")
+    }
+}
+
+impl<'a> Write for BootEntry<'a> {
+    /// Fails rather than cutting the path short
+    fn write_str(&mut self, string: &str) -> ::core::fmt::Result {
+        if self.path_length + string.len() > MAX_ENTRY_PATH {
+            return Err(::core::fmt::Error);
+        }
+        self.path[self.path_length..self.path_length + string.len()].copy_from_slice(string.as_bytes());
+        self.path_length += string.len();
+        Ok(())
+    }
+}
+
+/// The kernels found on a volume, the configuration's `kernel` first
+pub struct BootEntries<'a> {
+    entries: [BootEntry<'a>; MAX_ENTRIES],
+    count: usize,
+}
+
+impl<'a> BootEntries<'a> {
+    pub fn new() -> BootEntries<'a> {
+        BootEntries {
+            entries: [BootEntry::new(None); MAX_ENTRIES],
+            count: 0,
+        }
+    }
+
+    pub fn entries(&self) -> &[BootEntry<'a>] {
+        &self.entries[..self.count]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Whether an entry boots `path`. FAT ignores case, so this does too.
+    fn contains(&self, path: &str) -> bool {
+        self.entries().iter().any(|entry| config::same_path(entry.path(), path))
+    }
+
+    /// Adds `entry` if it isn't listed yet and `path` holds a kernel
+    fn add(&mut self, root_directory: &mut File, entry: BootEntry<'a>) {
+        if self.contains(entry.path()) {
+            return;
+        }
+        if !is_kernel(root_directory, entry.path()) {
+            debugln!("No kernel at {}", entry.path());
+            return;
+        }
+        if self.count == MAX_ENTRIES {
+            println!("More than {} kernels, leaving out {}", MAX_ENTRIES, entry.path());
+            return;
+        }
+        self.entries[self.count] = entry;
+        self.count += 1;
+    }
+}
+
+/// Whether `path` on the volume is an ELF file
+fn is_kernel(root_directory: &mut File, path: &str) -> bool {
+    let mut magic = [0; 4];
+    match root_directory.open(path, OpenMode::Read) {
+        Ok(mut file) => file.read(&mut magic).ok() == Some(magic.len()) && &magic == ::elf::MAGIC,
+        Err(_) => false,
+    }
+}
+
+/// The configuration's `kernel`, its `entry` lines, then any other ELF
+/// files in `config::OS_DIRECTORY` that aren't modules
+pub fn find_entries<'a>(root_directory: &mut File, config: &config::BootConfig<'a>) -> BootEntries<'a> {
+    let mut entries = BootEntries::new();
+
+    let mut entry = BootEntry::new(None);
+    if write!(entry, "{}", config.kernel_path).is_ok() {
+        entries.add(root_directory, entry);
+    }
+    for line in config.entries() {
+        let mut words = line.splitn(2, char::is_whitespace);
+        let path = words.next().unwrap_or("");
+        let command_line = words.next().map(str::trim);
+        let mut entry = BootEntry::new(command_line);
+        if write!(entry, "{}", path).is_ok() {
+            entries.add(root_directory, entry);
+        } else {
+            println!("{}: kernel path {} is too long", config::CONFIG_PATH, path);
+        }
+    }
+
+    let mut directory = match root_directory.open(config::OS_DIRECTORY, OpenMode::Read) {
+        Ok(directory) => directory,
+        Err(_) => return entries,
+    };
+    if let Ok(files) = directory.entries() {
+        for info in files.filter_map(|info| info.ok()).filter(|info| !info.is_directory()) {
+            let mut entry = BootEntry::new(None);
+            if write!(entry, "{}\\{}", config::OS_DIRECTORY, info.name()).is_err() {
+                continue;
+            }
+            // Modules can be ELF files too, but aren't kernels
+            if !config.modules().iter().any(|module| config::same_path(module, entry.path())) {
+                entries.add(root_directory, entry);
+            }
+        }
+    }
+    entries
+}
+
+/// A key press the menu acts on
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum MenuKey {
+    Up,
+    Down,
+    Enter,
+    /// 1 to 9, booting that entry
+    Digit(usize),
+    Other,
+}
+
+impl MenuKey {
+    fn from_input_key(key: InputKey) -> MenuKey {
+        match key.scan_code {
+            SCAN_UP => MenuKey::Up,
+            SCAN_DOWN => MenuKey::Down,
+            _ if key.unicode_char < 0x80 => MenuKey::from_byte(key.unicode_char as u8),
+            _ => MenuKey::Other,
+        }
+    }
+
+    /// A key typed on the serial port, which only has digits and Enter
+    fn from_byte(byte: u8) -> MenuKey {
+        match byte {
+            b'\r' | b'\n' => MenuKey::Enter,
+            b'1'...b'9' => MenuKey::Digit((byte - b'0') as usize),
+            _ => MenuKey::Other,
+        }
+    }
+}
+
+/// Which entry is selected and how long until it boots
+struct Menu {
+    count: usize,
+    selected: usize,
+    /// Polls left before booting, `None` once a key stopped the
+    /// countdown
+    polls_left: Option<usize>,
+}
+
+impl Menu {
+    fn new(count: usize, timeout: usize) -> Menu {
+        Menu {
+            count: count,
+            selected: 0,
+            polls_left: Some(timeout.saturating_mul(1_000_000 / POLL_INTERVAL)),
+        }
+    }
+
+    /// Advances by one poll that saw `key`, giving the entry to boot
+    /// once there is one
+    fn step(&mut self, key: Option<MenuKey>) -> Option<usize> {
+        let key = match key {
+            Some(key) => key,
+            None => {
+                return match self.polls_left {
+                    Some(0) => Some(self.selected),
+                    Some(polls) => {
+                        self.polls_left = Some(polls - 1);
+                        None
+                    },
+                    None => None,
+                };
+            },
+        };
+
+        self.polls_left = None;
+        match key {
+            MenuKey::Up => self.selected = (self.selected + self.count - 1) % self.count,
+            MenuKey::Down => self.selected = (self.selected + 1) % self.count,
+            MenuKey::Enter => return Some(self.selected),
+            MenuKey::Digit(digit) if digit <= self.count => return Some(digit - 1),
+            MenuKey::Digit(_) | MenuKey::Other => {},
+        }
+        None
+    }
+
+    fn seconds_left(&self) -> Option<usize> {
+        let polls_per_second = 1_000_000 / POLL_INTERVAL;
+        self.polls_left.map(|polls| polls / polls_per_second + if polls % polls_per_second > 0 { 1 } else { 0 })
+    }
+}
+
+/// Lets the user pick one of `entries` on the console or the serial
+/// port, giving the first if nobody does within `timeout` seconds. The
+/// menu is skipped when there is nothing to choose between.
+pub fn choose<'e, 'a>(system_table: &mut SystemTable, entries: &'e BootEntries<'a>,
+                      timeout: usize) -> Option<&'e BootEntry<'a>> {
+    let entries = entries.entries();
+    if entries.len() <= 1 || timeout == 0 {
+        return entries.first();
+    }
+
+    println!("Kernels:");
+    for (number, entry) in entries.iter().enumerate() {
+        println!("  {}. {} {}", number + 1, entry.path(), entry.command_line.unwrap_or(""));
+    }
+    println!("Type a number to boot that kernel, or Enter for the first. Booting it in {} s.", timeout);
+
+    let boot_services = system_table.boot_services;
+    let mut menu = Menu::new(entries.len(), timeout);
+    let _ = system_table.console_out().clear_screen();
+    let mut shown = None;
+    loop {
+        if shown != Some((menu.selected, menu.seconds_left())) {
+            shown = Some((menu.selected, menu.seconds_left()));
+            // The console is a nicety, serial shows the same
+            let _ = draw(system_table.console_out(), entries, &menu);
+        }
+
+        let key = match system_table.console_in().read_key_stroke() {
+            Ok(key) => Some(MenuKey::from_input_key(key)),
+            Err(_) => ::serial::SERIAL_WRITER.lock().try_getc().map(MenuKey::from_byte),
+        };
+        if let Some(choice) = menu.step(key) {
+            let _ = system_table.console_out().clear_screen();
+            return entries.get(choice);
+        }
+        boot_services.stall(POLL_INTERVAL);
+    }
+}
+
+/// Shows the menu at the top of the console
+fn draw(console: &mut SimpleTextOutputProtocol, entries: &[BootEntry], menu: &Menu) -> ::core::fmt::Result {
+    console.set_cursor_position(0, 0).map_err(|_| ::core::fmt::Error)?;
+    write!(console, "Kernels:\n")?;
+    for (number, entry) in entries.iter().enumerate() {
+        let marker = if number == menu.selected { '>' } else { ' ' };
+        write!(console, "{} {}. {} {}\n", marker, number + 1, entry.path(), entry.command_line.unwrap_or(""))?;
+    }
+    // Padded to cover a longer line drawn before
+    match menu.seconds_left() {
+        Some(seconds) => write!(console, "\nBooting the selected kernel in {} s, press any key to wait   ", seconds),
+        None => write!(console, "\nUp and Down to select, Enter to boot, or a number to boot that kernel"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Menu, MenuKey, BootEntry};
+    use core::fmt::Write;
+
+    #[test]
+    fn timeout() {
+        let mut menu = Menu::new(3, 1);
+        assert_eq!(menu.seconds_left(), Some(1));
+        for _ in 0..100 {
+            assert_eq!(menu.step(None), None);
+        }
+        assert_eq!(menu.seconds_left(), Some(0));
+        assert_eq!(menu.step(None), Some(0));
+
+        // A huge timeout from BOOT.CFG waits as long as it can
+        let mut menu = Menu::new(3, usize::max_value());
+        assert!(menu.seconds_left().unwrap() > 1_000_000);
+        assert_eq!(menu.step(None), None);
+    }
+
+    #[test]
+    fn keys() {
+        let mut menu = Menu::new(3, 1);
+        assert_eq!(menu.step(Some(MenuKey::Up)), None);
+        assert_eq!(menu.selected, 2);
+        assert_eq!(menu.seconds_left(), None);
+        for _ in 0..200 {
+            assert_eq!(menu.step(None), None);
+        }
+        assert_eq!(menu.step(Some(MenuKey::Down)), None);
+        assert_eq!(menu.selected, 0);
+        assert_eq!(menu.step(Some(MenuKey::Digit(4))), None);
+        assert_eq!(menu.step(Some(MenuKey::Digit(2))), Some(1));
+        assert_eq!(menu.step(Some(MenuKey::Enter)), Some(0));
+
+        assert_eq!(MenuKey::from_byte(b'\r'), MenuKey::Enter);
+        assert_eq!(MenuKey::from_byte(b'7'), MenuKey::Digit(7));
+        assert_eq!(MenuKey::from_byte(b'0'), MenuKey::Other);
+    }
+
+    #[test]
+    fn entry_paths() {
+        let mut entry = BootEntry::new(None);
+        write!(entry, "{}\\{}", "EFI\\OS", "debug.efi").unwrap();
+        assert_eq!(entry.path(), "EFI\\OS\\debug.efi");
+
+        let mut entry = BootEntry::new(None);
+        let long = [b'A'; 129];
+        assert!(write!(entry, "{}", ::core::str::from_utf8(&long).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+/// Tries before giving up on RDSEED or RDRAND, which can briefly run
+/// out of entropy
+const RETRIES: usize = 10;
+
+/// Seed read from `EFI_RNG_PROTOCOL` while boot services were up,
+/// advanced every time it is used
+static mut FIRMWARE_SEED: Option<u64> = None;
+
+/// Reads a seed from the firmware's `EFI_RNG_PROTOCOL`, if it has one,
+/// for `random_u64` to use on CPUs without RDSEED or RDRAND. Has to be
+/// called before exiting boot services.
+pub fn seed_from_firmware(boot_services: &::gnu_efi::api::BootServices) {
+    use gnu_efi::api::protocol::RngProtocol;
+
+    let handles = match boot_services.retrieve_handles_with_protocol::<RngProtocol>() {
+        Ok(handles) => handles,
+        Err(_) => return,
+    };
+    let seed = handles.iter().filter_map(|handle| -> Option<u64> {
+        let rng: &mut RngProtocol = match boot_services.retrieve_protocol_from_handle(handle) {
+            Ok(rng) => rng,
+            Err(_) => return None,
+        };
+        let mut bytes = [0u8; 8];
+        rng.get_rng(&mut bytes).ok().map(|_| {
+            bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64)
+        })
+    }).next();
+    unsafe {
+        FIRMWARE_SEED = seed;
+    }
+}
+
+/// A random number from RDSEED, then RDRAND, then the firmware's seed,
+/// and if there's none of them, from the timestamp counter
+pub fn random_u64() -> u64 {
+    if has_rdseed() {
+        if let Some(value) = retry(rdseed) {
+            return value;
+        }
+    }
+    if has_rdrand() {
+        if let Some(value) = retry(rdrand) {
+            return value;
+        }
+    }
+    if let Some(value) = firmware_random() {
+        return value;
+    }
+    tsc_entropy()
+}
+
+/// A random multiple of `alignment` below `limit`
+pub fn random_below(limit: usize, alignment: usize) -> usize {
+    let choices = limit / alignment;
+    if choices == 0 {
+        0
+    } else {
+        (random_u64() as usize % choices) * alignment
+    }
+}
+
+/// The next number derived from `FIRMWARE_SEED`, splitmix64 style
+fn firmware_random() -> Option<u64> {
+    unsafe {
+        FIRMWARE_SEED.map(|seed| {
+            FIRMWARE_SEED = Some(seed.wrapping_add(0x9e37_79b9_7f4a_7c15));
+            mix(seed)
+        })
+    }
+}
+
+fn retry(source: fn() -> Option<u64>) -> Option<u64> {
+    (0..RETRIES).filter_map(|_| source()).next()
+}
+
+fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}" (eax), "={ebx}" (ebx), "={ecx}" (ecx), "={edx}" (edx)
+             : "{eax}" (leaf), "{ecx}" (0)
+             :: "volatile");
+    }
+    (eax, ebx, ecx, edx)
+}
+
+/// CPUID.01H:ECX.RDRAND[bit 30]
+fn has_rdrand() -> bool {
+    let (_, _, ecx, _) = cpuid(0x1);
+    ecx & (1 << 30) != 0
+}
+
+/// CPUID.(EAX=07H, ECX=0H):EBX.RDSEED[bit 18]
+fn has_rdseed() -> bool {
+    let (max_leaf, _, _, _) = cpuid(0x0);
+    if max_leaf < 0x7 {
+        return false;
+    }
+    let (_, ebx, _, _) = cpuid(0x7);
+    ebx & (1 << 18) != 0
+}
+
+fn rdrand() -> Option<u64> {
+    let value: u64;
+    let success: u8;
+    unsafe {
+        asm!("rdrand $0; setc $1" : "=r" (value), "=r" (success) ::: "volatile");
+    }
+    if success == 1 { Some(value) } else { None }
+}
+
+fn rdseed() -> Option<u64> {
+    let value: u64;
+    let success: u8;
+    unsafe {
+        asm!("rdseed $0; setc $1" : "=r" (value), "=r" (success) ::: "volatile");
+    }
+    if success == 1 { Some(value) } else { None }
+}
+
+fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdtsc" : "={eax}" (low), "={edx}" (high) ::: "volatile");
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// Mixes the jitter of timing a few loops with the boot time. Only a
+/// handful of bits are unpredictable, but it beats a fixed layout.
+fn tsc_entropy() -> u64 {
+    let mut state = rdtsc();
+    for round in 0..64 {
+        let start = rdtsc();
+        for _ in 0..(state & 0xff) {
+            unsafe { asm!("pause" :::: "volatile"); }
+        }
+        state = mix(state ^ rdtsc().wrapping_sub(start) ^ round);
+    }
+    state
+}
+
+/// The splitmix64 finalizer
+fn mix(value: u64) -> u64 {
+    let value = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}